@@ -1,19 +1,81 @@
-use std::collections::BTreeMap;
+use crate::line_table::{self, LineInfo};
+use crate::number_parser;
+use crate::object::{self, Binding, ObjectFile, Relocation, Section, Target};
+use crate::section_map::{self, SectionInfo};
+use crate::symbol_table::{self, SymbolInfo, SymbolKind};
 use std::collections::HashMap;
 use std::error::Error;
 use std::fs::File;
-use std::io;
 use std::io::prelude::*;
-use std::io::BufRead;
-use std::u64;
-use crate::number_parser;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+mod labels;
 mod parser;
+mod preprocess;
+mod references;
+mod sections;
+mod source;
+use labels::{Label, Labels, Resolved};
 use parser::ICode;
+pub use parser::{mnemonics, register_names};
+pub use references::{Role, Span, SymbolUse};
+use sections::Sections;
+pub use source::AssemblyError;
+use source::{Includes, SourceLine};
 
 /// A struct to hold bytes read from y86
 /// bytes is a vector holding the bytes
+/// sections: where each section was laid out in bytes
+/// symbols: the labels and constants of the program
+/// lines: the source line behind every instruction and `.quad`
 pub struct Y86Assembler {
     bytes: Vec<u8>,
+    sections: Vec<SectionInfo>,
+    symbols: Vec<SymbolInfo>,
+    lines: Vec<LineInfo>,
+}
+
+/// Options controlling how a Y86 file is assembled
+/// include_paths: directories searched for `.include` files that are not
+/// found next to the including file, the equivalent of `-I`
+/// defines: constants visible to conditional assembly and operands,
+/// the equivalent of `-D NAME=VALUE`
+/// allow_overlap: lets `.pos` regions overlap instead of failing, the
+/// region appearing last in the source wins wherever they overlap
+/// placements: explicit addresses for sections, sections without one
+/// follow the previous section (`.text`, then `.data`, then `.bss`)
+#[derive(Default, Clone)]
+pub struct AssemblerOptions {
+    pub include_paths: Vec<PathBuf>,
+    pub defines: HashMap<String, u64>,
+    pub allow_overlap: bool,
+    pub placements: Vec<(String, u64)>,
+}
+
+impl AssemblerOptions {
+    /// Adds a definition written the way it would be on the command
+    /// line, either NAME=VALUE or just NAME, which defines it as 1
+    pub fn define(&mut self, definition: &str) -> Result<(), Box<dyn Error>> {
+        let mut split = definition.splitn(2, '=');
+        let name = split.next().unwrap().trim();
+        let value = match split.next() {
+            Some(value) => number_parser::parse_num(value.trim())?,
+            None => 1,
+        };
+        if name.is_empty() {
+            return Err(format!("Invalid definition \"{}\"", definition).into());
+        }
+        self.defines.insert(name.to_string(), value);
+        Ok(())
+    }
+
+    /// Places a section at a fixed address, written as NAME=ADDRESS
+    /// such as `.data=0x400`
+    pub fn place(&mut self, placement: &str) -> Result<(), Box<dyn Error>> {
+        self.placements
+            .push(section_map::parse_placement(placement)?);
+        Ok(())
+    }
 }
 
 impl Y86Assembler {
@@ -21,16 +83,140 @@ impl Y86Assembler {
     /// reads a Y86 file and generates a Y86Assembler with the
     /// machine code content
     pub fn from_file(file_name: String) -> Result<Self, Box<dyn Error>> {
-        let lines_iter = read_lines(file_name)?;
-        let lines: Vec<String> = lines_iter.map(|val| val.unwrap()).collect();
-        let mut positions: BTreeMap<u64, Vec<u8>> = BTreeMap::new();
-        get_positions(&mut positions, &lines)?;
+        Self::from_file_with_options(file_name, &AssemblerOptions::default())
+    }
+
+    /// Same as from_file, but with explicit options
+    /// options: the AssemblerOptions to assemble with
+    pub fn from_file_with_options(
+        file_name: String,
+        options: &AssemblerOptions,
+    ) -> Result<Self, Box<dyn Error>> {
+        let path = Path::new(&file_name);
+        Self::from_lines(path, &source::load(path)?, options)
+    }
+
+    /// Same as from_file_with_options, but with the contents of
+    /// file_name given as text instead of read from disk
+    /// text: the source, such as a file being edited that was not saved
+    pub fn from_text(
+        file_name: String,
+        text: &str,
+        options: &AssemblerOptions,
+    ) -> Result<Self, Box<dyn Error>> {
+        let path = Path::new(&file_name);
+        Self::from_lines(path, &source::load_text(path, text)?, options)
+    }
+
+    fn from_lines(
+        path: &Path,
+        lines: &[SourceLine],
+        options: &AssemblerOptions,
+    ) -> Result<Self, Box<dyn Error>> {
+        Self::from_assembly(get_positions(path, lines, options, false)?, options)
+    }
+
+    /// Lays the sections of an assembly out into an image
+    fn from_assembly(
+        assembly: Assembly,
+        options: &AssemblerOptions,
+    ) -> Result<Self, Box<dyn Error>> {
+        let mut placed = vec![];
+        for (index, section) in assembly.sections.list.iter().enumerate() {
+            let bytes = merge_position(&assembly.positions, index, section.size, options)?;
+            placed.push((section, bytes));
+        }
+        placed.sort_by_key(|(section, _)| section.base);
+        let mut furthest: Option<&sections::SectionState> = None;
+        for &(next, _) in placed.iter().filter(|(section, _)| section.size > 0) {
+            match furthest {
+                Some(prev) if prev.base + prev.size > next.base => {
+                    let message = format!(
+                        "Section {} (0x{:x}-0x{:x}) overlaps section {} (0x{:x}-0x{:x})",
+                        prev.name,
+                        prev.base,
+                        prev.base + prev.size - 1,
+                        next.name,
+                        next.base,
+                        next.base + next.size - 1
+                    );
+                    return Err(message.into());
+                }
+                _ => furthest = Some(next),
+            }
+        }
+        let size = placed
+            .iter()
+            .filter(|(section, _)| section.size > 0)
+            .map(|(section, _)| section.base + section.size)
+            .max()
+            .unwrap_or(0);
+        let mut bytes = vec![0; size as usize];
+        for (section, section_bytes) in placed {
+            let start = section.base as usize;
+            bytes[start..start + section_bytes.len()].copy_from_slice(&section_bytes);
+        }
         Ok(Y86Assembler {
-            bytes: merge_position(&positions),
+            bytes,
+            sections: assembly.sections.infos(),
+            symbols: assembly.symbols,
+            lines: assembly.lines,
         })
     }
 
-    /// Saves a the machine code content into a file specified by 
+    /// Wraps an already laid out image, such as the output of the linker
+    pub(crate) fn from_bytes(
+        bytes: Vec<u8>,
+        sections: Vec<SectionInfo>,
+        symbols: Vec<SymbolInfo>,
+    ) -> Self {
+        Y86Assembler {
+            bytes,
+            sections,
+            symbols,
+            lines: vec![],
+        }
+    }
+
+    /// The machine code of the program, starting at address 0
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// The symbols of the program, sorted by address
+    pub fn symbols(&self) -> &[SymbolInfo] {
+        &self.symbols
+    }
+
+    /// The line table of the program, in source order
+    pub fn lines(&self) -> &[LineInfo] {
+        &self.lines
+    }
+
+    /// Saves the line table into a file specified by file_name, which
+    /// the executer can load to show the source being stepped through
+    pub fn save_line_table(&self, file_name: String) -> Result<(), Box<dyn Error>> {
+        line_table::save_file(&self.lines, file_name)
+    }
+
+    /// Saves the symbol table into a file specified by file_name, which
+    /// the executer can load to refer to addresses by name
+    pub fn save_symbol_table(&self, file_name: String) -> Result<(), Box<dyn Error>> {
+        symbol_table::save_file(&self.symbols, file_name)
+    }
+
+    /// The sections of the program and where they were laid out
+    pub fn sections(&self) -> &[SectionInfo] {
+        &self.sections
+    }
+
+    /// Saves where each section was laid out into a file specified by
+    /// file_name, which the executer can load to protect and label memory
+    pub fn save_section_map(&self, file_name: String) -> Result<(), Box<dyn Error>> {
+        section_map::save_file(&self.sections, file_name)
+    }
+
+    /// Saves a the machine code content into a file specified by
     /// file_name
     pub fn save_file(&mut self, file_name: String) -> Result<(), Box<dyn Error>> {
         let mut file = File::create(file_name)?;
@@ -45,40 +231,444 @@ impl Y86Assembler {
     }
 }
 
-fn merge_position(positions: &BTreeMap<u64, Vec<u8>>) -> Vec<u8> {
-    let iter = positions.iter();
-    let mut res = vec![];
-    for (key, val) in iter {
-        while res.len() < *key as usize {
-            res.push(0);
+/// What an editor needs to know about a source file, even one that
+/// does not assemble
+/// uses: every symbol written in the source or the files it includes
+/// errors: everything that went wrong, for every line it went wrong on
+/// assembly: the program, None if there were errors
+pub struct Analysis {
+    pub uses: Vec<SymbolUse>,
+    pub errors: Vec<Box<dyn Error>>,
+    pub assembly: Option<Y86Assembler>,
+}
+
+/// Assembles the contents of file_name as far as it goes, to find the
+/// symbols it defines and refers to and all of its errors
+/// text: the source, such as a file being edited that was not saved
+/// options: the AssemblerOptions to assemble with
+pub fn analyze(file_name: String, text: &str, options: &AssemblerOptions) -> Analysis {
+    let path = Path::new(&file_name);
+    let assembled = source::load_text(path, text)
+        .and_then(|lines| assemble_lines(path, &lines, options, false));
+    let mut assembly = match assembled {
+        Ok(assembly) => assembly,
+        Err(e) => {
+            return Analysis {
+                uses: vec![],
+                errors: vec![e],
+                assembly: None,
+            }
         }
-        val.iter().for_each(|&b| res.push(b));
+    };
+    let uses = std::mem::take(&mut assembly.uses);
+    let mut errors = std::mem::take(&mut assembly.errors);
+    let assembly = if errors.is_empty() {
+        Y86Assembler::from_assembly(assembly, options)
+            .map_err(|e| errors.push(e))
+            .ok()
+    } else {
+        None
+    };
+    Analysis {
+        uses,
+        errors,
+        assembly,
+    }
+}
+
+/// Assembles a Y86 file into a relocatable object instead of an image
+/// Labels the file does not define are treated as external symbols, and
+/// every operand referring to a label gets a relocation entry
+/// file_name: a string holding the file name to read
+/// options: the AssemblerOptions to assemble with
+pub fn assemble_object(
+    file_name: String,
+    options: &AssemblerOptions,
+) -> Result<ObjectFile, Box<dyn Error>> {
+    let path = Path::new(&file_name);
+    let assembly = get_positions(path, &source::load(path)?, options, true)?;
+    let mut sections = vec![];
+    for (index, section) in assembly.sections.list.iter().enumerate() {
+        let bytes = if section.name == ".bss" {
+            vec![]
+        } else {
+            merge_position(&assembly.positions, index, section.size, options)?
+        };
+        sections.push(Section {
+            name: section.name.clone(),
+            size: section.size,
+            bytes,
+            writable: section.writable,
+            executable: section.executable,
+        });
+    }
+    let mut symbols: Vec<object::Symbol> = assembly
+        .labels
+        .globals()
+        .map(|(name, label)| object::Symbol {
+            name: name.to_string(),
+            binding: if assembly.globals.iter().any(|(global, _)| global == name) {
+                Binding::Global
+            } else {
+                Binding::Local
+            },
+            section: Some(label.section),
+            offset: label.offset,
+        })
+        .collect();
+    symbols.sort_by_key(|symbol| (symbol.section, symbol.offset));
+    let mut relocations = vec![];
+    for (section, offset, reference) in assembly.relocations {
+        let (target, addend) = match reference {
+            Resolved::Label {
+                section, offset, ..
+            } => (Target::Section(section), offset),
+            Resolved::External(name) => {
+                if !symbols.iter().any(|symbol| symbol.name == name) {
+                    symbols.push(object::Symbol {
+                        name: name.clone(),
+                        binding: Binding::Global,
+                        section: None,
+                        offset: 0,
+                    });
+                }
+                (Target::Symbol(name), 0)
+            }
+            Resolved::Constant(_) => continue,
+        };
+        relocations.push(Relocation {
+            section,
+            offset,
+            target,
+            addend,
+        });
+    }
+    Ok(ObjectFile {
+        name: file_name,
+        sections,
+        symbols,
+        relocations,
+    })
+}
+
+/// Everything known about a program once its lines are assembled,
+/// before the regions are merged into an image
+/// relocations: the section and offset of each immediate that refers
+/// to a label, and what it refers to
+/// lines: where each line emitting bytes put them
+/// uses: every symbol written in the source
+/// errors: every line that failed to assemble, in the order found
+#[derive(Default)]
+struct Assembly {
+    positions: Vec<Region>,
+    relocations: Vec<(usize, u64, Resolved)>,
+    labels: Labels,
+    globals: Vec<(String, SourceLine)>,
+    sections: Sections,
+    symbols: Vec<SymbolInfo>,
+    lines: Vec<LineInfo>,
+    uses: Vec<SymbolUse>,
+    errors: Vec<Box<dyn Error>>,
+}
+
+/// The bytes following a `.pos` (or the start of a section), with
+/// the first and last source lines that emitted them
+/// start is an offset into the section
+struct Region {
+    section: usize,
+    start: u64,
+    bytes: Vec<u8>,
+    lines: Option<(SourceLine, SourceLine)>,
+}
+
+impl Region {
+    fn end(&self) -> u64 {
+        self.start + self.bytes.len() as u64
+    }
+
+    fn describe(&self) -> String {
+        let range = match &self.lines {
+            Some((first, last)) if first.file == last.file => {
+                format!("{}:{}-{}", first.file, first.line, last.line)
+            }
+            Some((first, last)) => {
+                format!("{}:{}-{}:{}", first.file, first.line, last.file, last.line)
+            }
+            None => String::new(),
+        };
+        format!("0x{:x}-0x{:x} ({})", self.start, self.end() - 1, range)
     }
-    res
 }
 
+/// Lays the regions of a section out into its bytes, padding gaps with
+/// zeros up to size
+/// Overlapping regions are an error unless allow_overlap is set, in
+/// which case regions are written in source order
+fn merge_position(
+    positions: &[Region],
+    section: usize,
+    size: u64,
+    options: &AssemblerOptions,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let positions: Vec<&Region> = positions
+        .iter()
+        .filter(|region| region.section == section)
+        .collect();
+    if !options.allow_overlap {
+        let mut sorted: Vec<&Region> = positions
+            .iter()
+            .copied()
+            .filter(|r| !r.bytes.is_empty())
+            .collect();
+        sorted.sort_by_key(|region| region.start);
+        let mut furthest: Option<&Region> = None;
+        for region in sorted {
+            match furthest {
+                Some(prev) if prev.end() > region.start => {
+                    let message = format!(
+                        "Overlapping regions {} and {}",
+                        prev.describe(),
+                        region.describe()
+                    );
+                    return Err(message.into());
+                }
+                Some(prev) if prev.end() >= region.end() => {}
+                _ => furthest = Some(region),
+            }
+        }
+    }
+    let mut res = vec![0; size as usize];
+    for region in positions {
+        res[region.start as usize..region.end() as usize].copy_from_slice(&region.bytes);
+    }
+    Ok(res)
+}
+
+/// Assembles lines, failing with the first error found
 fn get_positions(
-    positions: &mut BTreeMap<u64, Vec<u8>>,
-    lines: &[String],
-) -> Result<(), Box<dyn Error>> {
-    let mut curr_position = 0;
-    let trimmed: Vec<String> = lines.iter().map(|line| trim_line(&line)).collect();
-    let mapping: HashMap<&str, u64> = map_labels(&trimmed)?;
-    let val: Result<(), Box<dyn Error>> = trimmed
+    path: &Path,
+    lines: &[SourceLine],
+    options: &AssemblerOptions,
+    relocatable: bool,
+) -> Result<Assembly, Box<dyn Error>> {
+    let mut assembly = assemble_lines(path, lines, options, relocatable)?;
+    if !assembly.errors.is_empty() {
+        return Err(assembly.errors.remove(0));
+    }
+    Ok(assembly)
+}
+
+/// Assembles lines, going on past the lines that fail so that every
+/// one of them ends up in the errors of the assembly
+/// Only fails if the lines can not even be preprocessed
+fn assemble_lines(
+    path: &Path,
+    lines: &[SourceLine],
+    options: &AssemblerOptions,
+    relocatable: bool,
+) -> Result<Assembly, Box<dyn Error>> {
+    let mut includes = Includes::new(path, &options.include_paths);
+    let preprocessed = preprocess::preprocess(lines, &options.defines, &mut includes)?;
+    let lines = &preprocessed.lines;
+    let trimmed: Vec<String> = lines.iter().map(|line| trim_line(&line.text)).collect();
+    let placements = if relocatable {
+        None
+    } else {
+        Some(&options.placements[..])
+    };
+    let (labels, mut sections, failed) = map_labels(lines, &trimmed, placements);
+    if !relocatable {
+        sections.lay_out(&options.placements);
+    }
+    let failed_lines: Vec<usize> = failed.iter().map(|(index, _)| *index).collect();
+    let mut assembly = Assembly {
+        labels,
+        globals: preprocessed.globals,
+        errors: failed.into_iter().map(|(_, e)| e).collect(),
+        ..Assembly::default()
+    };
+    assembly.labels.constants = preprocessed.constants;
+    let mut clashes: Vec<(&str, &Label)> = assembly
+        .labels
+        .globals()
+        .filter(|(name, _)| assembly.labels.constants.contains_key(*name))
+        .collect();
+    clashes.sort_by_key(|(_, label)| label.line);
+    for (name, label) in clashes {
+        let e = match preprocessed.definitions.get(name) {
+            Some(constant) => format!(
+                "Label {} is also a constant (defined at {}:{})",
+                name, constant.file, constant.line
+            ),
+            None => format!(
+                "Label {} is also a constant defined outside the source",
+                name
+            ),
+        };
+        assembly
+            .errors
+            .push(source::located(&lines[label.line], e.into()));
+    }
+    assembly.labels.bases = sections.list.iter().map(|section| section.base).collect();
+    sections.current = 0;
+    assembly.sections = sections;
+    for (name, source) in &assembly.globals {
+        if !assembly.labels.globals().any(|(label, _)| label == name) {
+            let e = format!("Undefined symbol {}", name).into();
+            assembly.errors.push(source::located(source, e));
+        }
+    }
+    for (index, (source, line)) in lines.iter().zip(trimmed.iter()).enumerate() {
+        if failed_lines.contains(&index) {
+            continue;
+        }
+        let emitted: Result<(), Box<dyn Error>> =
+            apply_mapping(&assembly.labels, index, line, relocatable)
+                .and_then(|(line, reference)| emit_line(&mut assembly, source, &line, reference));
+        if let Err(e) = emitted {
+            assembly.errors.push(source::located(source, e));
+        }
+    }
+    assembly.symbols = symbols(&assembly, lines, &preprocessed.definitions);
+    assembly.uses = references::collect(
+        lines,
+        &assembly.labels,
+        &preprocessed.definitions,
+        &assembly.globals,
+        relocatable,
+    );
+    Ok(assembly)
+}
+
+/// Collects the global labels and the constants of an assembled program
+/// into a symbol table sorted by address
+fn symbols(
+    assembly: &Assembly,
+    lines: &[SourceLine],
+    definitions: &HashMap<String, SourceLine>,
+) -> Vec<SymbolInfo> {
+    let labels = assembly.labels.globals().map(|(name, label)| {
+        let source = &lines[label.line];
+        let kind = if assembly.sections.list[label.section].executable
+            && labels_instruction(&lines[label.line..])
+        {
+            SymbolKind::Code
+        } else {
+            SymbolKind::Data
+        };
+        SymbolInfo {
+            name: name.to_string(),
+            address: assembly.labels.address(label),
+            kind,
+            file: Some(source.file.to_string()),
+            line: Some(source.line),
+        }
+    });
+    let constants = assembly.labels.constants.iter().map(|(name, &value)| {
+        let source = definitions.get(name);
+        SymbolInfo {
+            name: name.to_string(),
+            address: value,
+            kind: SymbolKind::Constant,
+            file: source.map(|source| source.file.to_string()),
+            line: source.map(|source| source.line),
+        }
+    });
+    let mut res: Vec<SymbolInfo> = labels.chain(constants).collect();
+    res.sort_by(|a, b| (a.address, &a.name).cmp(&(b.address, &b.name)));
+    res
+}
+
+/// Whether the label on the first of lines is followed by an
+/// instruction rather than by data such as `.quad` or nothing at all
+fn labels_instruction(lines: &[SourceLine]) -> bool {
+    let statement = lines
         .iter()
-        .map(|line| apply_mapping(&mapping, &line))
-        .try_for_each(|line| {
-            if line.starts_with(".pos") {
-                let position: u64 = number_parser::parse_num(&line[5..])?;
-                positions.insert(position, vec![]);
-                curr_position = position;
-            } else {
-                let curr_vec = positions.entry(curr_position).or_insert_with(|| vec![]);
-                curr_vec.append(&mut convert_line(&line)?);
+        .map(|line| {
+            let trimmed = trim_line(&line.text);
+            match trimmed.find(':') {
+                Some(colon) => trimmed[colon + 1..].trim().to_string(),
+                None => trimmed.trim().to_string(),
             }
-            Ok(())
+        })
+        .find(|statement| !statement.is_empty());
+    statement.is_some_and(|statement| !statement.starts_with('.'))
+}
+
+fn emit_line(
+    assembly: &mut Assembly,
+    source: &SourceLine,
+    line: &str,
+    reference: Option<Resolved>,
+) -> Result<(), Box<dyn Error>> {
+    if assembly.sections.switch(line)? {
+        return Ok(());
+    }
+    let section = assembly.sections.current;
+    let positions = &mut assembly.positions;
+    if line.starts_with(".pos") {
+        let position = assembly
+            .sections
+            .position(number_parser::parse_num(&line[5..])?)?;
+        positions.push(Region {
+            section,
+            start: position,
+            bytes: vec![],
+            lines: None,
+        });
+        return Ok(());
+    }
+    let mut bytes = convert_line(line)?;
+    if bytes.is_empty() {
+        return Ok(());
+    }
+    if assembly.sections.is_bss() && bytes.iter().any(|&b| b != 0) {
+        return Err("Only zeros can be placed in .bss".into());
+    }
+    if !positions.iter().any(|region| region.section == section) {
+        positions.push(Region {
+            section,
+            start: 0,
+            bytes: vec![],
+            lines: None,
         });
-    val
+    }
+    let region = positions
+        .iter_mut()
+        .rev()
+        .find(|region| region.section == section)
+        .unwrap();
+    if let Some(reference) = reference {
+        let offset = region.end() + immediate_offset(line)?;
+        assembly.relocations.push((section, offset, reference));
+    }
+    assembly.lines.push(LineInfo {
+        address: assembly.labels.bases[section] + region.end(),
+        size: bytes.len() as u64,
+        file: source.file.to_string(),
+        line: source.line,
+        text: source.text.trim_end().to_string(),
+    });
+    region.bytes.append(&mut bytes);
+    let first = match region.lines.take() {
+        Some((first, _)) => first,
+        None => source.clone(),
+    };
+    region.lines = Some((first, source.clone()));
+    Ok(())
+}
+
+/// Where the 8 byte immediate sits within the encoding of line
+fn immediate_offset(line: &str) -> Result<u64, Box<dyn Error>> {
+    if line.starts_with(".quad") {
+        return Ok(0);
+    }
+    let instr = line.split(' ').next().unwrap();
+    match parser::get_icode_from_string(instr)? {
+        ICode::IJXX | ICode::ICALL => Ok(1),
+        ICode::IIRMOVQ | ICode::IRMMOVQ | ICode::IMRMOVQ => Ok(2),
+        _ => Err(format!("{} does not take an address", instr).into()),
+    }
 }
 
 fn trim_line(line: &str) -> String {
@@ -89,28 +679,90 @@ fn trim_line(line: &str) -> String {
     res.replace("$", "")
 }
 
-fn apply_mapping(mapping: &HashMap<&str, u64>, line: &str) -> String {
+fn is_symbol_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '.'
+}
+
+fn is_symbol_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with(|c: char| c.is_ascii_digit())
+        && name.chars().all(is_symbol_char)
+}
+
+/// Where the statement of line starts, past its label if it has one
+fn statement_start(line: &str) -> usize {
+    let start = line.find(':').map_or(0, |colon| colon + 1);
+    line.len() - line[start..].trim_start().len()
+}
+
+/// The words of line from offset on, as where each starts and the
+/// word itself
+fn words(line: &str, mut offset: usize) -> Vec<(usize, &str)> {
+    let mut res = vec![];
+    while let Some(found) = line[offset..].find(is_symbol_char) {
+        let start = offset + found;
+        let end = line[start..]
+            .find(|c: char| !is_symbol_char(c))
+            .map_or(line.len(), |len| start + len);
+        res.push((start, &line[start..end]));
+        offset = end;
+    }
+    res
+}
+
+/// The words in the operands of line that can be symbols, which leaves
+/// out the mnemonic or directive, registers and section names
+/// line: the line without its comment
+fn operand_words(line: &str) -> Vec<Range<usize>> {
+    let start = statement_start(line);
+    let mnemonic_end = line[start..]
+        .find(char::is_whitespace)
+        .map_or(line.len(), |len| start + len);
+    if &line[start..mnemonic_end] == ".section" {
+        return vec![];
+    }
+    words(line, mnemonic_end)
+        .into_iter()
+        .filter(|&(start, _)| !line[..start].ends_with('%'))
+        .map(|(start, word)| start..start + word.len())
+        .collect()
+}
+
+/// Strips the label off line and replaces every symbol in its
+/// operands with the address or value it refers to
+/// Also returns the label the operands referred to, if any, which in
+/// an object must be the only one since a line gets a single relocation
+fn apply_mapping(
+    mapping: &Labels,
+    index: usize,
+    line: &str,
+    relocatable: bool,
+) -> Result<(String, Option<Resolved>), Box<dyn Error>> {
+    let mut reference = None;
     let mut res = String::new();
-    if line.contains(':') {
-        res.push_str(line[line.find(':').unwrap() + 1..].trim());
-    } else {
-        res = line.to_string();
-    }
-    let mut v: Vec<(&str, u64)> = Vec::new();
-    mapping.iter().for_each(|(key, &val)| v.push((key, val)));
-    v.sort_by(|a, b| {
-        let (key, _val) = a;
-        let (key_2, _val_2) = b;
-        key_2.len().partial_cmp(&key.len()).unwrap()
-    });
-    v.iter().for_each(|(key, val)| {
-        let mut expected = " ".to_string();
-        expected.push_str(key);
-        if res.contains(&expected) {
-            res = res.replace(key, &format!("0x{:x}", val));
+    let mut copied = statement_start(line);
+    for range in operand_words(line) {
+        let word = &line[range.clone()];
+        res.push_str(&line[copied..range.start]);
+        match mapping.resolve(index, word, relocatable)? {
+            Some(Resolved::Constant(val)) => res.push_str(&format!("0x{:x}", val)),
+            Some(_) if relocatable && reference.is_some() => {
+                return Err(format!(
+                    "{} is a second label on the line, which can not be relocated",
+                    word
+                )
+                .into());
+            }
+            Some(resolved) => {
+                res.push_str(&format!("0x{:x}", resolved.value()));
+                reference = Some(resolved);
+            }
+            None => res.push_str(word),
         }
-    });
-    res
+        copied = range.end;
+    }
+    res.push_str(&line[copied..]);
+    Ok((res.trim_end().to_string(), reference))
 }
 
 fn instr_size(line: &str) -> Result<u64, Box<dyn Error>> {
@@ -126,32 +778,65 @@ fn instr_size(line: &str) -> Result<u64, Box<dyn Error>> {
     Ok(val)
 }
 
-fn map_labels(lines: &[String]) -> Result<HashMap<&str, u64>, Box<dyn Error>> {
-    let mut res: HashMap<&str, u64> = HashMap::new();
-    let mut curr_addr = 0;
-    let val: Result<(), Box<dyn Error>> = lines.iter().try_for_each(|line| {
-        if line.starts_with(".pos") {
-            let position: u64 = number_parser::parse_num(&line[5..])?;
-            curr_addr = position;
+/// The index of every line that failed, and why
+type LineErrors = Vec<(usize, Box<dyn Error>)>;
+
+/// Maps the labels of every line, along with the lines that failed
+fn map_labels(
+    sources: &[SourceLine],
+    lines: &[String],
+    placements: Option<&[(String, u64)]>,
+) -> (Labels, Sections, LineErrors) {
+    let mut res = Labels::default();
+    let mut sections = Sections::new(placements);
+    let mut failed = vec![];
+    for (index, line) in lines.iter().enumerate() {
+        if let Err(e) = map_line(&mut res, &mut sections, index, line, sources) {
+            failed.push((index, source::located(&sources[index], e)));
+        }
+        res.skip_to(index + 1);
+    }
+    (res, sections, failed)
+}
+
+fn map_line(
+    res: &mut Labels,
+    sections: &mut Sections,
+    index: usize,
+    line: &str,
+    sources: &[SourceLine],
+) -> Result<(), Box<dyn Error>> {
+    if sections.switch(line)? {
+        res.define(None, index, sections.current, 0, sources)?;
+        return Ok(());
+    }
+    let section = sections.current;
+    let position = if line.starts_with(".pos") {
+        Some(sections.position(number_parser::parse_num(&line[5..])?)?)
+    } else {
+        None
+    };
+    let curr = sections.current();
+    if let Some(position) = position {
+        curr.counter = position;
+        res.define(None, index, section, curr.counter, sources)?;
+    } else {
+        let label = line.find(':').map(|end| line[..end].trim());
+        res.define(label, index, section, curr.counter, sources)?;
+        if line.contains(".quad") {
+            curr.counter += 8;
         } else {
+            let mut line = line.to_string();
             if line.contains(':') {
-                let mut split = line.split(':');
-                res.insert(split.next().unwrap().trim(), curr_addr);
+                line = line[line.find(':').unwrap() + 1..].trim().to_string();
             }
-            if line.contains(".quad") {
-                curr_addr += 8;
-            } else if !line.is_empty() {
-                let mut line = line.clone();
-                if line.contains(':') {
-                    line = line[line.find(':').unwrap() + 1..].trim().to_string();
-                }
-                curr_addr += instr_size(&line)?;
+            if !line.is_empty() {
+                curr.counter += instr_size(&line)?;
             }
         }
-        Ok(())
-    });
-    val?;
-    Ok(res)
+    }
+    curr.size = curr.size.max(curr.counter);
+    Ok(())
 }
 
 fn convert_line(line: &str) -> Result<Vec<u8>, Box<dyn Error>> {
@@ -160,11 +845,109 @@ fn convert_line(line: &str) -> Result<Vec<u8>, Box<dyn Error>> {
     }
     parser::parse(line)
 }
-
-fn read_lines(file_name: String) -> io::Result<io::Lines<io::BufReader<File>>> {
-    let file = File::open(file_name)?;
-    Ok(io::BufReader::new(file).lines())
-}
-
 // Go over each .pos, starting form there, pump values into a hashmap
 // Sort the map by key, then add values, with 000 between to the end result.
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assemble(text: &str, options: &AssemblerOptions) -> Result<Y86Assembler, Box<dyn Error>> {
+        Y86Assembler::from_text("test.ys".to_string(), text, options)
+    }
+
+    #[test]
+    fn lays_out_pos_regions_with_gaps_between_them() {
+        let text = ".pos 0x10\n.quad 0x1\n.pos 0x0\nnop\nhalt\n";
+        let assembled = assemble(text, &AssemblerOptions::default()).unwrap();
+        let bytes = assembled.bytes();
+        assert_eq!(bytes.len(), 0x18);
+        assert_eq!(&bytes[..3], &[0x10, 0x00, 0x00]);
+        assert_eq!(bytes[0x10], 1);
+    }
+
+    #[test]
+    fn reports_overlapping_pos_regions() {
+        let text = ".pos 0x0\n.quad 0x1\n.quad 0x2\n.pos 0x8\nnop\n";
+        let e = assemble(text, &AssemblerOptions::default()).err().unwrap();
+        assert_eq!(
+            e.to_string(),
+            "Overlapping regions 0x0-0xf (test.ys:2-3) and 0x8-0x8 (test.ys:5-5)"
+        );
+        // A region inside an earlier one is caught too, not only the next
+        let text = ".pos 0x0\n.quad 0x1\n.quad 0x2\n.quad 0x3\n.pos 0x20\nnop\n.pos 0x10\nhalt\n";
+        let e = assemble(text, &AssemblerOptions::default()).err().unwrap();
+        assert!(e.to_string().starts_with("Overlapping regions 0x0-0x17"));
+    }
+
+    #[test]
+    fn lets_the_last_region_win_when_overlap_is_allowed() {
+        let options = AssemblerOptions {
+            allow_overlap: true,
+            ..AssemblerOptions::default()
+        };
+        let text = ".pos 0x0\n.quad 0x1\n.pos 0x0\nnop\n";
+        let assembled = assemble(text, &options).unwrap();
+        assert_eq!(&assembled.bytes()[..2], &[0x10, 0x00]);
+    }
+
+    #[test]
+    fn reports_sections_placed_over_each_other() {
+        let mut options = AssemblerOptions::default();
+        options.place(".data=0x4").unwrap();
+        let text = ".quad 0x1\n.data\n.quad 0x2\n";
+        let e = assemble(text, &options).err().unwrap();
+        assert_eq!(
+            e.to_string(),
+            "Section .text (0x0-0x7) overlaps section .data (0x4-0xb)"
+        );
+    }
+
+    #[test]
+    fn reports_missing_operands_of_half_typed_instructions() {
+        let options = AssemblerOptions::default();
+        for text in [
+            "irmovq",
+            "irmovq $5",
+            "rrmovq %rax",
+            "addq",
+            "rmmovq %rax",
+            "rmmovq %rax, 8",
+            "mrmovq 8(%rsp",
+            "mrmovq",
+            "jmp",
+            "call",
+            "pushq",
+            "popq",
+        ] {
+            let analysis = analyze("x.ys".to_string(), text, &options);
+            assert_eq!(analysis.errors.len(), 1, "{}", text);
+            assert_eq!(
+                analysis.errors[0].to_string(),
+                "x.ys:1: Missing operand",
+                "{}",
+                text
+            );
+        }
+    }
+
+    #[test]
+    fn assembles_a_table_built_with_a_counted_rept() {
+        let text = "\
+.pos 0x0
+table:
+.rept 3, i
+.quad i
+irmovq i, %rax
+.endr
+";
+        let assembled = assemble(text, &AssemblerOptions::default()).unwrap();
+        let bytes = assembled.bytes();
+        assert_eq!(bytes.len(), 3 * 18);
+        for i in 0..3 {
+            let copy = &bytes[i * 18..(i + 1) * 18];
+            assert_eq!(copy[0], i as u8);
+            assert_eq!(&copy[8..12], &[0x30, 0xf0, i as u8, 0]);
+        }
+    }
+}
@@ -0,0 +1,318 @@
+use super::is_symbol_name;
+use super::source::SourceLine;
+use std::collections::HashMap;
+use std::error::Error;
+
+/// Every label defined in a program, keyed the way it can be
+/// referenced
+/// globals: plain `name:` labels, which must be unique
+/// locals: `.L` labels, only visible until the next global label
+/// numeric: `N:` labels, which may be redefined and are referenced
+/// as `Nf` (next definition) or `Nb` (previous definition)
+/// constants: `.equ` and externally defined constants
+/// bases: the address each section is laid out at
+#[derive(Default)]
+pub struct Labels {
+    globals: HashMap<String, Label>,
+    locals: HashMap<(usize, String), Label>,
+    numeric: HashMap<String, Vec<Label>>,
+    scopes: Vec<usize>,
+    scope: usize,
+    pub constants: HashMap<String, u64>,
+    pub bases: Vec<u64>,
+}
+
+/// What a symbol in an operand resolved to
+/// Labels and externals are addresses that move if the program is
+/// relocated, constants never do
+/// line: the index of the line defining the label
+pub enum Resolved {
+    Constant(u64),
+    Label {
+        section: usize,
+        offset: u64,
+        address: u64,
+        line: usize,
+    },
+    External(String),
+}
+
+impl Resolved {
+    /// The value to assemble in place of the symbol, externals are
+    /// left as 0 for the linker to fill in
+    pub fn value(&self) -> u64 {
+        match self {
+            Resolved::Constant(val) => *val,
+            Resolved::Label { address, .. } => *address,
+            Resolved::External(_) => 0,
+        }
+    }
+}
+
+/// Where a label points, as an offset into a section, and the index
+/// of the line defining it
+#[derive(Clone, Copy)]
+pub struct Label {
+    pub section: usize,
+    pub offset: u64,
+    pub line: usize,
+}
+
+impl Labels {
+    /// Records the label defined on line index (if any) at offset in
+    /// section
+    /// Must be called for every line, in order
+    pub fn define(
+        &mut self,
+        name: Option<&str>,
+        index: usize,
+        section: usize,
+        offset: u64,
+        sources: &[SourceLine],
+    ) -> Result<(), Box<dyn Error>> {
+        let label = Label {
+            section,
+            offset,
+            line: index,
+        };
+        match name {
+            None => {}
+            Some(name) if name.chars().all(|c| c.is_ascii_digit()) && !name.is_empty() => {
+                self.numeric
+                    .entry(name.to_string())
+                    .or_default()
+                    .push(label);
+            }
+            Some(name) if !is_symbol_name(name) => {
+                return Err(format!("Invalid label name \"{}\"", name).into());
+            }
+            Some(name) if name.starts_with(".L") => {
+                let key = (self.scope, name.to_string());
+                if let Some(previous) = self.locals.insert(key, label) {
+                    return Err(duplicate(name, &sources[previous.line]));
+                }
+            }
+            Some(name) => {
+                if let Some(previous) = self.globals.insert(name.to_string(), label) {
+                    return Err(duplicate(name, &sources[previous.line]));
+                }
+                self.scope = index + 1;
+            }
+        }
+        self.scopes.push(self.scope);
+        Ok(())
+    }
+
+    /// The address label ends up at once sections are laid out
+    pub fn address(&self, label: &Label) -> u64 {
+        self.bases[label.section] + label.offset
+    }
+
+    fn resolved(&self, label: &Label) -> Resolved {
+        Resolved::Label {
+            section: label.section,
+            offset: label.offset,
+            address: self.address(label),
+            line: label.line,
+        }
+    }
+
+    /// The global labels, in no particular order
+    pub fn globals(&self) -> impl Iterator<Item = (&str, &Label)> {
+        self.globals.iter().map(|(name, label)| (&name[..], label))
+    }
+
+    /// Every label with its name, global, local and numeric alike, in
+    /// no particular order
+    pub fn definitions(&self) -> impl Iterator<Item = (&str, &Label)> {
+        let locals = self
+            .locals
+            .iter()
+            .map(|((_, name), label)| (&name[..], label));
+        let numeric = self
+            .numeric
+            .iter()
+            .flat_map(|(name, labels)| labels.iter().map(move |label| (&name[..], label)));
+        self.globals().chain(locals).chain(numeric)
+    }
+
+    /// Keeps the scope of every line known when the lines up to lines
+    /// failed before their labels could be recorded
+    pub fn skip_to(&mut self, lines: usize) {
+        if self.scopes.len() < lines {
+            self.scopes.resize(lines, self.scope);
+        }
+    }
+
+    /// Resolves a symbol referenced on line index
+    /// Returns None if word is not a symbol at all (a plain number),
+    /// and fails if it looks like a symbol that was never defined
+    /// relocatable: whether unknown global names are external
+    /// symbols, left for the linker, instead of an error
+    pub fn resolve(
+        &self,
+        index: usize,
+        word: &str,
+        relocatable: bool,
+    ) -> Result<Option<Resolved>, Box<dyn Error>> {
+        if word.starts_with(|c: char| c.is_ascii_digit()) {
+            return self.resolve_numeric(index, word);
+        }
+        let found = if word.starts_with(".L") {
+            self.locals
+                .get(&(self.scopes[index], word.to_string()))
+                .map(|label| self.resolved(label))
+        } else {
+            self.globals
+                .get(word)
+                .map(|label| self.resolved(label))
+                .or_else(|| self.constants.get(word).map(|&val| Resolved::Constant(val)))
+        };
+        match found {
+            Some(resolved) => Ok(Some(resolved)),
+            None if relocatable && !word.starts_with(".L") => {
+                Ok(Some(Resolved::External(word.to_string())))
+            }
+            None => Err(format!("Undefined symbol {}", word).into()),
+        }
+    }
+
+    fn resolve_numeric(
+        &self,
+        index: usize,
+        word: &str,
+    ) -> Result<Option<Resolved>, Box<dyn Error>> {
+        let (name, direction) = word.split_at(word.len() - 1);
+        if !name.chars().all(|c| c.is_ascii_digit()) || (direction != "f" && direction != "b") {
+            return Ok(None);
+        }
+        let definitions = self.numeric.get(name).map_or(&[][..], |v| &v[..]);
+        let found = if direction == "f" {
+            definitions.iter().find(|label| label.line > index)
+        } else {
+            definitions.iter().rev().find(|label| label.line <= index)
+        };
+        match found {
+            Some(label) => Ok(Some(self.resolved(label))),
+            None => Err(format!("Undefined local label {}", word).into()),
+        }
+    }
+}
+
+fn duplicate(name: &str, previous: &SourceLine) -> Box<dyn Error> {
+    format!(
+        "Duplicate label {} (first defined at {}:{})",
+        name, previous.file, previous.line
+    )
+    .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Defines one label per line, in order, every line a quad after the
+    /// previous one in a section laid out at 0x100
+    fn define(names: &[Option<&str>]) -> Result<Labels, Box<dyn Error>> {
+        let sources: Vec<SourceLine> = (0..names.len())
+            .map(|index| SourceLine {
+                file: "test.ys".into(),
+                line: index + 1,
+                text: String::new(),
+            })
+            .collect();
+        let mut labels = Labels {
+            bases: vec![0x100],
+            ..Labels::default()
+        };
+        for (index, name) in names.iter().enumerate() {
+            labels.define(*name, index, 0, index as u64 * 8, &sources)?;
+        }
+        Ok(labels)
+    }
+
+    fn address(labels: &Labels, index: usize, word: &str) -> u64 {
+        labels.resolve(index, word, false).unwrap().unwrap().value()
+    }
+
+    #[test]
+    fn resolves_numeric_labels_forwards_and_backwards() {
+        let labels = define(&[Some("1"), None, Some("1"), None, Some("2")]).unwrap();
+        assert_eq!(address(&labels, 1, "1b"), 0x100);
+        assert_eq!(address(&labels, 1, "1f"), 0x110);
+        // A label on the referencing line itself counts as behind it
+        assert_eq!(address(&labels, 2, "1b"), 0x110);
+        assert_eq!(address(&labels, 0, "1f"), 0x110);
+        assert_eq!(address(&labels, 3, "2f"), 0x120);
+        let e = labels.resolve(3, "1f", false).err().unwrap();
+        assert_eq!(e.to_string(), "Undefined local label 1f");
+        assert!(labels.resolve(3, "2b", false).is_err());
+        // Plain numbers are not symbols
+        assert!(labels.resolve(0, "12", false).unwrap().is_none());
+        assert!(labels.resolve(0, "1x", false).unwrap().is_none());
+    }
+
+    #[test]
+    fn scopes_local_labels_to_the_global_label_before_them() {
+        let labels = define(&[
+            Some("first"),
+            Some(".Lloop"),
+            None,
+            Some("second"),
+            Some(".Lloop"),
+            None,
+        ])
+        .unwrap();
+        assert_eq!(address(&labels, 2, ".Lloop"), 0x108);
+        assert_eq!(address(&labels, 5, ".Lloop"), 0x120);
+        assert_eq!(address(&labels, 5, "first"), 0x100);
+        let labels = define(&[Some("first"), None, Some("second"), Some(".Lend")]).unwrap();
+        assert!(labels.resolve(1, ".Lend", false).is_err());
+        // Locals are never left for the linker
+        assert!(labels.resolve(1, ".Lend", true).is_err());
+    }
+
+    #[test]
+    fn resolves_constants_and_externals() {
+        let mut labels = define(&[Some("main")]).unwrap();
+        labels.constants.insert("SIZE".to_string(), 8);
+        assert!(matches!(
+            labels.resolve(0, "SIZE", false).unwrap(),
+            Some(Resolved::Constant(8))
+        ));
+        assert!(matches!(
+            labels.resolve(0, "main", false).unwrap(),
+            Some(Resolved::Label {
+                address: 0x100,
+                line: 0,
+                ..
+            })
+        ));
+        let e = labels.resolve(0, "printf", false).err().unwrap();
+        assert_eq!(e.to_string(), "Undefined symbol printf");
+        assert!(matches!(
+            labels.resolve(0, "printf", true).unwrap(),
+            Some(Resolved::External(name)) if name == "printf"
+        ));
+    }
+
+    #[test]
+    fn rejects_duplicate_and_invalid_labels() {
+        let e = define(&[Some("main"), None, Some("main")]).err().unwrap();
+        assert_eq!(
+            e.to_string(),
+            "Duplicate label main (first defined at test.ys:1)"
+        );
+        let e = define(&[Some("a"), Some(".Lx"), Some(".Lx")])
+            .err()
+            .unwrap();
+        assert_eq!(
+            e.to_string(),
+            "Duplicate label .Lx (first defined at test.ys:2)"
+        );
+        assert!(define(&[Some("a"), Some(".Lx"), Some("b"), Some(".Lx")]).is_ok());
+        assert!(define(&[Some("1"), Some("1")]).is_ok());
+        let e = define(&[Some("not a label")]).err().unwrap();
+        assert_eq!(e.to_string(), "Invalid label name \"not a label\"");
+    }
+}
@@ -66,11 +66,25 @@ pub fn parse(line: &str) -> Result<Vec<u8>, Box<dyn Error>> {
         parse_quad(line)
     } else {
         let mut split_line = line.split(' ');
-        let instr = Parser::new(&split_line.next().unwrap().to_string())?;
+        let instr = Parser::new(split_line.next().unwrap())?;
         instr.parse(line)
     }
 }
 
+/// The mnemonics of every instruction, sorted
+pub fn mnemonics() -> Vec<&'static str> {
+    let mut res: Vec<&'static str> = INSTRUCTION_CODE.keys().copied().collect();
+    res.sort_unstable();
+    res
+}
+
+/// The registers as written in operands, such as `%rax`, by number
+pub fn register_names() -> Vec<&'static str> {
+    let mut res: Vec<(&'static str, u8)> = REGISTERS.iter().map(|(&k, &v)| (k, v)).collect();
+    res.sort_unstable_by_key(|&(_, number)| number);
+    res.into_iter().map(|(name, _)| name).collect()
+}
+
 pub fn get_icode_from_string(string: &str) -> Result<ICode, Box<dyn Error>> {
     let b: u8 = match INSTRUCTION_CODE.get(string) {
         Some(&val) => val,
@@ -89,6 +103,7 @@ pub fn parse_quad(line: &str) -> Result<Vec<u8>, Box<dyn Error>> {
     Ok(res)
 }
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Copy, Clone, FromPrimitive, PartialEq)]
 pub enum ICode {
     IHALT = 0x0,
@@ -106,6 +121,7 @@ pub enum ICode {
     IINVALID = 0x10,
     ITOOSHORT = 0x11,
 }
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Copy, Clone, FromPrimitive)]
 pub enum Register {
     RRAX = 0x0,
@@ -152,6 +168,17 @@ impl Display for InvalidRegisterError {
     }
 }
 
+#[derive(Debug)]
+struct MissingOperandError;
+
+impl std::error::Error for MissingOperandError {}
+
+impl Display for MissingOperandError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "Missing operand")
+    }
+}
+
 pub fn get_icode_from_byte(b: u8) -> Result<ICode, Box<dyn std::error::Error>> {
     match FromPrimitive::from_u8(b >> 4) {
         Some(val) => Ok(val),
@@ -161,7 +188,7 @@ pub fn get_icode_from_byte(b: u8) -> Result<ICode, Box<dyn std::error::Error>> {
 
 impl Parser {
     pub fn new(instr: &str) -> Result<Self, Box<dyn std::error::Error>> {
-        let instruction_type = match INSTRUCTION_CODE.get(&instr[..]) {
+        let instruction_type = match INSTRUCTION_CODE.get(instr) {
             Some(&val) => val,
             None => return Err(Box::new(InvalidInstructionError)),
         };
@@ -199,6 +226,11 @@ fn get_register(value: &str) -> Result<u8, Box<dyn std::error::Error>> {
     }
 }
 
+/// An operand the line should have had, which may still be being typed
+fn operand(value: Option<&str>) -> Result<&str, Box<dyn std::error::Error>> {
+    value.ok_or_else(|| Box::new(MissingOperandError) as Box<dyn std::error::Error>)
+}
+
 fn push_le(vec: &mut Vec<u8>, val: u64) {
     for i in 0..8 {
         vec.push((val >> (i * 8)) as u8);
@@ -214,8 +246,8 @@ fn parse_irmovq(line: &str, res: &mut Vec<u8>) -> Result<(), Box<dyn std::error:
     while first.is_some() && first.unwrap() == "" {
         first = instr_val.next();
     }
-    let val_c = get_immediate(first.unwrap().trim())?;
-    let reg = get_register(split.next().unwrap().trim())?;
+    let val_c = get_immediate(operand(first)?.trim())?;
+    let reg = get_register(operand(split.next())?.trim())?;
     let b: u8 = form_byte(0x0F, reg);
     res.push(b);
     push_le(res, val_c);
@@ -231,8 +263,8 @@ fn parse_rr_opq(line: &str, res: &mut Vec<u8>) -> Result<(), Box<dyn std::error:
     while first.is_some() && first.unwrap() == "" {
         first = reg_split.next();
     }
-    let reg_a = get_register(first.unwrap().trim())?;
-    let reg_b = get_register(split.next().unwrap().trim())?;
+    let reg_a = get_register(operand(first)?.trim())?;
+    let reg_b = get_register(operand(split.next())?.trim())?;
     res.push(form_byte(reg_a, reg_b));
     Ok(())
 }
@@ -245,12 +277,12 @@ fn parse_mrmovq(line: &str, res: &mut Vec<u8>) -> Result<(), Box<dyn std::error:
     while first.is_some() && first.unwrap() == "" {
         first = imm_reg_split.next();
     }
-    let mem_brackets = first.unwrap().trim();
+    let mem_brackets = operand(first)?.trim();
     let mut num_reg_b = mem_brackets.split('(');
     let val_c = get_immediate(num_reg_b.next().unwrap().trim())?;
-    let mut reg_only = num_reg_b.next().unwrap().split(')');
+    let mut reg_only = operand(num_reg_b.next())?.split(')');
     let reg_b = get_register(reg_only.next().unwrap().trim())?;
-    let reg_a = get_register(split.next().unwrap().trim())?;
+    let reg_a = get_register(operand(split.next())?.trim())?;
     res.push(form_byte(reg_a, reg_b));
     push_le(res, val_c);
     Ok(())
@@ -264,11 +296,11 @@ fn parse_rmmovq(line: &str, res: &mut Vec<u8>) -> Result<(), Box<dyn std::error:
     while first.is_some() && first.unwrap() == "" {
         first = instr_reg_a.next();
     }
-    let reg_a = get_register(first.unwrap().trim())?;
-    let mem_brackets = split.next().unwrap().trim();
+    let reg_a = get_register(operand(first)?.trim())?;
+    let mem_brackets = operand(split.next())?.trim();
     let mut num_reg_b = mem_brackets.split('(');
-    let val_c = get_immediate(num_reg_b.next().unwrap().trim().trim())?;
-    let mut reg_only = num_reg_b.next().unwrap().trim().split(')');
+    let val_c = get_immediate(num_reg_b.next().unwrap().trim())?;
+    let mut reg_only = operand(num_reg_b.next())?.trim().split(')');
     let reg_b = get_register(reg_only.next().unwrap().trim())?;
     res.push(form_byte(reg_a, reg_b));
     push_le(res, val_c);
@@ -281,7 +313,7 @@ fn parse_jxx_call(line: &str, res: &mut Vec<u8>) -> Result<(), Box<dyn std::erro
     while first.is_some() && first.unwrap() == "" {
         first = split.next();
     }
-    let val_c = get_immediate(first.unwrap().trim())?;
+    let val_c = get_immediate(operand(first)?.trim())?;
     push_le(res, val_c);
     Ok(())
 }
@@ -293,7 +325,7 @@ fn parse_push_pop(line: &str, res: &mut Vec<u8>) -> Result<(), Box<dyn std::erro
     while first.is_some() && first.unwrap() == "" {
         first = split.next();
     }
-    let reg_a = get_register(first.unwrap().trim())?;
+    let reg_a = get_register(operand(first)?.trim())?;
     res.push(form_byte(reg_a, 0x0F));
     Ok(())
 }
@@ -0,0 +1,546 @@
+use super::source::{self, Includes, SourceLine};
+use super::{is_symbol_char, is_symbol_name, trim_line};
+use crate::expression::{self, Context};
+use std::collections::HashMap;
+use std::error::Error;
+
+/// Constants visible to directives while preprocessing: the
+/// definitions passed in through AssemblerOptions, then any `.equ`
+/// seen so far in the source
+/// labels: the labels defined so far, so `.ifdef` only sees a label
+/// defined above it, the same as a constant
+/// repeated: how many lines `.rept` blocks have expanded to so far
+struct Symbols<'a> {
+    defines: &'a HashMap<String, u64>,
+    constants: HashMap<String, u64>,
+    definitions: HashMap<String, SourceLine>,
+    labels: Vec<String>,
+    globals: Vec<(String, SourceLine)>,
+    repeated: u64,
+}
+
+impl<'a> Context for Symbols<'a> {
+    fn symbol(&self, name: &str) -> Option<u64> {
+        self.constants
+            .get(name)
+            .or_else(|| self.defines.get(name))
+            .copied()
+    }
+}
+
+impl<'a> Symbols<'a> {
+    fn is_defined(&self, name: &str) -> bool {
+        self.symbol(name).is_some() || self.labels.iter().any(|label| label == name)
+    }
+}
+
+struct Conditional {
+    opened: SourceLine,
+    // Whether the enclosing block is being assembled at all
+    outer_active: bool,
+    // Whether a branch of this block has already been taken
+    taken: bool,
+    active: bool,
+    seen_else: bool,
+}
+
+/// The lines left to assemble once directives have been applied,
+/// along with every constant in scope, whether defined in the source
+/// or passed in, the lines defining the `.equ` ones, and the names
+/// exported with `.global`
+pub struct Preprocessed {
+    pub lines: Vec<SourceLine>,
+    pub constants: HashMap<String, u64>,
+    pub definitions: HashMap<String, SourceLine>,
+    pub globals: Vec<(String, SourceLine)>,
+}
+
+/// The most times a `.rept` block may be repeated
+const MAX_REPEAT: u64 = 0x10000;
+
+/// The most lines all `.rept` blocks together may expand to, nested
+/// ones included
+const MAX_REPEATED_LINES: u64 = 0x40000;
+
+/// Applies the conditional assembly directives (`.if`, `.ifdef`,
+/// `.ifndef`, `.else`, `.endif`), expands `.include` and `.rept`
+/// blocks and collects `.equ` constants and `.global` names
+/// Only includes in a block being assembled are read, so they can be
+/// guarded with `.ifndef`
+/// lines: the lines of the file being assembled
+/// defines: constants defined outside of the source, such as `-D NAME=VALUE`
+/// includes: the files being read, starting with the file being assembled
+pub fn preprocess(
+    lines: &[SourceLine],
+    defines: &HashMap<String, u64>,
+    includes: &mut Includes,
+) -> Result<Preprocessed, Box<dyn Error>> {
+    let mut symbols = Symbols {
+        defines,
+        constants: HashMap::new(),
+        definitions: HashMap::new(),
+        labels: vec![],
+        globals: vec![],
+        repeated: 0,
+    };
+    let mut res = vec![];
+    process_block(lines, &mut symbols, includes, &mut res)?;
+    let mut constants = defines.clone();
+    constants.extend(symbols.constants);
+    Ok(Preprocessed {
+        lines: res,
+        constants,
+        definitions: symbols.definitions,
+        globals: symbols.globals,
+    })
+}
+
+/// Processes the lines of a whole file or of one copy of a `.rept`
+/// body, whose conditional blocks must all be closed within it
+fn process_block(
+    lines: &[SourceLine],
+    symbols: &mut Symbols,
+    includes: &mut Includes,
+    res: &mut Vec<SourceLine>,
+) -> Result<(), Box<dyn Error>> {
+    let mut stack: Vec<Conditional> = vec![];
+    process_lines(lines, &mut stack, symbols, includes, res)?;
+    if let Some(cond) = stack.pop() {
+        let e = "conditional block is missing its .endif".into();
+        return Err(source::located(&cond.opened, e));
+    }
+    Ok(())
+}
+
+fn process_lines(
+    lines: &[SourceLine],
+    stack: &mut Vec<Conditional>,
+    symbols: &mut Symbols,
+    includes: &mut Includes,
+    res: &mut Vec<SourceLine>,
+) -> Result<(), Box<dyn Error>> {
+    let mut index = 0;
+    while index < lines.len() {
+        let line = &lines[index];
+        index += 1;
+        let active = stack.last().is_none_or(|cond| cond.active);
+        if active && directive_of(line) == ".rept" {
+            let end = matching_endr(lines, index).map_err(|e| source::located(line, e))?;
+            let expanded = expand_rept(line, &lines[index..end], symbols)
+                .map_err(|e| source::located(line, e))?;
+            for body in expanded {
+                process_block(&body, symbols, includes, res)?;
+            }
+            index = end + 1;
+            continue;
+        }
+        if active && directive_of(line) == ".include" {
+            let included = includes.open(line)?;
+            process_block(&included, symbols, includes, res)?;
+            includes.close();
+            continue;
+        }
+        let directive =
+            apply_directive(line, active, stack, symbols).map_err(|e| source::located(line, e))?;
+        if !directive && active {
+            let trimmed = trim_line(&line.text);
+            if trimmed.contains(':') {
+                let label = trimmed[..trimmed.find(':').unwrap()].trim();
+                symbols.labels.push(label.to_string());
+            }
+            res.push(line.clone());
+        }
+    }
+    Ok(())
+}
+
+fn directive_of(line: &SourceLine) -> String {
+    let trimmed = trim_line(&line.text);
+    trimmed.split_whitespace().next().unwrap_or("").to_string()
+}
+
+/// Finds the `.endr` closing the `.rept` whose body starts at start
+fn matching_endr(lines: &[SourceLine], start: usize) -> Result<usize, Box<dyn Error>> {
+    let mut depth = 0;
+    for (index, line) in lines.iter().enumerate().skip(start) {
+        match directive_of(line).as_str() {
+            ".rept" => depth += 1,
+            ".endr" if depth == 0 => return Ok(index),
+            ".endr" => depth -= 1,
+            _ => {}
+        }
+    }
+    Err(".rept block is missing its .endr".into())
+}
+
+/// Copies body as many times as the `.rept` on line asks for,
+/// replacing the optional counter symbol with the iteration number
+/// Every copy keeps pointing at the original source line
+fn expand_rept(
+    line: &SourceLine,
+    body: &[SourceLine],
+    symbols: &mut Symbols,
+) -> Result<Vec<Vec<SourceLine>>, Box<dyn Error>> {
+    let trimmed = trim_line(&line.text);
+    let args = trimmed[".rept".len()..].trim();
+    let mut split = args.splitn(2, ',');
+    let count = expression::evaluate(split.next().unwrap(), symbols)?;
+    let counter = match split.next().map(str::trim) {
+        Some(name) if is_symbol_name(name) => Some(name),
+        Some(_) => return Err(".rept expects a count and an optional counter symbol".into()),
+        None => None,
+    };
+    if count > MAX_REPEAT {
+        let message = format!(".rept count {} is over the limit of {}", count, MAX_REPEAT);
+        return Err(message.into());
+    }
+    symbols.repeated = symbols
+        .repeated
+        .saturating_add(count.saturating_mul(body.len() as u64));
+    if symbols.repeated > MAX_REPEATED_LINES {
+        let message = format!(
+            ".rept blocks expand to over the limit of {} lines",
+            MAX_REPEATED_LINES
+        );
+        return Err(message.into());
+    }
+    let res = (0..count)
+        .map(|iteration| {
+            body.iter()
+                .map(|body_line| SourceLine {
+                    text: match counter {
+                        Some(name) => replace_symbol(&body_line.text, name, &iteration.to_string()),
+                        None => body_line.text.clone(),
+                    },
+                    ..body_line.clone()
+                })
+                .collect()
+        })
+        .collect();
+    Ok(res)
+}
+
+/// Replaces every whole-word occurrence of name in text with value
+fn replace_symbol(text: &str, name: &str, value: &str) -> String {
+    let mut res = String::new();
+    let mut rest = text;
+    while let Some(found) = rest.find(name) {
+        let before = rest[..found].chars().next_back();
+        let after = rest[found + name.len()..].chars().next();
+        res.push_str(&rest[..found]);
+        if before.is_some_and(is_symbol_char) || after.is_some_and(is_symbol_char) {
+            res.push_str(name);
+        } else {
+            res.push_str(value);
+        }
+        rest = &rest[found + name.len()..];
+    }
+    res.push_str(rest);
+    res
+}
+
+/// Handles line if it is a directive, returning whether it was one
+fn apply_directive(
+    line: &SourceLine,
+    active: bool,
+    stack: &mut Vec<Conditional>,
+    symbols: &mut Symbols,
+) -> Result<bool, Box<dyn Error>> {
+    let trimmed = trim_line(&line.text);
+    let mut split = trimmed.splitn(2, char::is_whitespace);
+    let directive = split.next().unwrap();
+    let args = split.next().unwrap_or("").trim();
+    match directive {
+        ".if" | ".ifdef" | ".ifndef" => {
+            let cond = if !active {
+                false
+            } else if directive == ".if" {
+                expression::evaluate(args, symbols)? != 0
+            } else {
+                symbols.is_defined(single_name(directive, args)?) == (directive == ".ifdef")
+            };
+            stack.push(Conditional {
+                opened: line.clone(),
+                outer_active: active,
+                taken: cond,
+                active: cond,
+                seen_else: false,
+            });
+        }
+        ".else" => {
+            let cond = match stack.last_mut() {
+                Some(cond) if !cond.seen_else => cond,
+                Some(_) => return Err(".else repeated in the same conditional block".into()),
+                None => return Err(".else without a matching .if".into()),
+            };
+            cond.seen_else = true;
+            cond.active = cond.outer_active && !cond.taken;
+            cond.taken = true;
+        }
+        ".endif" => {
+            if stack.pop().is_none() {
+                return Err(".endif without a matching .if".into());
+            }
+        }
+        ".equ" if active => {
+            let mut split = args.splitn(2, ',');
+            let name = split.next().unwrap().trim();
+            let value = match split.next() {
+                Some(value) if !name.is_empty() => expression::evaluate(value, symbols)?,
+                _ => return Err(".equ expects a name and a value".into()),
+            };
+            symbols.constants.insert(name.to_string(), value);
+            symbols.definitions.insert(name.to_string(), line.clone());
+        }
+        ".global" | ".globl" if active => {
+            for name in args.split(',').map(str::trim) {
+                if !is_symbol_name(name) {
+                    return Err(format!("{} expects a list of symbol names", directive).into());
+                }
+                symbols.globals.push((name.to_string(), line.clone()));
+            }
+        }
+        ".endr" if active => return Err(".endr without a matching .rept".into()),
+        ".equ" | ".endr" | ".global" | ".globl" => {}
+        _ => return Ok(false),
+    }
+    Ok(true)
+}
+
+fn single_name<'a>(directive: &str, args: &'a str) -> Result<&'a str, Box<dyn Error>> {
+    if args.is_empty() || args.contains(char::is_whitespace) {
+        return Err(format!("{} expects a single symbol name", directive).into());
+    }
+    Ok(args)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    fn run(text: &str, defines: &HashMap<String, u64>) -> Result<Preprocessed, Box<dyn Error>> {
+        let path = Path::new("test.ys");
+        let mut includes = Includes::new(path, &[]);
+        preprocess(&source::load_text(path, text)?, defines, &mut includes)
+    }
+
+    /// The text of the lines left to assemble
+    fn texts(text: &str) -> Vec<String> {
+        let preprocessed = run(text, &HashMap::new()).unwrap();
+        preprocessed
+            .lines
+            .into_iter()
+            .map(|line| line.text)
+            .collect()
+    }
+
+    fn error(text: &str) -> String {
+        run(text, &HashMap::new()).err().unwrap().to_string()
+    }
+
+    #[test]
+    fn expands_includes_in_place() {
+        let dir = std::env::temp_dir().join(format!("y86-{}-preprocess", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("defs.ys"), ".equ SIZE, 8\nirmovq SIZE, %rax\n").unwrap();
+        fs::write(dir.join("self.ys"), ".include \"self.ys\"\n").unwrap();
+        let root = dir.join("main.ys");
+        let load = |text: &str| {
+            let paths: Vec<PathBuf> = vec![];
+            let mut includes = Includes::new(&root, &paths);
+            let lines = source::load_text(&root, text)?;
+            preprocess(&lines, &HashMap::new(), &mut includes)
+        };
+        let preprocessed = load("nop\n.include \"defs.ys\"\nhalt\n").unwrap();
+        let lines: Vec<(&str, usize)> = preprocessed
+            .lines
+            .iter()
+            .map(|line| (&line.text[..], line.line))
+            .collect();
+        assert_eq!(
+            lines,
+            vec![("nop", 1), ("irmovq SIZE, %rax", 2), ("halt", 3)]
+        );
+        assert!(preprocessed.lines[1].file.ends_with("defs.ys"));
+        assert_eq!(preprocessed.constants["SIZE"], 8);
+        // An include guarded by a block that is not assembled is never read
+        let guarded = load(".ifdef MISSING\n.include \"nowhere.ys\"\n.endif\n");
+        assert!(guarded.unwrap().lines.is_empty());
+        let e = load(".include \"self.ys\"\n").err().unwrap().to_string();
+        assert!(e.contains("recursive include of \"self.ys\""));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn assembles_only_the_branch_whose_condition_holds() {
+        let text = "\
+.equ SIZE, 4
+.if SIZE > 2
+big
+.else
+small
+.endif
+.ifdef SIZE
+defined
+.endif
+.ifndef SIZE
+undefined
+.endif";
+        assert_eq!(texts(text), vec!["big", "defined"]);
+    }
+
+    #[test]
+    fn sees_definitions_passed_in_and_labels_defined_above() {
+        let mut defines = HashMap::new();
+        defines.insert("DEBUG".to_string(), 0);
+        let text = "\
+.ifdef later
+too early
+.endif
+later: nop
+.ifdef later
+seen
+.endif
+.if DEBUG
+debug
+.else
+release
+.endif";
+        let lines: Vec<String> = run(text, &defines)
+            .unwrap()
+            .lines
+            .into_iter()
+            .map(|line| line.text)
+            .collect();
+        assert_eq!(lines, vec!["later: nop", "seen", "release"]);
+    }
+
+    #[test]
+    fn takes_no_branch_inside_a_block_that_is_not_assembled() {
+        let text = "\
+.if 0
+.if 1
+inner
+.else
+inner else
+.endif
+.equ HIDDEN, 1
+.else
+.if 0
+nested
+.else
+nested else
+.endif
+.endif
+.ifdef HIDDEN
+hidden
+.endif";
+        assert_eq!(texts(text), vec!["nested else"]);
+    }
+
+    #[test]
+    fn reports_unbalanced_conditionals() {
+        assert_eq!(
+            error("nop\n.else\n"),
+            "test.ys:2: .else without a matching .if"
+        );
+        assert_eq!(
+            error(".endif\n"),
+            "test.ys:1: .endif without a matching .if"
+        );
+        assert_eq!(
+            error(".if 1\n.else\n.else\n.endif\n"),
+            "test.ys:3: .else repeated in the same conditional block"
+        );
+        assert_eq!(
+            error("nop\n.if 1\n.if 0\n.endif\n"),
+            "test.ys:2: conditional block is missing its .endif"
+        );
+        assert_eq!(
+            error(".ifdef A B\n.endif\n"),
+            "test.ys:1: .ifdef expects a single symbol name"
+        );
+    }
+
+    #[test]
+    fn repeats_a_block_with_its_counter() {
+        let text = "\
+.rept 3, i
+.quad i
+.endr
+.rept 0
+never
+.endr";
+        assert_eq!(texts(text), vec![".quad 0", ".quad 1", ".quad 2"]);
+    }
+
+    #[test]
+    fn replaces_only_the_whole_counter() {
+        let text = ".rept 2, n\nirmovq n, %rn_n\n.endr\n";
+        assert_eq!(texts(text), vec!["irmovq 0, %rn_n", "irmovq 1, %rn_n"]);
+    }
+
+    #[test]
+    fn expands_nested_repeats_and_conditionals_within_each_copy() {
+        let text = "\
+.rept 2, i
+.rept 2, j
+.if i == j
+same
+.else
+different
+.endif
+.endr
+.endr";
+        assert_eq!(texts(text), vec!["same", "different", "different", "same"]);
+    }
+
+    #[test]
+    fn caps_the_repeat_count() {
+        assert!(texts(&format!(".rept {}\n.endr\n", MAX_REPEAT)).is_empty());
+        assert_eq!(
+            error(&format!("nop\n.rept {}\nnop\n.endr\n", MAX_REPEAT + 1)),
+            format!(
+                "test.ys:2: .rept count {} is over the limit of {}",
+                MAX_REPEAT + 1,
+                MAX_REPEAT
+            )
+        );
+    }
+
+    #[test]
+    fn caps_the_lines_nested_repeats_expand_to() {
+        // Each block is well under MAX_REPEAT, but not all of them together
+        let text = ".rept 0x100\n.rept 0x100\n.rept 0x100\nnop\n.endr\n.endr\n.endr\n";
+        assert_eq!(
+            error(text),
+            format!(
+                "test.ys:3: .rept blocks expand to over the limit of {} lines",
+                MAX_REPEATED_LINES
+            )
+        );
+    }
+
+    #[test]
+    fn reports_unbalanced_repeats() {
+        assert_eq!(
+            error(".rept 2\nnop\n"),
+            "test.ys:1: .rept block is missing its .endr"
+        );
+        assert_eq!(
+            error(".endr\n"),
+            "test.ys:1: .endr without a matching .rept"
+        );
+        assert_eq!(
+            error(".rept 2, 3\n.endr\n"),
+            "test.ys:1: .rept expects a count and an optional counter symbol"
+        );
+        // A conditional cannot span copies of the body
+        assert_eq!(
+            error(".rept 2\n.if 1\n.endr\n.endif\n"),
+            "test.ys:2: conditional block is missing its .endif"
+        );
+    }
+}
@@ -0,0 +1,160 @@
+use super::labels::{Labels, Resolved};
+use super::source::SourceLine;
+use super::{operand_words, words};
+use std::collections::HashMap;
+
+/// What a symbol written in the source is to the assembler
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Role {
+    Label,
+    Constant,
+    Reference,
+}
+
+/// Where a symbol is written in the source
+/// line: counting from 1
+/// start, end: byte offsets of the name within the line
+#[derive(Clone, PartialEq, Debug)]
+pub struct Span {
+    pub file: String,
+    pub line: usize,
+    pub start: usize,
+    pub end: usize,
+}
+
+/// A symbol written in the source, where it is defined or where an
+/// operand refers to it
+/// definition: where the symbol it stands for is defined, the span
+/// itself for a definition, None for a symbol defined outside of the
+/// source or not at all
+#[derive(Clone, Debug)]
+pub struct SymbolUse {
+    pub name: String,
+    pub role: Role,
+    pub span: Span,
+    pub definition: Option<Span>,
+}
+
+impl SymbolUse {
+    pub fn is_definition(&self) -> bool {
+        self.role != Role::Reference
+    }
+}
+
+/// The code of line, without its comment
+fn code(line: &SourceLine) -> &str {
+    &line.text[..line.text.find('#').unwrap_or(line.text.len())]
+}
+
+fn span(line: &SourceLine, start: usize, name: &str) -> Span {
+    Span {
+        file: line.file.to_string(),
+        line: line.line,
+        start,
+        end: start + name.len(),
+    }
+}
+
+/// Where the arguments of the directive on line start
+fn arguments(line: &SourceLine, directive: &str) -> usize {
+    code(line)
+        .find(directive)
+        .map_or(0, |start| start + directive.len())
+}
+
+/// Finds every label and constant defined in lines, and every symbol
+/// their operands, `.equ` values and `.global` names refer to
+/// Lines repeated by `.rept` show up once
+/// labels: the labels of lines, as mapped by map_labels
+/// definitions: the lines defining each `.equ` constant
+/// globals: the names exported with `.global`, and where
+/// relocatable: whether unknown names are external symbols
+pub fn collect(
+    lines: &[SourceLine],
+    labels: &Labels,
+    definitions: &HashMap<String, SourceLine>,
+    globals: &[(String, SourceLine)],
+    relocatable: bool,
+) -> Vec<SymbolUse> {
+    let mut res = vec![];
+    let mut label_spans = HashMap::new();
+    for (name, label) in labels.definitions() {
+        let line = &lines[label.line];
+        if let Some(start) = code(line).find(name) {
+            label_spans.insert(label.line, span(line, start, name));
+            res.push(definition(name, Role::Label, span(line, start, name)));
+        }
+    }
+    let constant_spans: HashMap<&str, Span> = definitions
+        .iter()
+        .filter_map(|(name, line)| {
+            let (start, _) = words(code(line), arguments(line, ".equ"))
+                .into_iter()
+                .next()?;
+            Some((&name[..], span(line, start, name)))
+        })
+        .collect();
+    for (name, line) in definitions {
+        if let Some(span) = constant_spans.get(&name[..]) {
+            res.push(definition(name, Role::Constant, span.clone()));
+        }
+        // The value of a constant can only refer to other constants
+        let code = code(line);
+        let value = code.find(',').map_or(code.len(), |comma| comma + 1);
+        for (start, word) in words(code, value) {
+            if !word.starts_with(|c: char| c.is_ascii_digit()) {
+                let definition = constant_spans.get(word).cloned();
+                res.push(reference(line, start, word, definition));
+            }
+        }
+    }
+    for (name, line) in globals {
+        let directive = code(line).split_whitespace().next().unwrap_or("");
+        let definition = labels
+            .globals()
+            .find(|(label, _)| label == name)
+            .and_then(|(_, label)| label_spans.get(&label.line).cloned());
+        for (start, word) in words(code(line), arguments(line, directive)) {
+            if word == name {
+                res.push(reference(line, start, word, definition.clone()));
+            }
+        }
+    }
+    for (index, line) in lines.iter().enumerate() {
+        let code = code(line);
+        for range in operand_words(code) {
+            let word = &code[range.clone()];
+            let definition = match labels.resolve(index, word, relocatable) {
+                Ok(None) => continue,
+                Ok(Some(Resolved::Label { line, .. })) => label_spans.get(&line).cloned(),
+                Ok(Some(Resolved::Constant(_))) => constant_spans.get(word).cloned(),
+                Ok(Some(Resolved::External(_))) | Err(_) => None,
+            };
+            res.push(reference(line, range.start, word, definition));
+        }
+    }
+    res.sort_by(|a, b| {
+        let (a, b) = (&a.span, &b.span);
+        (&a.file, a.line, a.start).cmp(&(&b.file, b.line, b.start))
+    });
+    res.dedup_by(|a, b| a.span == b.span && a.role == b.role);
+    res
+}
+
+fn definition(name: &str, role: Role, span: Span) -> SymbolUse {
+    SymbolUse {
+        name: name.to_string(),
+        role,
+        definition: Some(span.clone()),
+        span,
+    }
+}
+
+fn reference(line: &SourceLine, start: usize, name: &str, definition: Option<Span>) -> SymbolUse {
+    SymbolUse {
+        name: name.to_string(),
+        role: Role::Reference,
+        span: span(line, start, name),
+        definition,
+    }
+}
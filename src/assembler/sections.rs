@@ -0,0 +1,281 @@
+use super::is_symbol_name;
+use crate::section_map::{self, SectionInfo};
+use std::error::Error;
+
+/// A section being assembled, with its own location counter
+/// size: how far the location counter ever got
+/// base: the address the section is laid out at
+#[derive(Clone)]
+pub struct SectionState {
+    pub name: String,
+    pub writable: bool,
+    pub executable: bool,
+    pub counter: u64,
+    pub size: u64,
+    pub base: u64,
+}
+
+/// Every section seen so far, and the one lines are assembled into
+/// Programs that never switch sections live entirely in `.text`
+/// placements: the sections placed at a fixed address, None in an
+/// object, whose addresses are only decided by the linker
+#[derive(Clone)]
+pub struct Sections {
+    pub list: Vec<SectionState>,
+    pub current: usize,
+    placements: Option<Vec<(String, u64)>>,
+}
+
+impl Default for Sections {
+    fn default() -> Self {
+        Self::new(Some(&[]))
+    }
+}
+
+impl Sections {
+    /// Starts out in `.text`
+    pub fn new(placements: Option<&[(String, u64)]>) -> Self {
+        let mut res = Sections {
+            list: vec![],
+            current: 0,
+            placements: placements.map(<[_]>::to_vec),
+        };
+        res.enter(".text", None);
+        res
+    }
+
+    /// Switches sections if line is `.text`, `.data`, `.bss` or
+    /// `.section NAME[, "flags"]`, returning whether it was
+    pub fn switch(&mut self, line: &str) -> Result<bool, Box<dyn Error>> {
+        let mut split = line.splitn(2, char::is_whitespace);
+        let directive = split.next().unwrap();
+        let args = split.next().unwrap_or("").trim();
+        match directive {
+            ".text" | ".data" | ".bss" if args.is_empty() => self.enter(directive, None),
+            ".text" | ".data" | ".bss" => {
+                return Err(format!("{} does not take any arguments", directive).into())
+            }
+            ".section" => {
+                let mut split = args.splitn(2, ',');
+                let name = split.next().unwrap().trim();
+                if !is_symbol_name(name) {
+                    return Err(".section expects a section name".into());
+                }
+                let flags = match split.next().map(str::trim) {
+                    Some(flags)
+                        if flags.len() >= 2 && flags.starts_with('"') && flags.ends_with('"') =>
+                    {
+                        let flags = &flags[1..flags.len() - 1];
+                        if !flags.chars().all(|c| "rwx".contains(c)) {
+                            return Err(format!("Invalid section flags \"{}\"", flags).into());
+                        }
+                        Some((flags.contains('w'), flags.contains('x')))
+                    }
+                    Some(_) => return Err(".section flags must be quoted".into()),
+                    None => None,
+                };
+                self.enter(name, flags);
+            }
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    /// Flags given for a section seen before replace its flags, so that
+    /// `.section .text, "rx"` applies to the `.text` every program starts in
+    fn enter(&mut self, name: &str, flags: Option<(bool, bool)>) {
+        self.current = match self.list.iter().position(|section| section.name == name) {
+            Some(index) => index,
+            None => {
+                let (writable, executable) = SectionInfo::default_flags(name);
+                self.list.push(SectionState {
+                    name: name.to_string(),
+                    writable,
+                    executable,
+                    counter: 0,
+                    size: 0,
+                    base: 0,
+                });
+                self.list.len() - 1
+            }
+        };
+        if let Some((writable, executable)) = flags {
+            let section = self.current();
+            section.writable = writable;
+            section.executable = executable;
+        }
+    }
+
+    pub fn current(&mut self) -> &mut SectionState {
+        &mut self.list[self.current]
+    }
+
+    /// Whether the current section only reserves zero-filled space
+    pub fn is_bss(&self) -> bool {
+        self.list[self.current].name == ".bss"
+    }
+
+    /// The offset into the current section of the address a `.pos`
+    /// moves to
+    /// Only works in a section whose address is known before it is laid
+    /// out, `.text` or a placed section, while in an object the address
+    /// is taken as an offset
+    pub fn position(&self, address: u64) -> Result<u64, Box<dyn Error>> {
+        let placements = match &self.placements {
+            Some(placements) => placements,
+            None => return Ok(address),
+        };
+        let section = &self.list[self.current];
+        let base = match placements.iter().find(|(name, _)| *name == section.name) {
+            Some(&(_, base)) => base,
+            None if section.name == ".text" => 0,
+            None => {
+                let message = format!(
+                    ".pos in {} needs the section placed at a fixed address",
+                    section.name
+                );
+                return Err(message.into());
+            }
+        };
+        address.checked_sub(base).ok_or_else(|| {
+            let message = format!(
+                ".pos 0x{:x} is before the start of {} at 0x{:x}",
+                address, section.name, base
+            );
+            message.into()
+        })
+    }
+
+    /// Assigns every section its base address
+    pub fn lay_out(&mut self, placements: &[(String, u64)]) {
+        let sizes: Vec<(&str, u64)> = self
+            .list
+            .iter()
+            .map(|section| (&section.name[..], section.size))
+            .collect();
+        let bases = section_map::lay_out(&sizes, placements);
+        self.list
+            .iter_mut()
+            .zip(bases)
+            .for_each(|(section, base)| section.base = base);
+    }
+
+    /// The non-empty sections, as seen from the executer
+    pub fn infos(&self) -> Vec<SectionInfo> {
+        self.list
+            .iter()
+            .filter(|section| section.size > 0)
+            .map(|section| SectionInfo {
+                name: section.name.clone(),
+                start: section.base,
+                size: section.size,
+                writable: section.writable,
+                executable: section.executable,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn flags(sections: &Sections, name: &str) -> (bool, bool) {
+        let section = sections.list.iter().find(|s| s.name == name).unwrap();
+        (section.writable, section.executable)
+    }
+
+    #[test]
+    fn switches_between_sections_and_back() {
+        let mut sections = Sections::default();
+        assert_eq!(sections.list[sections.current].name, ".text");
+        assert!(sections.switch(".data").unwrap());
+        sections.current().counter = 8;
+        assert!(sections.switch(".bss").unwrap());
+        assert!(sections.is_bss());
+        assert!(sections.switch(".data").unwrap());
+        assert_eq!(sections.current().counter, 8);
+        assert!(!sections.switch("nop").unwrap());
+        assert_eq!(sections.list.len(), 3);
+    }
+
+    #[test]
+    fn applies_section_flags() {
+        let mut sections = Sections::default();
+        assert_eq!(flags(&sections, ".text"), (true, true));
+        sections.switch(".section .text, \"rx\"").unwrap();
+        assert_eq!(flags(&sections, ".text"), (false, true));
+        sections.switch(".section .rodata, \"r\"").unwrap();
+        assert_eq!(flags(&sections, ".rodata"), (false, false));
+        sections.switch(".section .stack").unwrap();
+        assert_eq!(flags(&sections, ".stack"), (true, false));
+    }
+
+    #[test]
+    fn rejects_malformed_section_directives() {
+        let mut sections = Sections::default();
+        let error =
+            |sections: &mut Sections, line: &str| sections.switch(line).err().unwrap().to_string();
+        assert_eq!(
+            error(&mut sections, ".data 0x10"),
+            ".data does not take any arguments"
+        );
+        assert_eq!(
+            error(&mut sections, ".section"),
+            ".section expects a section name"
+        );
+        assert_eq!(
+            error(&mut sections, ".section .rom, rx"),
+            ".section flags must be quoted"
+        );
+        assert_eq!(
+            error(&mut sections, ".section .rom, \"rq\""),
+            "Invalid section flags \"rq\""
+        );
+    }
+
+    #[test]
+    fn takes_pos_relative_to_where_the_section_is_placed() {
+        let placements = vec![(".data".to_string(), 0x400)];
+        let mut sections = Sections::new(Some(&placements));
+        assert_eq!(sections.position(0x10).unwrap(), 0x10);
+        sections.switch(".data").unwrap();
+        assert_eq!(sections.position(0x410).unwrap(), 0x10);
+        let e = sections.position(0x10).err().unwrap();
+        assert_eq!(
+            e.to_string(),
+            ".pos 0x10 is before the start of .data at 0x400"
+        );
+        sections.switch(".bss").unwrap();
+        assert!(sections.position(0x10).is_err());
+        // In an object, .pos is an offset wherever the section ends up
+        let mut sections = Sections::new(None);
+        sections.switch(".bss").unwrap();
+        assert_eq!(sections.position(0x10).unwrap(), 0x10);
+    }
+
+    #[test]
+    fn lays_out_sections_and_leaves_out_empty_ones() {
+        let mut sections = Sections::default();
+        sections.current().size = 0x12;
+        sections.switch(".bss").unwrap();
+        sections.current().size = 0x8;
+        sections.switch(".data").unwrap();
+        sections.current().size = 0x4;
+        sections.switch(".section .empty").unwrap();
+        sections.lay_out(&[]);
+        let infos: Vec<(String, u64, u64)> = sections
+            .infos()
+            .into_iter()
+            .map(|info| (info.name, info.start, info.size))
+            .collect();
+        assert_eq!(
+            infos,
+            vec![
+                (".text".to_string(), 0x0, 0x12),
+                (".bss".to_string(), 0x20, 0x8),
+                (".data".to_string(), 0x18, 0x4),
+            ]
+        );
+    }
+}
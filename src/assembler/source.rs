@@ -0,0 +1,297 @@
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+use std::fs::File;
+use std::io::{self, BufRead};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+/// A single line of Y86 source, remembering the file and line
+/// number it was read from so diagnostics can point back at it
+#[derive(Clone)]
+pub struct SourceLine {
+    pub file: Rc<str>,
+    pub line: usize,
+    pub text: String,
+}
+
+/// An error attributed to a specific file:line in the source
+#[derive(Debug)]
+pub struct AssemblyError {
+    file: String,
+    line: usize,
+    message: String,
+}
+
+impl Error for AssemblyError {}
+
+impl AssemblyError {
+    /// The file the error was found in
+    pub fn file(&self) -> &str {
+        &self.file
+    }
+
+    /// The line the error was found on, counting from 1
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    /// What went wrong, without the location
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+impl Display for AssemblyError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.file, self.line, self.message)
+    }
+}
+
+/// Attaches the location of line to err, unless err already
+/// carries a location of its own
+pub fn located(line: &SourceLine, err: Box<dyn Error>) -> Box<dyn Error> {
+    if err.is::<AssemblyError>() {
+        return err;
+    }
+    Box::new(AssemblyError {
+        file: line.file.to_string(),
+        line: line.line,
+        message: err.to_string(),
+    })
+}
+
+/// Reads the lines of file_name, leaving any `.include` in it for the
+/// preprocessor to expand
+pub fn load(file_name: &Path) -> Result<Vec<SourceLine>, Box<dyn Error>> {
+    load_lines(file_name, read_lines(file_name)?)
+}
+
+/// Same as load, but with the contents of file_name given as text,
+/// such as a file being edited that was not saved yet
+pub fn load_text(file_name: &Path, text: &str) -> Result<Vec<SourceLine>, Box<dyn Error>> {
+    load_lines(file_name, text.lines().map(|line| Ok(line.to_string())))
+}
+
+fn load_lines<I: Iterator<Item = io::Result<String>>>(
+    path: &Path,
+    texts: I,
+) -> Result<Vec<SourceLine>, Box<dyn Error>> {
+    let file: Rc<str> = path.display().to_string().into();
+    let mut res = vec![];
+    for (index, text) in texts.enumerate() {
+        res.push(SourceLine {
+            file: file.clone(),
+            line: index + 1,
+            text: text?,
+        });
+    }
+    Ok(res)
+}
+
+/// The files being read, outermost first, so that an `.include` is
+/// looked up next to the file containing it and cycles are caught
+/// paths: directories searched, in order, when an include is not
+/// found next to the file that includes it
+pub struct Includes<'a> {
+    paths: &'a [PathBuf],
+    stack: Vec<(PathBuf, Rc<str>)>,
+}
+
+impl<'a> Includes<'a> {
+    /// root: the file being assembled
+    pub fn new(root: &Path, paths: &'a [PathBuf]) -> Self {
+        let canonical = root.canonicalize().unwrap_or_else(|_| root.to_path_buf());
+        Includes {
+            paths,
+            stack: vec![(canonical, root.display().to_string().into())],
+        }
+    }
+
+    /// Reads the file pulled in by the `.include` on line, which stays
+    /// open until close is called
+    pub fn open(&mut self, line: &SourceLine) -> Result<Vec<SourceLine>, Box<dyn Error>> {
+        let name = include_target(&line.text).map_err(|e| located(line, e))?;
+        let target =
+            resolve_include(Path::new(&*line.file), &name, self.paths).ok_or_else(|| {
+                located(
+                    line,
+                    format!("could not find include file \"{}\"", name).into(),
+                )
+            })?;
+        let canonical = target.canonicalize()?;
+        if let Some(start) = self.stack.iter().position(|(p, _)| *p == canonical) {
+            let mut chain: Vec<&str> = self.stack[start..].iter().map(|(_, f)| &f[..]).collect();
+            chain.push(&name);
+            let message = format!("recursive include of \"{}\" ({})", name, chain.join(" -> "));
+            return Err(located(line, message.into()));
+        }
+        let lines = load(&target).map_err(|e| located(line, e))?;
+        self.stack
+            .push((canonical, target.display().to_string().into()));
+        Ok(lines)
+    }
+
+    /// Closes the file opened last
+    pub fn close(&mut self) {
+        self.stack.pop();
+    }
+}
+
+/// The quoted file name of an `.include` directive
+fn include_target(line: &str) -> Result<String, Box<dyn Error>> {
+    let mut line = line.trim();
+    if line.contains('#') {
+        line = line[..line.find('#').unwrap()].trim();
+    }
+    let name = line[".include".len()..].trim();
+    if name.len() < 2 || !name.starts_with('"') || !name.ends_with('"') {
+        return Err(".include expects a quoted file name".into());
+    }
+    Ok(name[1..name.len() - 1].to_string())
+}
+
+fn resolve_include(including: &Path, name: &str, include_paths: &[PathBuf]) -> Option<PathBuf> {
+    let relative = including
+        .parent()
+        .unwrap_or_else(|| Path::new(""))
+        .join(name);
+    if relative.is_file() {
+        return Some(relative);
+    }
+    include_paths
+        .iter()
+        .map(|dir| dir.join(name))
+        .find(|candidate| candidate.is_file())
+}
+
+fn read_lines(path: &Path) -> io::Result<io::Lines<io::BufReader<File>>> {
+    let file = File::open(path)?;
+    Ok(io::BufReader::new(file).lines())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    /// A directory of its own for each test, removed once it is done
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("y86-{}-{}", std::process::id(), name));
+            fs::create_dir_all(&path).unwrap();
+            TempDir(path)
+        }
+
+        fn write(&self, name: &str, text: &str) -> PathBuf {
+            let path = self.0.join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, text).unwrap();
+            path
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            fs::remove_dir_all(&self.0).ok();
+        }
+    }
+
+    fn include_line(file: &Path, text: &str) -> SourceLine {
+        SourceLine {
+            file: file.display().to_string().into(),
+            line: 1,
+            text: text.to_string(),
+        }
+    }
+
+    #[test]
+    fn finds_includes_next_to_the_including_file_first() {
+        let dir = TempDir::new("include-relative");
+        let root = dir.write("main.ys", "");
+        dir.write("defs.ys", "next\n");
+        dir.write("lib/defs.ys", "lib\n");
+        let paths = vec![dir.0.join("lib")];
+        let mut includes = Includes::new(&root, &paths);
+        let lines = includes
+            .open(&include_line(&root, ".include \"defs.ys\""))
+            .unwrap();
+        assert_eq!(lines[0].text, "next");
+        assert_eq!(lines[0].line, 1);
+        assert!(lines[0].file.ends_with("defs.ys"));
+    }
+
+    #[test]
+    fn searches_the_include_paths_in_order() {
+        let dir = TempDir::new("include-paths");
+        let root = dir.write("src/main.ys", "");
+        dir.write("first/macros.ys", "first\n");
+        dir.write("second/macros.ys", "second\n");
+        let paths = vec![
+            dir.0.join("missing"),
+            dir.0.join("first"),
+            dir.0.join("second"),
+        ];
+        let mut includes = Includes::new(&root, &paths);
+        let lines = includes
+            .open(&include_line(&root, ".include \"macros.ys\" # macros"))
+            .unwrap();
+        assert_eq!(lines[0].text, "first");
+        let e = includes
+            .open(&include_line(&root, ".include \"nowhere.ys\""))
+            .err()
+            .unwrap();
+        assert!(e
+            .to_string()
+            .ends_with("could not find include file \"nowhere.ys\""));
+    }
+
+    #[test]
+    fn rejects_an_include_without_a_quoted_name() {
+        let dir = TempDir::new("include-unquoted");
+        let root = dir.write("main.ys", "");
+        let mut includes = Includes::new(&root, &[]);
+        let e = includes
+            .open(&include_line(&root, ".include defs.ys"))
+            .err()
+            .unwrap();
+        assert_eq!(
+            e.to_string(),
+            format!("{}:1: .include expects a quoted file name", root.display())
+        );
+    }
+
+    #[test]
+    fn reports_the_chain_of_a_recursive_include() {
+        let dir = TempDir::new("include-cycle");
+        let root = dir.write("main.ys", "");
+        let a = dir.write("a.ys", "");
+        dir.write("b.ys", "");
+        let mut includes = Includes::new(&root, &[]);
+        includes
+            .open(&include_line(&root, ".include \"a.ys\""))
+            .unwrap();
+        let b = dir.0.join("b.ys");
+        includes
+            .open(&include_line(&a, ".include \"b.ys\""))
+            .unwrap();
+        let e = includes
+            .open(&include_line(&b, ".include \"a.ys\""))
+            .err()
+            .unwrap();
+        assert!(e.to_string().contains("recursive include of \"a.ys\""));
+        assert!(e.to_string().ends_with("b.ys -> a.ys)"));
+        // Including the same file again once it is closed is fine
+        includes.close();
+        includes.close();
+        includes
+            .open(&include_line(&root, ".include \"a.ys\""))
+            .unwrap();
+        let e = includes
+            .open(&include_line(&a, ".include \"main.ys\""))
+            .err()
+            .unwrap();
+        assert!(e.to_string().contains("recursive include of \"main.ys\""));
+    }
+}
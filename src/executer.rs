@@ -1,13 +1,82 @@
+mod breakpoints;
 mod commands;
+mod dap_server;
+mod gdb_server;
+mod history;
 mod instructions;
 mod print;
+mod trace;
+mod tui;
+mod watchpoints;
+use crate::assembler::Y86Assembler;
+use crate::expression::Context;
+use crate::line_table::{self, LineInfo};
+use crate::section_map::{self, SectionInfo};
+use crate::symbol_table::{self, SymbolInfo, SymbolKind};
+use std::collections::HashMap;
 use std::error::Error;
+use std::ffi::OsStr;
+use std::fmt::{self, Display, Formatter};
 use std::fs::File;
 use std::io::prelude::*;
-use std::io::stdin;
+use std::io::{stdin, stdout};
+use std::path::{Path, PathBuf};
 
-use instructions::Instruction;
+use rustyline::error::ReadlineError;
+use rustyline::DefaultEditor;
+
+use breakpoints::Breakpoints;
+use history::{History, Restore};
+use instructions::{
+    ICode, Instruction, InvalidICode, Register, CC_OVERFLOW_MASK, CC_SIGN_MASK, CC_ZERO_MASK,
+};
 use print::*;
+pub use trace::{Trace, TraceFormat};
+use watchpoints::{Hit, Watchpoints};
+
+/// Why State::run_until stopped
+/// Done: done said so after an instruction
+/// Halted: the PC reached a halt, which is not executed
+/// Breakpoint: the breakpoints that stopped execution before the
+/// instruction at the PC, by id
+/// Watchpoint: the instruction just executed accessed memory being
+/// watched, and the accesses
+/// MismatchedReturn: the instruction just executed was a ret that does
+/// not match its call, and how
+/// StepLimit: the step limit was reached, after that many instructions
+/// Error: the instruction at the PC could not be decoded or executed,
+/// or a breakpoint condition could not be evaluated
+pub enum StopReason {
+    Done,
+    Halted,
+    Breakpoint(Vec<usize>),
+    Watchpoint(Instruction, Vec<Hit>),
+    MismatchedReturn(Instruction, String),
+    StepLimit(u64),
+    Error(Box<dyn Error>),
+}
+
+impl Display for StopReason {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            StopReason::Done => write!(f, "Stopped"),
+            StopReason::Halted => write!(f, "The program halted"),
+            StopReason::Breakpoint(ids) => {
+                let ids: Vec<String> = ids.iter().map(usize::to_string).collect();
+                write!(f, "Breakpoint {}", ids.join(", "))
+            }
+            StopReason::Watchpoint(_, hits) => {
+                let hits: Vec<String> = hits.iter().map(Hit::to_string).collect();
+                write!(f, "{}", hits.join(", "))
+            }
+            StopReason::MismatchedReturn(_, problem) => write!(f, "Mismatched return: {}", problem),
+            StopReason::StepLimit(steps) => {
+                write!(f, "Stopped after {} instructions, the step limit", steps)
+            }
+            StopReason::Error(e) => write!(f, "{}", e),
+        }
+    }
+}
 
 /// A state representing the Y86 program
 /// registers: a vector representing the registers
@@ -16,12 +85,96 @@ use print::*;
 /// program_size: u64, the size of the program memory
 /// program_counter: the program counter at all times, pointing to an address
 /// in memory
+/// sections: the known sections of the program, memory outside of them
+/// is unrestricted
+/// symbols: the symbol table of the program, empty if none was loaded
+/// lines: the line table of the program, empty if none was loaded
+/// sources: the contents of the source files named by the line table
+/// history: the undo log used to execute in reverse
+/// step_limit: how many instructions a single command may execute
+/// frames: the calls that have not returned yet, outermost first
+/// previous_registers, previous_condition_code: the registers and
+/// condition codes at the stop before this one, to show what changed
+/// trace: where every instruction executed is written, if anywhere
+/// trace_error: why the trace stopped being written, until a front end
+/// takes it to show
+/// breakpoints, watchpoints: where the debugger stops the program
+/// scripts: the scripts being run by `source`, outermost first
 pub struct State {
     registers: Vec<u64>,
     program_map: Vec<u8>,
     condition_code: u8,
     program_size: u64,
     program_counter: u64,
+    sections: Vec<SectionInfo>,
+    symbols: Vec<SymbolInfo>,
+    lines: Vec<LineInfo>,
+    sources: HashMap<String, Vec<String>>,
+    history: History,
+    step_limit: u64,
+    frames: Vec<Frame>,
+    previous_registers: Vec<u64>,
+    previous_condition_code: u8,
+    trace: Option<Trace>,
+    trace_error: Option<Box<dyn Error>>,
+    breakpoints: Breakpoints,
+    watchpoints: Watchpoints,
+    scripts: Vec<PathBuf>,
+}
+
+/// A call that has not returned yet
+/// call_site: the address of the call instruction
+/// function: the address that was called
+/// slot: where the return address was pushed
+/// return_address: where the matching ret should go back to
+#[derive(Clone, Debug)]
+pub struct Frame {
+    pub call_site: u64,
+    pub function: u64,
+    pub slot: u64,
+    pub return_address: u64,
+}
+
+// How many instructions a single command may execute unless told otherwise
+const DEFAULT_STEP_LIMIT: u64 = 1_000_000;
+
+// Where the commands typed in earlier sessions are kept, in the home
+// directory
+const HISTORY_FILE: &str = ".y86_history";
+
+// The commands run at the start of every session, in the home directory
+const INIT_FILE: &str = ".ydbinit";
+
+/// An access that the section containing address does not allow
+#[derive(Debug)]
+pub struct ProtectionError {
+    address: u64,
+    section: String,
+    access: &'static str,
+}
+
+impl Error for ProtectionError {}
+
+impl Display for ProtectionError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Cannot {} 0x{:x}, section {} does not allow it",
+            self.access, self.address, self.section
+        )
+    }
+}
+
+/// An access past the end of the program memory
+#[derive(Debug)]
+pub struct OutOfBoundsError(u64);
+
+impl Error for OutOfBoundsError {}
+
+impl Display for OutOfBoundsError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "Address 0x{:x} is out of bounds", self.0)
+    }
 }
 
 impl State {
@@ -30,17 +183,151 @@ impl State {
     /// file
     pub fn new(file_name: String) -> Result<Self, Box<dyn Error>> {
         let mut file = File::open(file_name)?;
-        let program_size = file.metadata()?.len();
-        let program_counter = 0;
         let mut program_map = Vec::new();
         file.read_to_end(&mut program_map)?;
-        Ok(State {
+        Ok(State::from_bytes(program_map))
+    }
+
+    /// Creates a new state of the program from machine code in memory
+    /// program_map: the bytes of the program, starting at address 0
+    pub fn from_bytes(program_map: Vec<u8>) -> Self {
+        let program_size = program_map.len() as u64;
+        let program_counter = 0;
+        State {
             registers: vec![0; 16],
             program_map,
             program_size,
             condition_code: 0,
             program_counter,
-        })
+            sections: vec![],
+            symbols: vec![],
+            lines: vec![],
+            sources: HashMap::new(),
+            history: History::default(),
+            step_limit: DEFAULT_STEP_LIMIT,
+            frames: vec![],
+            previous_registers: vec![0; 16],
+            previous_condition_code: 0,
+            trace: None,
+            trace_error: None,
+            breakpoints: Breakpoints::default(),
+            watchpoints: Watchpoints::default(),
+            scripts: vec![],
+        }
+    }
+
+    /// Loads a section map saved by the assembler or the linker
+    /// file_name: string representing the file name of the section map
+    pub fn load_section_map(&mut self, file_name: String) -> Result<(), Box<dyn Error>> {
+        self.sections = section_map::from_file(file_name)?;
+        Ok(())
+    }
+
+    /// Sets the sections of the program
+    /// sections: where each section is laid out and its permissions
+    pub fn set_sections(&mut self, sections: Vec<SectionInfo>) {
+        self.sections = sections;
+    }
+
+    /// Gets the sections of the program
+    pub fn get_sections(&self) -> &[SectionInfo] {
+        &self.sections
+    }
+
+    /// Finds the section containing an address
+    /// address: u64 representing the address
+    pub fn section_at(&self, address: u64) -> Option<&SectionInfo> {
+        self.sections
+            .iter()
+            .find(|section| section.contains(address))
+    }
+
+    /// Loads a symbol table saved by the assembler or the linker
+    /// file_name: string representing the file name of the symbol table
+    pub fn load_symbol_table(&mut self, file_name: String) -> Result<(), Box<dyn Error>> {
+        self.symbols = symbol_table::from_file(file_name)?;
+        Ok(())
+    }
+
+    /// Sets the symbols of the program
+    /// symbols: the labels and constants of the program
+    pub fn set_symbols(&mut self, symbols: Vec<SymbolInfo>) {
+        self.symbols = symbols;
+    }
+
+    /// Gets the symbols of the program
+    pub fn get_symbols(&self) -> &[SymbolInfo] {
+        &self.symbols
+    }
+
+    /// Finds a symbol by name
+    /// name: the name of the label or constant
+    pub fn find_symbol(&self, name: &str) -> Option<&SymbolInfo> {
+        self.symbols.iter().find(|symbol| symbol.name == name)
+    }
+
+    /// Loads a line table saved by the assembler, along with whichever
+    /// of the source files it names can still be read
+    /// file_name: string representing the file name of the line table
+    pub fn load_line_table(&mut self, file_name: String) -> Result<(), Box<dyn Error>> {
+        self.set_lines(line_table::from_file(file_name)?);
+        Ok(())
+    }
+
+    /// Sets the line table of the program, reading the source files it
+    /// names when they exist
+    /// lines: the source line behind each address
+    pub fn set_lines(&mut self, lines: Vec<LineInfo>) {
+        self.sources.clear();
+        for line in &lines {
+            if self.sources.contains_key(&line.file) {
+                continue;
+            }
+            if let Ok(text) = std::fs::read_to_string(&line.file) {
+                let text = text.lines().map(str::to_string).collect();
+                self.sources.insert(line.file.clone(), text);
+            }
+        }
+        self.lines = lines;
+    }
+
+    /// Gets the line table of the program
+    pub fn get_lines(&self) -> &[LineInfo] {
+        &self.lines
+    }
+
+    /// Finds the source line that emitted the byte at an address
+    /// address: u64 representing the address
+    pub fn line_at(&self, address: u64) -> Option<&LineInfo> {
+        self.lines.iter().find(|line| line.contains(address))
+    }
+
+    /// Gets the text of a source line, from the source file if it could
+    /// be read, otherwise from the line table
+    /// file: the name of the source file
+    /// line: the line number, starting at 1
+    pub fn source_line(&self, file: &str, line: usize) -> Option<String> {
+        match self.sources.get(file) {
+            Some(text) => text.get(line.wrapping_sub(1)).cloned(),
+            None => self
+                .lines
+                .iter()
+                .find(|info| info.file == file && info.line == line)
+                .map(|info| info.text.clone()),
+        }
+    }
+
+    /// Fails if an address lies in a section that is not executable
+    /// address: u64 representing the address to fetch from
+    pub fn check_executable(&self, address: u64) -> Result<(), Box<dyn Error>> {
+        match self.section_at(address) {
+            Some(section) if !section.executable => Err(Box::new(ProtectionError {
+                address,
+                section: section.name.clone(),
+                access: "execute",
+            })),
+            _ => Ok(()),
+        }
     }
 
     /// Retrieve the value of a register
@@ -53,6 +340,9 @@ impl State {
     /// register_id: u8 representing the id of the register
     /// value: u64 representing the new value to put in the register
     pub fn set_register(&mut self, register_id: u8, value: u64) {
+        if let Some(trace) = &mut self.trace {
+            trace.register(register_id, value);
+        }
         self.registers[register_id as usize] = value;
     }
 
@@ -67,15 +357,52 @@ impl State {
         self.condition_code = value;
     }
 
+    /// Gets the registers as they were at the previous stop
+    pub fn get_previous_registers(&self) -> &[u64] {
+        &self.previous_registers
+    }
+
+    /// Gets the condition codes as they were at the previous stop
+    pub fn get_previous_condition_code(&self) -> u8 {
+        self.previous_condition_code
+    }
+
+    /// The Y86 status of the program: AOK while it can run, HLT at a
+    /// halt, ADR when the PC cannot be executed from and INS at an
+    /// invalid instruction
+    pub fn status(&self) -> &'static str {
+        if self.check_executable(self.get_pc()).is_err() {
+            return "ADR";
+        }
+        match Instruction::decode(self, self.get_pc()) {
+            Ok(instr) if instr.get_icode() == ICode::IHALT => "HLT",
+            Ok(_) => "AOK",
+            Err(e) if e.is::<InvalidICode>() => "INS",
+            Err(_) => "ADR",
+        }
+    }
+
     /// Gets the program size
     pub fn get_program_size(&self) -> u64 {
         self.program_size
     }
 
-    /// Reads a memory address in little-endian
+    /// Reads a memory address in little-endian on behalf of the program,
+    /// which read watchpoints see
     /// address: u64 representing the address
     /// Returns a Result, fails if memory is out of bounds
-    pub fn read_le(&self, address: u64) -> Result<u64, Box<dyn Error>> {
+    pub fn read_le(&mut self, address: u64) -> Result<u64, Box<dyn Error>> {
+        let res = self.peek_le(address)?;
+        self.watchpoints.read(address, res);
+        Ok(res)
+    }
+
+    /// Reads a memory address in little-endian without triggering
+    /// watchpoints, for the debugger itself and instruction fetches
+    /// address: u64 representing the address
+    /// Returns a Result, fails if memory is out of bounds
+    pub fn peek_le(&self, address: u64) -> Result<u64, Box<dyn Error>> {
+        self.check_bounds(address)?;
         let mut res: u64 = 0;
         for i in 0..8 {
             res = (res << 8) | self.program_map[(address + 7 - i) as usize] as u64;
@@ -83,18 +410,420 @@ impl State {
         Ok(res)
     }
 
+    /// Reads a single byte without triggering watchpoints
+    /// address: u64 representing the address
+    /// Returns a Result, fails if memory is out of bounds
+    pub fn peek_byte(&self, address: u64) -> Result<u8, Box<dyn Error>> {
+        match self.program_map.get(address as usize) {
+            Some(byte) => Ok(*byte),
+            None => Err(Box::new(OutOfBoundsError(address))),
+        }
+    }
+
+    /// Writes a single byte on behalf of the debugger, ignoring section
+    /// permissions, which neither watchpoints nor the history see
+    /// address: u64 representing the address
+    /// value: the byte to write
+    /// Returns a Result, fails if memory is out of bounds
+    pub fn poke_byte(&mut self, address: u64, value: u8) -> Result<(), Box<dyn Error>> {
+        match self.program_map.get_mut(address as usize) {
+            Some(byte) => {
+                *byte = value;
+                Ok(())
+            }
+            None => Err(Box::new(OutOfBoundsError(address))),
+        }
+    }
+
     /// Writes to memory address in little-endian
     /// address: u64 representing the address
     /// value: u64 representing the value to insert into memory
-    /// Returns a result, fails if memory is out of bounds
+    /// Returns a result, fails if memory is out of bounds or read only
     pub fn write_le(&mut self, address: u64, value: u64) -> Result<(), Box<dyn Error>> {
+        self.check_bounds(address)?;
         for i in 0..8 {
-            let val = ((value >> 8 * i) & 0xFF) as u8;
+            match self.section_at(address + i) {
+                Some(section) if !section.writable => {
+                    return Err(Box::new(ProtectionError {
+                        address: address + i,
+                        section: section.name.clone(),
+                        access: "write",
+                    }))
+                }
+                _ => (),
+            }
+        }
+        let old = self.peek_le(address)?;
+        self.watchpoints.write(address, old, value);
+        self.history.write(address, old);
+        if let Some(trace) = &mut self.trace {
+            trace.memory(address, value);
+        }
+        for i in 0..8 {
+            let val = ((value >> (8 * i)) & 0xFF) as u8;
             self.program_map[(address + i) as usize] = val;
         }
         Ok(())
     }
 
+    /// Records the state before executing an instruction, so that it
+    /// can be undone
+    pub fn begin_instruction(&mut self) {
+        self.history.begin(
+            &self.registers,
+            self.condition_code,
+            self.program_counter,
+            &self.frames,
+        );
+    }
+
+    /// Marks the end of the instruction being recorded
+    pub fn end_instruction(&mut self) {
+        self.history.end();
+    }
+
+    /// Puts back everything the instruction being recorded changed,
+    /// leaving it out of the history
+    pub fn abort_instruction(&mut self) {
+        if let Some(restore) = self.history.abort() {
+            self.restore(restore);
+        }
+    }
+
+    /// Makes a change on behalf of the debugger, such as `set` or
+    /// `jump`, recording it like an instruction so that stepping back
+    /// undoes it rather than skipping over it
+    /// change: makes the change, which is undone if it fails
+    pub fn record_change<F>(&mut self, change: F) -> Result<(), Box<dyn Error>>
+    where
+        F: FnOnce(&mut State) -> Result<(), Box<dyn Error>>,
+    {
+        self.begin_instruction();
+        let res = change(self);
+        if res.is_err() {
+            self.abort_instruction();
+        } else {
+            self.end_instruction();
+        }
+        res
+    }
+
+    /// Executes the instruction at the PC, then keeps executing until
+    /// one of the reasons in StopReason stops it
+    /// Breakpoints at the PC to begin with are passed over, since that
+    /// is where execution stopped last
+    /// done: given the instruction just executed and the resulting state
+    pub fn run_until<F>(&mut self, mut done: F) -> StopReason
+    where
+        F: FnMut(&Instruction, &State) -> bool,
+    {
+        let mut steps: u64 = 0;
+        loop {
+            let instr = match Instruction::new(self) {
+                Ok(instr) => instr,
+                Err(e) => return StopReason::Error(e),
+            };
+            if instr.get_icode() == ICode::IHALT {
+                return StopReason::Halted;
+            }
+            if steps > 0 {
+                match self.hit_breakpoints(instr.get_location()) {
+                    Ok(ids) if ids.is_empty() => (),
+                    Ok(ids) => return StopReason::Breakpoint(ids),
+                    Err(e) => return StopReason::Error(e),
+                }
+            }
+            if steps >= self.get_step_limit() {
+                return StopReason::StepLimit(steps);
+            }
+            self.watchpoints.take_hits();
+            let problem = match self.execute(&instr) {
+                Ok(problem) => problem,
+                Err(e) => return StopReason::Error(e),
+            };
+            steps += 1;
+            let hits = self.watchpoints.take_hits();
+            if !hits.is_empty() {
+                return StopReason::Watchpoint(instr, hits);
+            }
+            if let Some(problem) = problem {
+                return StopReason::MismatchedReturn(instr, problem);
+            }
+            if done(&instr, self) {
+                return StopReason::Done;
+            }
+        }
+    }
+
+    /// Gets the breakpoints of the program
+    pub fn get_breakpoints(&self) -> &Breakpoints {
+        &self.breakpoints
+    }
+
+    /// Gets the breakpoints of the program to add or delete some
+    pub fn get_breakpoints_mut(&mut self) -> &mut Breakpoints {
+        &mut self.breakpoints
+    }
+
+    /// Gets the watchpoints of the program
+    pub fn get_watchpoints(&self) -> &Watchpoints {
+        &self.watchpoints
+    }
+
+    /// Gets the watchpoints of the program to add or delete some
+    pub fn get_watchpoints_mut(&mut self) -> &mut Watchpoints {
+        &mut self.watchpoints
+    }
+
+    /// Gets the scripts being run by `source`, outermost first
+    pub fn get_scripts(&self) -> &[PathBuf] {
+        &self.scripts
+    }
+
+    /// Records that a script is being run from within the ones before it
+    pub fn push_script(&mut self, path: PathBuf) {
+        self.scripts.push(path);
+    }
+
+    /// Records that the innermost script is done
+    pub fn pop_script(&mut self) {
+        self.scripts.pop();
+    }
+
+    /// Decides whether a breakpoint stops execution at address, counting
+    /// the hit, see Breakpoints::hit
+    fn hit_breakpoints(&mut self, address: u64) -> Result<Vec<usize>, Box<dyn Error>> {
+        // The conditions read the state the breakpoints are part of
+        let mut breakpoints = std::mem::take(&mut self.breakpoints);
+        let res = breakpoints.hit(self, address);
+        self.breakpoints = breakpoints;
+        res
+    }
+
+    /// Lets change run the program or change the state, then keeps the
+    /// registers and condition codes from before if it changed
+    /// anything, for front ends to show what changed at this stop
+    pub fn track_changes<F, R>(&mut self, change: F) -> R
+    where
+        F: FnOnce(&mut State) -> R,
+    {
+        let registers = self.registers.clone();
+        let condition_code = self.condition_code;
+        let program_counter = self.program_counter;
+        let res = change(self);
+        if self.registers != registers
+            || self.condition_code != condition_code
+            || self.program_counter != program_counter
+        {
+            self.previous_registers = registers;
+            self.previous_condition_code = condition_code;
+        }
+        res
+    }
+
+    /// Puts back the registers, condition codes, PC and memory from
+    /// before the last recorded instruction
+    /// Returns false if there is nothing left to undo
+    pub fn undo_instruction(&mut self) -> bool {
+        match self.history.undo() {
+            Some(restore) => {
+                self.restore(restore);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Puts back the state an undo record was taken from
+    fn restore(&mut self, restore: Restore) {
+        self.registers = restore.registers;
+        self.condition_code = restore.condition_code;
+        self.program_counter = restore.program_counter;
+        self.frames = restore.frames;
+        for (address, old) in restore.memory {
+            for i in 0..8 {
+                self.program_map[(address + i) as usize] = (old >> (8 * i)) as u8;
+            }
+        }
+    }
+
+    /// Executes the instruction at the PC and undoes it again, without
+    /// tracing it, returning the watched accesses it made
+    /// The undo log only keeps writes, so this is how executing in
+    /// reverse finds the reads too
+    pub fn replay_watchpoints(&mut self) -> Vec<Hit> {
+        let instr = match Instruction::new(self) {
+            Ok(instr) => instr,
+            Err(_) => return vec![],
+        };
+        let trace = self.trace.take();
+        self.watchpoints.take_hits();
+        self.begin_instruction();
+        instr.execute(self).ok();
+        self.abort_instruction();
+        self.trace = trace;
+        self.watchpoints.take_hits()
+    }
+
+    /// How many instructions can be undone
+    pub fn get_history_len(&self) -> usize {
+        self.history.len()
+    }
+
+    /// Gets how many instructions are kept for executing in reverse
+    pub fn get_history_limit(&self) -> usize {
+        self.history.get_limit()
+    }
+
+    /// Sets how many instructions are kept for executing in reverse
+    /// limit: the number of instructions, 0 turns recording off
+    pub fn set_history_limit(&mut self, limit: usize) {
+        self.history.set_limit(limit);
+    }
+
+    /// Gets the calls that have not returned yet, outermost first
+    pub fn get_frames(&self) -> &[Frame] {
+        &self.frames
+    }
+
+    /// Records a call that was just executed
+    /// frame: the call, where it went and where it will return to
+    pub fn push_frame(&mut self, frame: Frame) {
+        self.frames.push(frame);
+    }
+
+    /// Records a ret that was just executed, dropping every frame it
+    /// popped off the stack
+    /// location: the address of the ret instruction
+    /// slot: the stack pointer the ret popped its return address from
+    /// return_to: the address the ret went back to
+    /// Returns a description of the problem if the ret does not match
+    /// the call that pushed slot
+    pub fn pop_frame(&mut self, location: u64, slot: u64, return_to: u64) -> Option<String> {
+        let matching = self
+            .frames
+            .iter()
+            .rposition(|frame| frame.slot == slot)
+            .map(|index| self.frames[index].clone());
+        self.frames.retain(|frame| frame.slot > slot);
+        match matching {
+            Some(frame) if frame.return_address == return_to => None,
+            Some(frame) => Some(format!(
+                "ret at 0x{:x} returned to 0x{:x} instead of 0x{:x}, the return address was overwritten",
+                location, return_to, frame.return_address
+            )),
+            None => Some(format!(
+                "ret at 0x{:x} returned to 0x{:x} but the stack pointer 0x{:x} does not hold a return address",
+                location, return_to, slot
+            )),
+        }
+    }
+
+    /// Executes an instruction, recording it so that it can be undone
+    /// and keeping track of calls and returns
+    /// instr: the instruction at the PC
+    /// Returns a description of the problem if it was a ret that does
+    /// not match its call
+    pub fn execute(&mut self, instr: &Instruction) -> Result<Option<String>, Box<dyn Error>> {
+        let rsp = self.get_register(Register::RRSP as u8);
+        if let Some(trace) = &mut self.trace {
+            trace.begin();
+        }
+        self.begin_instruction();
+        if let Err(e) = instr.execute(self) {
+            if let Some(trace) = &mut self.trace {
+                trace.abort();
+            }
+            self.abort_instruction();
+            return Err(e);
+        }
+        self.end_instruction();
+        let problem = match instr.get_icode() {
+            ICode::ICALL => {
+                self.push_frame(Frame {
+                    call_site: instr.get_location(),
+                    function: self.get_pc(),
+                    slot: self.get_register(Register::RRSP as u8),
+                    return_address: instr.get_val_p(),
+                });
+                None
+            }
+            ICode::IRET => self.pop_frame(instr.get_location(), rsp, self.get_pc()),
+            _ => None,
+        };
+        let (condition_code, status) = (self.condition_code, self.status());
+        // The instruction went through either way, a trace that can no
+        // longer be written is dropped rather than failing it
+        if let Some(trace) = &mut self.trace {
+            if let Err(e) = trace.step(instr, condition_code, status) {
+                self.trace = None;
+                self.trace_error = Some(format!("Stopped tracing: {}", e).into());
+            }
+        }
+        Ok(problem)
+    }
+
+    /// Writes the halt the program stopped at to the trace, as the last
+    /// instruction it executes
+    fn trace_halt(&mut self) -> Result<(), Box<dyn Error>> {
+        let instr = Instruction::new(self)?;
+        let (condition_code, status) = (self.condition_code, self.status());
+        if let Some(trace) = &mut self.trace {
+            trace.begin();
+            trace.step(&instr, condition_code, status)?;
+        }
+        Ok(())
+    }
+
+    /// Takes the reason the trace stopped being written, if it did since
+    /// the last time
+    pub fn take_trace_error(&mut self) -> Option<Box<dyn Error>> {
+        self.trace_error.take()
+    }
+
+    /// Starts writing every instruction executed to trace, or stops
+    /// writing them with None
+    pub fn set_trace(&mut self, trace: Option<Trace>) -> Result<(), Box<dyn Error>> {
+        if let Some(previous) = &mut self.trace {
+            previous.flush()?;
+        }
+        self.trace = trace;
+        Ok(())
+    }
+
+    /// Describes an address relative to the closest label before it,
+    /// such as `main+0x8`, or None without a symbol table
+    /// address: u64 representing the address
+    pub fn describe_address(&self, address: u64) -> Option<String> {
+        let symbol = self
+            .symbols
+            .iter()
+            .filter(|symbol| symbol.kind != SymbolKind::Constant && symbol.address <= address)
+            .max_by_key(|symbol| symbol.address)?;
+        Some(match address - symbol.address {
+            0 => symbol.name.clone(),
+            offset => format!("{}+0x{:x}", symbol.name, offset),
+        })
+    }
+
+    /// Gets how many instructions a single command may execute
+    pub fn get_step_limit(&self) -> u64 {
+        self.step_limit
+    }
+
+    /// Sets how many instructions a single command may execute
+    /// limit: the number of instructions, at least 1
+    pub fn set_step_limit(&mut self, limit: u64) {
+        self.step_limit = limit.max(1);
+    }
+
+    fn check_bounds(&self, address: u64) -> Result<(), Box<dyn Error>> {
+        match address.checked_add(8) {
+            Some(end) if end <= self.program_map.len() as u64 => Ok(()),
+            _ => Err(Box::new(OutOfBoundsError(address))),
+        }
+    }
+
     /// Sets the value of the program counter
     /// new_pc: u64 representing the new pc to set
     pub fn set_pc(&mut self, new_pc: u64) {
@@ -106,7 +835,6 @@ impl State {
         self.program_counter
     }
 
-
     /// Reads a single byte in memory
     /// address: u64 representing the address to the value to read
     pub fn read_byte(&self, address: u64) -> u8 {
@@ -114,43 +842,253 @@ impl State {
     }
 }
 
+/// Lets debugger commands refer to labels and constants by name, to
+/// registers as %name or $name (along with pc, cc, zf, sf and of) and to
+/// memory as M[address]
+impl Context for State {
+    fn symbol(&self, name: &str) -> Option<u64> {
+        self.find_symbol(name).map(|symbol| symbol.address)
+    }
+
+    fn register(&self, name: &str) -> Option<u64> {
+        let cc = self.get_condition_code();
+        match name {
+            "pc" => Some(self.get_pc()),
+            "cc" => Some(cc as u64),
+            "zf" => Some((cc & CC_ZERO_MASK != 0) as u64),
+            "sf" => Some((cc & CC_SIGN_MASK != 0) as u64),
+            "of" => Some((cc & CC_OVERFLOW_MASK != 0) as u64),
+            _ => register_id(name).map(|id| self.get_register(id)),
+        }
+    }
+
+    fn memory(&self, address: u64) -> Result<u64, Box<dyn Error>> {
+        self.peek_le(address)
+    }
+}
+
+/// Loads a Y86 program to debug, starting at its first non-zero byte,
+/// failing if it has none
+/// file_name: String representing the name of a Y86 Machine code file,
+/// or of a source file (`.ys`) to assemble
+/// A section map (`.map`), a symbol table (`.sym`) and a line table
+/// (`.lines`) next to a machine code file, named after it with the
+/// extension replaced, are loaded when present
+fn load(file_name: &str) -> Result<State, Box<dyn Error>> {
+    let mut state = if Path::new(file_name).extension() == Some(OsStr::new("ys")) {
+        assemble(file_name)?
+    } else {
+        read(file_name)?
+    };
+    let start = state
+        .program_map
+        .iter()
+        .position(|&byte| byte != 0)
+        .ok_or_else(|| format!("{} holds no instructions", file_name))?;
+    state.set_pc(start as u64);
+    Ok(state)
+}
+
+/// Assembles a Y86 source file to debug, keeping its section map,
+/// symbol table and line table
+fn assemble(file_name: &str) -> Result<State, Box<dyn Error>> {
+    let assembler = Y86Assembler::from_file(file_name.to_string())?;
+    let mut state = State::from_bytes(assembler.bytes().to_vec());
+    state.set_sections(assembler.sections().to_vec());
+    state.set_symbols(assembler.symbols().to_vec());
+    state.set_lines(assembler.lines().to_vec());
+    Ok(state)
+}
+
+/// Reads a Y86 machine code file to debug, along with the tables saved
+/// next to it
+fn read(file_name: &str) -> Result<State, Box<dyn Error>> {
+    let mut state = State::new(file_name.to_string())?;
+    let map = Path::new(file_name).with_extension("map");
+    if map.exists() {
+        state.load_section_map(map.to_string_lossy().to_string())?;
+    }
+    let sym = Path::new(file_name).with_extension("sym");
+    if sym.exists() {
+        state.load_symbol_table(sym.to_string_lossy().to_string())?;
+    }
+    let lines = Path::new(file_name).with_extension("lines");
+    if lines.exists() {
+        state.load_line_table(lines.to_string_lossy().to_string())?;
+    }
+    Ok(state)
+}
 
 /// Generic function to debug a Y86 program
 /// file_name: String representing the name of a Y86 Machine code file
+/// The program is loaded along with its section map, symbol table and
+/// line table, then the user's init file (`~/.ydbinit`) is run, followed
+/// by a script of debugger commands (`.ydb`) next to the program once
+/// the user agrees to it
 pub fn debug(file_name: String) -> Result<(), Box<dyn Error>> {
-    let mut state = State::new(file_name.clone())?;
-    while state.read_byte(state.get_pc()) == 0 {
-        state.set_pc(state.get_pc() + 1);
-    }
+    let mut state = load(&file_name)?;
     println!(
         "## Opened {:}, starting PC 0x{:x}",
         file_name,
         state.get_pc()
     );
 
+    let mut editor = DefaultEditor::new()?;
+    let home = std::env::var_os("HOME");
+    let history = home.as_ref().map(|home| Path::new(home).join(HISTORY_FILE));
+    if let Some(history) = &history {
+        // There is no history yet the first time
+        editor.load_history(history).ok();
+    }
+    // The init file is the user's own, so it runs without asking
+    let init = home.as_ref().map(|home| Path::new(home).join(INIT_FILE));
+    if let Some(init) = init.filter(|init| init.is_file()) {
+        let mut instruction = Instruction::new(&state)?;
+        let command = format!("source {}", init.to_string_lossy());
+        run_command(command, &mut instruction, &mut state);
+    }
+    let script = Path::new(&file_name).with_extension("ydb");
+    if script.exists() && confirm_script(&mut editor, &script)? {
+        let mut instruction = Instruction::new(&state)?;
+        let command = format!("source {}", script.to_string_lossy());
+        run_command(command, &mut instruction, &mut state);
+    }
+
+    let mut last = String::new();
     loop {
         let mut instruction = Instruction::new(&state)?;
         print_instruction(&instruction);
-        print!(">    ");
-        std::io::stdout().flush()?;
-        let mut buffer = String::new();
-        match stdin().read_line(&mut buffer) {
-            Ok(_) => (),
-            Err(_) => {
-                eprintln!("Could not parse input, please try again");
-                continue;
-            }
+        print_source(&state, state.get_pc(), 1, None);
+        let mut buffer = match editor.readline(">    ") {
+            Ok(line) => line.trim().to_string(),
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(e) => return Err(e.into()),
+        };
+        // An empty line repeats the last command, so that stepping
+        // only takes enter
+        if buffer.is_empty() {
+            buffer = last.clone();
+        } else {
+            editor.add_history_entry(buffer.as_str())?;
+            last = buffer.clone();
         }
-        buffer = buffer.trim().to_string();
         if buffer.starts_with("quit") {
             break;
         }
-        match commands::run(buffer, &mut instruction, &mut state) {
-            Ok(_) => (),
-            Err(e) => {
-                eprintln!("{:}", e);
-            }
-        }
+        run_command(buffer, &mut instruction, &mut state);
+    }
+    if let Some(history) = &history {
+        editor.save_history(history)?;
     }
     Ok(())
 }
+
+/// Asks whether to run the commands in script, since a program can
+/// come with a script its user has never looked at
+fn confirm_script(editor: &mut DefaultEditor, script: &Path) -> Result<bool, Box<dyn Error>> {
+    let prompt = format!(
+        "## Run the commands in {}? (y/n) ",
+        script.to_string_lossy()
+    );
+    match editor.readline(&prompt) {
+        Ok(answer) => Ok(matches!(answer.trim(), "y" | "yes")),
+        Err(ReadlineError::Interrupted) | Err(ReadlineError::Eof) => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Debugs a Y86 program full screen, showing its disassembly, source,
+/// registers, stack and memory at once
+/// file_name: String representing the name of a Y86 Machine code file,
+/// or of a source file (`.ys`) to assemble
+pub fn debug_tui(file_name: String) -> Result<(), Box<dyn Error>> {
+    let mut state = load(&file_name)?;
+    tui::run(&mut state, &file_name)
+}
+
+/// Runs a Y86 program until it halts, writing every instruction it
+/// executes to trace instead of stopping to debug it
+/// file_name: String representing the name of a Y86 Machine code file,
+/// or of a source file (`.ys`) to assemble
+/// Fails with the reason the program could not go on, once the trace
+/// is written up to that point, or with why the trace could not be
+/// written once the program halts
+pub fn run_traced(file_name: String, trace: Trace) -> Result<(), Box<dyn Error>> {
+    let mut state = load(&file_name)?;
+    state.set_trace(Some(trace))?;
+    let limit = state.get_step_limit();
+    let mut steps: u64 = 0;
+    let res = loop {
+        // Whatever is left of the step limit, as the run goes on past
+        // a ret that does not match its call
+        let left = limit.saturating_sub(steps);
+        if left == 0 {
+            break Err(StopReason::StepLimit(limit).to_string().into());
+        }
+        state.set_step_limit(left);
+        let stop = state.run_until(|_, _| {
+            steps += 1;
+            false
+        });
+        match stop {
+            StopReason::Halted => break state.trace_halt(),
+            StopReason::MismatchedReturn(..) => steps += 1,
+            StopReason::StepLimit(_) => break Err(StopReason::StepLimit(limit).to_string().into()),
+            StopReason::Error(e) => break Err(e),
+            stop => break Err(stop.to_string().into()),
+        }
+    };
+    state.set_step_limit(limit);
+    state.set_trace(None)?;
+    let trace_error = state.take_trace_error();
+    res?;
+    trace_error.map_or(Ok(()), Err)
+}
+
+/// Lets gdb debug a Y86 program over the Remote Serial Protocol,
+/// returning once gdb detaches or kills the program
+/// file_name: String representing the name of a Y86 Machine code file,
+/// loaded along with its section map, symbol table and line table
+/// address: where to listen for gdb, such as `127.0.0.1:1234`
+pub fn serve_gdb(file_name: String, address: &str) -> Result<(), Box<dyn Error>> {
+    let mut state = load(&file_name)?;
+    gdb_server::serve(&mut state, address)
+}
+
+/// Lets an editor debug Y86 programs over the Debug Adapter Protocol,
+/// with messages read from stdin and written to stdout
+/// Programs are launched from machine code or from source files, which
+/// are assembled on the fly
+pub fn serve_dap() -> Result<(), Box<dyn Error>> {
+    dap_server::serve(stdin().lock(), stdout())
+}
+
+/// Runs a debugger command, printing its error if it fails
+fn run_command(command: String, instruction: &mut Instruction, state: &mut State) {
+    let res = state.track_changes(|state| commands::run(command, instruction, state));
+    if let Err(e) = res {
+        eprintln!("{:}", e);
+    }
+    if let Some(e) = state.take_trace_error() {
+        eprintln!("{:}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refuses_to_load_a_program_without_instructions() {
+        let path = std::env::temp_dir().join(format!("y86-{}-zeros.yo", std::process::id()));
+        for bytes in [vec![], vec![0; 16]] {
+            std::fs::write(&path, bytes).unwrap();
+            assert!(load(&path.to_string_lossy()).is_err());
+        }
+        std::fs::write(&path, [0, 0, 0x10]).unwrap();
+        let state = load(&path.to_string_lossy()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(state.get_pc(), 2);
+    }
+}
@@ -0,0 +1,263 @@
+use crate::expression::{Context, Expr};
+use std::error::Error;
+
+/// A place to stop at
+/// condition: the expression as typed and parsed, the breakpoint only
+/// stops when it evaluates to non-zero
+/// ignore: how many more hits to pass over before stopping
+/// hits: how many times the breakpoint was reached with its condition true
+/// temporary: deletes the breakpoint the first time it stops
+struct Breakpoint {
+    id: usize,
+    address: u64,
+    condition: Option<(String, Expr)>,
+    ignore: u64,
+    hits: u64,
+    temporary: bool,
+}
+
+impl Breakpoint {
+    /// Whether the condition holds, true without one
+    fn holds(&self, context: &dyn Context) -> Result<bool, Box<dyn Error>> {
+        match &self.condition {
+            Some((_, condition)) => Ok(condition.eval(context)? != 0),
+            None => Ok(true),
+        }
+    }
+
+    /// The error of a condition that cannot be evaluated, naming the
+    /// breakpoint
+    fn condition_error(&self, e: Box<dyn Error>) -> Box<dyn Error> {
+        let text = self.condition.as_ref().map_or("", |(text, _)| &text[..]);
+        format!(
+            "Error in condition \"{}\" of breakpoint {}: {}",
+            text, self.id, e
+        )
+        .into()
+    }
+}
+
+/// The breakpoints of a program being debugged
+#[derive(Default)]
+pub struct Breakpoints {
+    list: Vec<Breakpoint>,
+    last_id: usize,
+}
+
+impl Breakpoints {
+    /// Adds a breakpoint, returning its id
+    /// address: where to stop
+    /// condition: an expression that must hold for the breakpoint to stop
+    /// temporary: whether to delete the breakpoint once it stops
+    pub fn add(
+        &mut self,
+        address: u64,
+        condition: Option<&str>,
+        temporary: bool,
+    ) -> Result<usize, Box<dyn Error>> {
+        let condition = match condition {
+            Some(text) => Some((text.to_string(), Expr::parse(text)?)),
+            None => None,
+        };
+        self.last_id += 1;
+        let id = self.last_id;
+        self.list.push(Breakpoint {
+            id,
+            address,
+            condition,
+            ignore: 0,
+            hits: 0,
+            temporary,
+        });
+        Ok(id)
+    }
+
+    /// Deletes every breakpoint at address
+    pub fn delete_at(&mut self, address: u64) {
+        self.list.retain(|breakpoint| breakpoint.address != address);
+    }
+
+    /// Deletes breakpoint id, if there is one
+    pub fn delete(&mut self, id: usize) {
+        self.list.retain(|breakpoint| breakpoint.id != id);
+    }
+
+    /// Whether any breakpoint is set at address
+    pub fn is_set(&self, address: u64) -> bool {
+        self.list
+            .iter()
+            .any(|breakpoint| breakpoint.address == address)
+    }
+
+    /// Makes breakpoint id pass over its next count hits
+    /// Returns whether there is such a breakpoint
+    pub fn ignore(&mut self, id: usize, count: u64) -> bool {
+        match self.list.iter_mut().find(|breakpoint| breakpoint.id == id) {
+            Some(breakpoint) => {
+                breakpoint.ignore = count;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Decides whether execution should stop at address, counting the hit
+    /// on every breakpoint there whose condition holds
+    /// Returns the ids of the breakpoints that stop, none to go on
+    /// A condition that cannot be evaluated is an error, which stops
+    /// execution so it can be fixed
+    pub fn hit(
+        &mut self,
+        context: &dyn Context,
+        address: u64,
+    ) -> Result<Vec<usize>, Box<dyn Error>> {
+        let mut error = None;
+        let mut stopped = vec![];
+        let mut finished = vec![];
+        for breakpoint in self.list.iter_mut() {
+            if breakpoint.address != address {
+                continue;
+            }
+            match breakpoint.holds(context) {
+                Ok(false) => continue,
+                Ok(true) => (),
+                Err(e) => {
+                    error.get_or_insert(breakpoint.condition_error(e));
+                    continue;
+                }
+            }
+            breakpoint.hits += 1;
+            if breakpoint.ignore > 0 {
+                breakpoint.ignore -= 1;
+                continue;
+            }
+            stopped.push(breakpoint.id);
+            if breakpoint.temporary {
+                finished.push(breakpoint.id);
+            }
+        }
+        self.list
+            .retain(|breakpoint| !finished.contains(&breakpoint.id));
+        match error {
+            Some(e) => Err(e),
+            None => Ok(stopped),
+        }
+    }
+
+    /// Whether a breakpoint at address would stop with the current state,
+    /// without counting it as a hit, for executing in reverse
+    /// A condition that cannot be evaluated is an error, the same as
+    /// with hit
+    pub fn stops_at(&self, context: &dyn Context, address: u64) -> Result<bool, Box<dyn Error>> {
+        let mut stop = false;
+        for breakpoint in &self.list {
+            if breakpoint.address != address {
+                continue;
+            }
+            match breakpoint.holds(context) {
+                Ok(holds) => stop |= holds,
+                Err(e) => return Err(breakpoint.condition_error(e)),
+            }
+        }
+        Ok(stop)
+    }
+
+    /// Prints every breakpoint
+    pub fn print(&self) {
+        if self.list.is_empty() {
+            println!("      No breakpoints");
+        }
+        for breakpoint in &self.list {
+            let mut line = format!(
+                "      {:<3} 0x{:<8x} hits {:}",
+                breakpoint.id, breakpoint.address, breakpoint.hits
+            );
+            if breakpoint.temporary {
+                line.push_str(", temporary");
+            }
+            if breakpoint.ignore > 0 {
+                line.push_str(&format!(", ignore next {}", breakpoint.ignore));
+            }
+            if let Some((text, _)) = &breakpoint.condition {
+                line.push_str(&format!(", if {}", text));
+            }
+            println!("{}", line);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A program whose only register is %rax
+    struct Registers {
+        rax: u64,
+    }
+
+    impl Context for Registers {
+        fn symbol(&self, _name: &str) -> Option<u64> {
+            None
+        }
+
+        fn register(&self, name: &str) -> Option<u64> {
+            match name {
+                "rax" => Some(self.rax),
+                _ => None,
+            }
+        }
+    }
+
+    #[test]
+    fn stops_only_when_the_condition_holds() {
+        let mut breakpoints = Breakpoints::default();
+        let id = breakpoints.add(0x10, Some("%rax == 3"), false).unwrap();
+        assert!(breakpoints
+            .hit(&Registers { rax: 2 }, 0x10)
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            breakpoints.hit(&Registers { rax: 3 }, 0x10).unwrap(),
+            vec![id]
+        );
+        assert!(breakpoints
+            .hit(&Registers { rax: 3 }, 0x18)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn passes_over_ignored_hits_and_deletes_temporary_breakpoints() {
+        let mut breakpoints = Breakpoints::default();
+        let context = Registers { rax: 0 };
+        let id = breakpoints.add(0x10, None, true).unwrap();
+        assert!(breakpoints.ignore(id, 1));
+        assert!(breakpoints.hit(&context, 0x10).unwrap().is_empty());
+        assert_eq!(breakpoints.hit(&context, 0x10).unwrap(), vec![id]);
+        assert!(!breakpoints.is_set(0x10));
+        assert!(!breakpoints.ignore(id, 1));
+    }
+
+    #[test]
+    fn reports_conditions_that_cannot_be_evaluated() {
+        let mut breakpoints = Breakpoints::default();
+        breakpoints.add(0x10, Some("%rbx"), false).unwrap();
+        let context = Registers { rax: 0 };
+        let e = breakpoints.hit(&context, 0x10).unwrap_err();
+        assert!(e
+            .to_string()
+            .starts_with("Error in condition \"%rbx\" of breakpoint 1"));
+        assert!(breakpoints.stops_at(&context, 0x10).is_err());
+    }
+
+    #[test]
+    fn checks_a_breakpoint_without_counting_it() {
+        let mut breakpoints = Breakpoints::default();
+        let context = Registers { rax: 0 };
+        let id = breakpoints.add(0x10, None, true).unwrap();
+        assert!(breakpoints.stops_at(&context, 0x10).unwrap());
+        assert!(breakpoints.is_set(0x10));
+        breakpoints.delete(id);
+        assert!(!breakpoints.stops_at(&context, 0x10).unwrap());
+    }
+}
@@ -1,14 +1,16 @@
-use super::instructions::{ICode, Instruction};
-use super::print::{print_all_registers, print_memory_quad_value};
-use super::State;
-use lazy_static::lazy_static;
-use std::collections::HashSet;
+use super::instructions::{
+    ICode, Instruction, Register, CC_OVERFLOW_MASK, CC_SIGN_MASK, CC_ZERO_MASK,
+};
+use super::print::{
+    print_all_registers, print_backtrace, print_disassembly, print_hexdump, print_instruction,
+    print_memory, print_memory_quad_value, print_sections, print_source, print_symbols,
+    register_id, Format,
+};
+use super::watchpoints::Kind;
+use super::{State, StopReason, Trace};
+use crate::expression;
 use std::error::Error;
-use std::sync::{Arc, Mutex};
-
-lazy_static! {
-    static ref SET: Arc<Mutex<HashSet<u64>>> = Arc::new(Mutex::new(HashSet::new()));
-}
+use std::fs;
 
 #[derive(Debug, Clone)]
 pub struct InvalidParameter;
@@ -30,6 +32,35 @@ impl Error for InvalidParameter {
     }
 }
 
+/// Evaluates the argument of a command as an address expression, such
+/// as `loop`, `main+0x10` or `64`
+fn parse_address(input: &str, state: &State) -> Result<u64, Box<dyn Error>> {
+    match input.find(" ") {
+        Some(i) => expression::evaluate(&input[i..], state),
+        None => {
+            let boxed: Box<InvalidParameter> = InvalidParameter.into();
+            Err(boxed)
+        }
+    }
+}
+
+/// Evaluates the argument of a command as an address expression
+/// optionally followed by a comma and a length, such as `array, 16`
+/// default: the length when none is given
+fn parse_address_and_len(
+    input: &str,
+    state: &State,
+    default: u64,
+) -> Result<(u64, u64), Box<dyn Error>> {
+    match input.split_once(',') {
+        Some((location, len)) => Ok((
+            parse_address(location, state)?,
+            expression::evaluate(len, state)?,
+        )),
+        None => Ok((parse_address(input, state)?, default)),
+    }
+}
+
 pub fn run(
     input: String,
     instr: &mut Instruction,
@@ -44,124 +75,659 @@ pub fn run(
         "step" => run_step(instr, state),
         "run" => run_run(instr, state),
         "next" => run_next(instr, state),
-        "jump" => run_jump(input, instr, state),
-        "break" => run_break(input, instr, state),
+        "finish" => run_finish(instr, state),
+        "until" => run_until_command(input, instr, state),
+        "steplimit" => run_step_limit(input, instr, state),
+        "jump" => state.record_change(|state| run_jump(input, instr, state)),
+        "break" => run_break(input, instr, state, false),
+        "tbreak" => run_break(input, instr, state, true),
         "delete" => run_delete(input, instr, state),
-        "registers" => run_registers(instr, state),
+        "ignore" => run_ignore(input, instr, state),
+        "breakpoints" => run_breakpoints(instr, state),
+        "watch" => run_watch(input, instr, state, Kind::Write),
+        "rwatch" => run_watch(input, instr, state, Kind::Read),
+        "awatch" => run_watch(input, instr, state, Kind::Access),
+        "watchpoints" => run_watchpoints(instr, state),
+        "back" | "reverse-step" => run_back(input, instr, state),
+        "reverse-continue" => run_reverse_continue(instr, state),
+        "history" => run_history(input, instr, state),
+        "trace" => run_trace(input, instr, state),
+        _ if command.split('/').next() == Some("registers") => run_registers(input, instr, state),
+        "set" => state.record_change(|state| run_set(input, instr, state)),
+        "backtrace" | "bt" => run_backtrace(instr, state),
         "examine" => run_examine(input, instr, state),
-        _ => Ok(eprintln!("Invalid command, please try again")),
+        _ if command.starts_with("examine/") => run_examine(input, instr, state),
+        "hexdump" => run_hexdump(input, instr, state),
+        "disassemble" => run_disassemble(input, instr, state),
+        "sections" => run_sections(instr, state),
+        "symbols" => run_symbols(instr, state),
+        "list" => run_list(input, instr, state),
+        "source" => run_source(input, instr, state),
+        _ => {
+            eprintln!("Invalid command, please try again");
+            Ok(())
+        }
     }
 }
 
-fn run_step(instr: &mut Instruction, state: &mut State) -> Result<(), Box<dyn Error>> {
-    instr.execute(state)?;
-    Ok(())
+fn run_step(_instr: &mut Instruction, state: &mut State) -> Result<(), Box<dyn Error>> {
+    run_until(state, |_, _| true)
 }
-fn run_run(instr: &mut Instruction, state: &mut State) -> Result<(), Box<dyn Error>> {
-    instr.execute(state)?;
-    let mut curr = Instruction::new(&state)?;
-    while !SET.lock().unwrap().contains(&curr.get_location()) && curr.get_icode() != ICode::IHALT {
-        curr.execute(state)?;
-        curr = Instruction::new(&state)?;
+/// Runs the program with State::run_until, then says why it stopped
+/// unless it was done
+/// done: given the instruction just executed and the resulting state
+fn run_until<F>(state: &mut State, done: F) -> Result<(), Box<dyn Error>>
+where
+    F: FnMut(&Instruction, &State) -> bool,
+{
+    match state.run_until(done) {
+        StopReason::Done | StopReason::Halted => (),
+        StopReason::Breakpoint(ids) => {
+            for id in ids {
+                println!("## Breakpoint {}, 0x{:x}", id, state.get_pc());
+            }
+        }
+        StopReason::Watchpoint(instr, hits) => {
+            for hit in hits {
+                println!("## {}", hit);
+            }
+            print_instruction(&instr);
+        }
+        StopReason::MismatchedReturn(instr, problem) => {
+            println!("## Mismatched return: {}", problem);
+            print_instruction(&instr);
+        }
+        StopReason::StepLimit(steps) => {
+            println!("## Stopped after {} instructions, the step limit", steps);
+        }
+        StopReason::Error(e) => return Err(e),
     }
     Ok(())
 }
+
+/// Whether instr is a ret that popped the frame whose stack pointer
+/// was rsp, returning to its caller
+fn returned(instr: &Instruction, state: &State, rsp: u64) -> bool {
+    instr.get_icode() == ICode::IRET && state.get_register(Register::RRSP as u8) > rsp
+}
+
+fn run_run(_instr: &mut Instruction, state: &mut State) -> Result<(), Box<dyn Error>> {
+    run_until(state, |_, _| false)
+}
 fn run_next(instr: &mut Instruction, state: &mut State) -> Result<(), Box<dyn Error>> {
     let val_p = instr.get_val_p();
-    instr.execute(state)?;
-    let mut curr = Instruction::new(&state)?;
-    while !SET.lock().unwrap().contains(&curr.get_location())
-        && curr.get_icode() != ICode::IHALT
-        && state.get_pc() != val_p
-    {
-        curr.execute(state)?;
-        curr = Instruction::new(&state)?;
+    run_until(state, |_, state| state.get_pc() == val_p)
+}
+fn run_finish(_instr: &mut Instruction, state: &mut State) -> Result<(), Box<dyn Error>> {
+    let rsp = state.get_register(Register::RRSP as u8);
+    run_until(state, |instr, state| {
+        let finished = returned(instr, state, rsp);
+        if finished {
+            println!("## Returned to 0x{:x}", state.get_pc());
+        }
+        finished
+    })
+}
+fn run_until_command(
+    input: String,
+    instr: &mut Instruction,
+    state: &mut State,
+) -> Result<(), Box<dyn Error>> {
+    let rsp = state.get_register(Register::RRSP as u8);
+    let location = instr.get_location();
+    // Without an address, stop past the current instruction in the
+    // same frame, so that a loop's backward jump runs the whole loop
+    let destination = match input.find(" ") {
+        Some(_) => Some(parse_address(&input, state)?),
+        None => None,
+    };
+    run_until(state, |instr, state| {
+        let pc = state.get_pc();
+        let reached = match destination {
+            Some(destination) => pc == destination,
+            None => pc > location && state.get_register(Register::RRSP as u8) >= rsp,
+        };
+        reached || returned(instr, state, rsp)
+    })
+}
+fn run_step_limit(
+    input: String,
+    _instr: &mut Instruction,
+    state: &mut State,
+) -> Result<(), Box<dyn Error>> {
+    if let Some(i) = input.find(" ") {
+        state.set_step_limit(input[i..].trim().parse::<u64>()?);
     }
+    println!(
+        "      Commands stop after {} instructions",
+        state.get_step_limit()
+    );
     Ok(())
 }
-fn run_jump(
+fn run_back(
     input: String,
     _instr: &mut Instruction,
     state: &mut State,
 ) -> Result<(), Box<dyn Error>> {
-    let index = input.find(" ");
-
-    let destination: u64 = match index {
-        Some(i) => {
-            let num = &input[i..].trim().trim_start_matches("0x");
-            u64::from_str_radix(num, 16)?
+    let count = match input.find(" ") {
+        Some(i) => input[i..].trim().parse::<usize>()?,
+        None => 1,
+    };
+    for _ in 0..count {
+        if !state.undo_instruction() {
+            println!("## No more history, at the oldest recorded instruction");
+            break;
         }
-        None => {
-            let boxed: Box<InvalidParameter> = InvalidParameter.into();
-            Err(boxed)?
+    }
+    Ok(())
+}
+/// Undoes instructions until a breakpoint would stop at the PC or the
+/// instruction at the PC touches a watchpoint
+fn run_reverse_continue(_instr: &mut Instruction, state: &mut State) -> Result<(), Box<dyn Error>> {
+    while state.undo_instruction() {
+        if state.get_breakpoints().stops_at(state, state.get_pc())? {
+            println!("## Breakpoint at 0x{:x}", state.get_pc());
+            return Ok(());
         }
+        let hits = state.replay_watchpoints();
+        for hit in hits.iter() {
+            println!("## {}", hit);
+        }
+        if !hits.is_empty() {
+            println!("## Stopped before the access, at 0x{:x}", state.get_pc());
+            return Ok(());
+        }
+    }
+    println!("## No more history, at the oldest recorded instruction");
+    Ok(())
+}
+fn run_history(
+    input: String,
+    _instr: &mut Instruction,
+    state: &mut State,
+) -> Result<(), Box<dyn Error>> {
+    if let Some(i) = input.find(" ") {
+        state.set_history_limit(input[i..].trim().parse::<usize>()?);
+    }
+    println!(
+        "      {} of at most {} instructions recorded",
+        state.get_history_len(),
+        state.get_history_limit()
+    );
+    Ok(())
+}
+/// trace FILE [START END]: writes every instruction executed from now on
+/// to FILE, as CSV if it ends in `.csv` and JSON Lines otherwise, only
+/// those at addresses in START..END if given
+/// trace off: stops writing the trace
+fn run_trace(
+    input: String,
+    _instr: &mut Instruction,
+    state: &mut State,
+) -> Result<(), Box<dyn Error>> {
+    let args: Vec<&str> = input.split_whitespace().skip(1).collect();
+    let (file_name, range) = match args.as_slice() {
+        ["off"] => {
+            state.set_trace(None)?;
+            println!("      Trace off");
+            return Ok(());
+        }
+        [file_name] => (*file_name, None),
+        [file_name, start, end] => {
+            let start = expression::evaluate(start, state)?;
+            let end = expression::evaluate(end, state)?;
+            (*file_name, Some((start, end)))
+        }
+        _ => return Err(InvalidParameter.into()),
     };
+    state.set_trace(Some(Trace::create(file_name, range)?))?;
+    match range {
+        Some((start, end)) => println!(
+            "      Tracing 0x{:x}-0x{:x} to {}",
+            start,
+            end.saturating_sub(1),
+            file_name
+        ),
+        None => println!("      Tracing to {}", file_name),
+    }
+    Ok(())
+}
+fn run_jump(
+    input: String,
+    _instr: &mut Instruction,
+    state: &mut State,
+) -> Result<(), Box<dyn Error>> {
+    let destination = parse_address(&input, state)?;
+    check_destination(state, destination)?;
     state.set_pc(destination);
     Ok(())
 }
+
+/// Fails unless the instruction at address can be executed, so that
+/// the PC is never moved where the debugger cannot go on from
+fn check_destination(state: &State, address: u64) -> Result<(), Box<dyn Error>> {
+    state.check_executable(address)?;
+    Instruction::decode(state, address)?;
+    Ok(())
+}
 fn run_break(
     input: String,
     _instr: &mut Instruction,
-    _state: &mut State,
+    state: &mut State,
+    temporary: bool,
 ) -> Result<(), Box<dyn Error>> {
-    let index = input.find(" ");
+    let (location, condition) = match input.find(" if ") {
+        Some(i) => (&input[..i], Some(input[i + 4..].trim())),
+        None => (&input[..], None),
+    };
+    let address = parse_address(location, state)?;
+    let id = state
+        .get_breakpoints_mut()
+        .add(address, condition, temporary)?;
+    println!("      Breakpoint {} at 0x{:x}", id, address);
+    Ok(())
+}
+fn run_delete(
+    input: String,
+    _instr: &mut Instruction,
+    state: &mut State,
+) -> Result<(), Box<dyn Error>> {
+    let breakpoint = parse_address(&input, state)?;
+    state.get_breakpoints_mut().delete_at(breakpoint);
+    state.get_watchpoints_mut().delete_at(breakpoint);
+    Ok(())
+}
 
-    let breakpoint: u64 = match index {
-        Some(i) => {
-            let num = &input[i..].trim().trim_start_matches("0x");
-            u64::from_str_radix(num, 16)?
-        }
-        None => {
+// How many bytes a watchpoint covers when no length is given
+const WATCH_LEN: u64 = 8;
+
+/// watch ADDR[, LEN] stops when the program writes any of the LEN
+/// bytes at ADDR, rwatch when it reads them and awatch on both
+fn run_watch(
+    input: String,
+    _instr: &mut Instruction,
+    state: &mut State,
+    kind: Kind,
+) -> Result<(), Box<dyn Error>> {
+    let (address, len) = parse_address_and_len(&input, state, WATCH_LEN)?;
+    let id = state.get_watchpoints_mut().add(address, len, kind)?;
+    println!(
+        "      Watchpoint {} on 0x{:x}-0x{:x}",
+        id,
+        address,
+        address + (len - 1)
+    );
+    Ok(())
+}
+
+fn run_watchpoints(_instr: &mut Instruction, state: &mut State) -> Result<(), Box<dyn Error>> {
+    state.get_watchpoints().print();
+    Ok(())
+}
+
+fn run_ignore(
+    input: String,
+    _instr: &mut Instruction,
+    state: &mut State,
+) -> Result<(), Box<dyn Error>> {
+    let args: Vec<&str> = input.split_whitespace().skip(1).collect();
+    let (id, count) = match args.as_slice() {
+        [id, count] => (id.parse::<usize>()?, count.parse::<u64>()?),
+        _ => {
             let boxed: Box<InvalidParameter> = InvalidParameter.into();
             Err(boxed)?
         }
     };
-    SET.lock().unwrap().insert(breakpoint);
+    if !state.get_breakpoints_mut().ignore(id, count) {
+        return Err(format!("No breakpoint number {}", id).into());
+    }
     Ok(())
 }
-fn run_delete(
+
+fn run_breakpoints(_instr: &mut Instruction, state: &mut State) -> Result<(), Box<dyn Error>> {
+    state.get_breakpoints().print();
+    Ok(())
+}
+
+/// registers shows values in hex, registers/s in signed decimal
+fn run_registers(
     input: String,
     _instr: &mut Instruction,
-    _state: &mut State,
+    state: &mut State,
 ) -> Result<(), Box<dyn Error>> {
-    let index = input.find(" ");
+    let signed = match input.split(' ').next().unwrap() {
+        "registers" | "registers/x" => false,
+        "registers/s" => true,
+        _ => return Err(InvalidParameter.into()),
+    };
+    print_all_registers(state, signed);
+    Ok(())
+}
 
-    let breakpoint: u64 = match index {
-        Some(i) => {
-            let num = &input[i..].trim().trim_start_matches("0x");
-            u64::from_str_radix(num, 16)?
+/// Handles `set %reg = EXPR`, `set M[EXPR] = EXPR`, `set pc = EXPR`
+/// and `set cc ZF=1 SF=0 OF=0` (or `set cc = EXPR` for the raw bits)
+fn run_set(
+    input: String,
+    _instr: &mut Instruction,
+    state: &mut State,
+) -> Result<(), Box<dyn Error>> {
+    let args = input.trim_start_matches("set").trim();
+    if let Some(flags) = args.strip_prefix("cc") {
+        if !flags.trim_start().starts_with('=') {
+            return set_flags(flags, state);
         }
+    }
+    let (target, value) = match args.split_once('=') {
+        Some((target, value)) => (target.trim(), expression::evaluate(value, state)?),
         None => {
             let boxed: Box<InvalidParameter> = InvalidParameter.into();
             Err(boxed)?
         }
     };
-    SET.lock().unwrap().remove(&breakpoint);
+    if target == "pc" || target == "%pc" {
+        check_destination(state, value)?;
+        state.set_pc(value);
+    } else if target == "cc" || target == "%cc" {
+        state.set_condition_code(value as u8 & (CC_ZERO_MASK | CC_SIGN_MASK | CC_OVERFLOW_MASK));
+    } else if let Some(name) = target.strip_prefix('%') {
+        match register_id(name) {
+            Some(id) => state.set_register(id, value),
+            None => return Err(format!("Unknown register %{}", name).into()),
+        }
+    } else if target.starts_with("M[") && target.ends_with(']') {
+        let address = expression::evaluate(&target[2..target.len() - 1], state)?;
+        state.write_le(address, value)?;
+    } else {
+        return Err(format!("Cannot set {}", target).into());
+    }
     Ok(())
 }
 
-fn run_registers(_instr: &mut Instruction, state: &mut State) -> Result<(), Box<dyn Error>> {
-    print_all_registers(state);
+/// Sets individual condition codes from a list such as `ZF=1 SF=0`
+fn set_flags(flags: &str, state: &mut State) -> Result<(), Box<dyn Error>> {
+    let mut cc = state.get_condition_code();
+    for flag in flags.split(|c: char| c == ',' || c.is_whitespace()) {
+        if flag.is_empty() {
+            continue;
+        }
+        let (name, value) = match flag.split_once('=') {
+            Some((name, value)) => (name, expression::evaluate(value, state)?),
+            None => return Err(format!("Expected FLAG=VALUE, got {}", flag).into()),
+        };
+        let mask = match name.to_uppercase().as_str() {
+            "ZF" => CC_ZERO_MASK,
+            "SF" => CC_SIGN_MASK,
+            "OF" => CC_OVERFLOW_MASK,
+            _ => return Err(format!("Unknown condition code {}", name).into()),
+        };
+        if value != 0 {
+            cc |= mask;
+        } else {
+            cc &= !mask;
+        }
+    }
+    state.set_condition_code(cc);
     Ok(())
 }
 
-fn run_examine(
+fn run_backtrace(_instr: &mut Instruction, state: &mut State) -> Result<(), Box<dyn Error>> {
+    print_backtrace(state);
+    Ok(())
+}
+
+fn run_sections(_instr: &mut Instruction, state: &mut State) -> Result<(), Box<dyn Error>> {
+    print_sections(state);
+    Ok(())
+}
+
+fn run_symbols(_instr: &mut Instruction, state: &mut State) -> Result<(), Box<dyn Error>> {
+    print_symbols(state);
+    Ok(())
+}
+
+// How many lines `list` shows on either side of the one it centers on
+const LIST_CONTEXT: usize = 5;
+
+fn run_list(
     input: String,
     _instr: &mut Instruction,
     state: &mut State,
 ) -> Result<(), Box<dyn Error>> {
-    let index = input.find(" ");
+    let around = match input.find(" ") {
+        Some(i) => Some(input[i..].trim().parse::<usize>()?),
+        None => None,
+    };
+    if !print_source(state, state.get_pc(), LIST_CONTEXT, around) {
+        println!("      No source line for 0x{:x}", state.get_pc());
+    }
+    Ok(())
+}
 
-    let address: u64 = match index {
-        Some(i) => {
-            let num = &input[i..].trim().trim_start_matches("0x");
-            u64::from_str_radix(num, 16)?
-        }
+/// examine ADDR shows the quad at ADDR, examine/NFU ADDR shows N units
+/// of size U (b, w, l or q) in format F (x, d, s, c or i), each part
+/// being optional
+fn run_examine(
+    input: String,
+    _instr: &mut Instruction,
+    state: &mut State,
+) -> Result<(), Box<dyn Error>> {
+    let address = parse_address(&input, state)?;
+    let command = input.split(' ').next().unwrap();
+    let spec = match command.split_once('/') {
+        Some((_, spec)) => spec,
         None => {
-            let boxed: Box<InvalidParameter> = InvalidParameter.into();
-            Err(boxed)?
+            state.peek_le(address)?;
+            print_memory_quad_value(state, address);
+            return Ok(());
         }
     };
-    print_memory_quad_value(state, address);
+    let digits = spec
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(spec.len());
+    let count = match digits {
+        0 => 1,
+        _ => spec[..digits].parse::<u64>()?,
+    };
+    let mut format = Format::Hex;
+    let mut size = None;
+    for c in spec[digits..].chars() {
+        match c {
+            'x' => format = Format::Hex,
+            'd' => format = Format::Decimal,
+            's' => format = Format::Signed,
+            'c' => format = Format::Char,
+            'i' => format = Format::Instruction,
+            'b' => size = Some(1),
+            'w' => size = Some(2),
+            'l' => size = Some(4),
+            'q' => size = Some(8),
+            _ => return Err(format!("Unknown format or unit size '{}'", c).into()),
+        }
+    }
+    let size = size.unwrap_or(if format == Format::Char { 1 } else { 8 });
+    print_memory(state, address, count, size, format)
+}
+
+// How many instructions disassemble shows when no count is given
+const DISASSEMBLE_COUNT: u64 = 10;
+
+/// disassemble [ADDR[, COUNT]] shows the instructions around ADDR, or
+/// around the PC when no address is given
+fn run_disassemble(
+    input: String,
+    _instr: &mut Instruction,
+    state: &mut State,
+) -> Result<(), Box<dyn Error>> {
+    let (address, count) = if input.trim_end().contains(' ') {
+        parse_address_and_len(&input, state, DISASSEMBLE_COUNT)?
+    } else {
+        (state.get_pc(), DISASSEMBLE_COUNT)
+    };
+    print_disassembly(state, address, count)
+}
+
+// How many bytes hexdump shows when no length is given
+const HEXDUMP_LEN: u64 = 64;
+
+/// hexdump ADDR[, LEN] shows LEN bytes from ADDR
+fn run_hexdump(
+    input: String,
+    _instr: &mut Instruction,
+    state: &mut State,
+) -> Result<(), Box<dyn Error>> {
+    let (address, len) = parse_address_and_len(&input, state, HEXDUMP_LEN)?;
+    print_hexdump(state, address, len)
+}
+
+// How deep scripts can source other scripts
+const MAX_SOURCE_DEPTH: usize = 16;
+
+/// source FILE runs the debugger commands in FILE, one per line, where
+/// blank lines and lines starting with # are skipped
+/// The script stops at the first command that fails, or at quit
+/// Scripts can source other scripts, but not one already running
+fn run_source(
+    input: String,
+    instr: &mut Instruction,
+    state: &mut State,
+) -> Result<(), Box<dyn Error>> {
+    let file_name = match input.find(" ") {
+        Some(i) => input[i..].trim().to_string(),
+        None => return Err(InvalidParameter.into()),
+    };
+    let script = fs::read_to_string(&file_name).map_err(|e| format!("{}: {}", file_name, e))?;
+    let path = fs::canonicalize(&file_name)?;
+    {
+        let sources = state.get_scripts();
+        if sources.contains(&path) {
+            let chain: Vec<String> = sources
+                .iter()
+                .chain(Some(&path))
+                .map(|source| source.to_string_lossy().to_string())
+                .collect();
+            return Err(format!("recursive source of {}", chain.join(" -> ")).into());
+        }
+        if sources.len() >= MAX_SOURCE_DEPTH {
+            let message = format!(
+                "{}: scripts are nested over the limit of {}",
+                file_name, MAX_SOURCE_DEPTH
+            );
+            return Err(message.into());
+        }
+    }
+    state.push_script(path);
+    let running = Running(state);
+    run_script(&file_name, &script, instr, running.0)
+}
+
+/// Pops the script pushed last once it is done running, even if it
+/// panics, so that it can be run again
+struct Running<'a>(&'a mut State);
+
+impl Drop for Running<'_> {
+    fn drop(&mut self) {
+        self.0.pop_script();
+    }
+}
+
+/// Runs the commands of a script for source
+/// file_name: where the script came from, for errors
+fn run_script(
+    file_name: &str,
+    script: &str,
+    instr: &mut Instruction,
+    state: &mut State,
+) -> Result<(), Box<dyn Error>> {
+    for (number, line) in script.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if line.starts_with("quit") {
+            break;
+        }
+        *instr = Instruction::new(state)?;
+        if let Err(e) = run(line.to_string(), instr, state) {
+            return Err(format!("{}:{}: {}", file_name, number + 1, e).into());
+        }
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::section_map::SectionInfo;
+
+    /// irmovq $0x5, %rax; halt, then a quad of data at 0x10
+    fn program() -> State {
+        let mut bytes = vec![0; 0x18];
+        bytes[..10].copy_from_slice(&[0x30, 0xf0, 5, 0, 0, 0, 0, 0, 0, 0]);
+        bytes[0x10] = 0xff;
+        let mut state = State::from_bytes(bytes);
+        state.set_sections(vec![
+            SectionInfo {
+                name: ".text".to_string(),
+                start: 0,
+                size: 0x10,
+                writable: false,
+                executable: true,
+            },
+            SectionInfo {
+                name: ".data".to_string(),
+                start: 0x10,
+                size: 0x8,
+                writable: true,
+                executable: false,
+            },
+        ]);
+        state
+    }
+
+    fn command(state: &mut State, input: &str) -> Result<(), Box<dyn Error>> {
+        let mut instr = Instruction::new(state).unwrap();
+        run(input.to_string(), &mut instr, state)
+    }
+
+    #[test]
+    fn moves_the_pc_only_where_execution_can_go_on() {
+        let mut state = program();
+        command(&mut state, "set pc = 0xa").unwrap();
+        assert_eq!(state.get_pc(), 0xa);
+        for input in ["set pc = 0x10", "set pc = 0x100", "jump 0x10", "jump 0x18"] {
+            assert!(command(&mut state, input).is_err(), "{}", input);
+            assert_eq!(state.get_pc(), 0xa, "{}", input);
+        }
+        command(&mut state, "jump 0x0").unwrap();
+        assert_eq!(state.get_pc(), 0);
+        // Whatever the commands left, the next instruction can be decoded
+        assert!(Instruction::new(&state).is_ok());
+    }
+
+    #[test]
+    fn runs_scripts_and_catches_recursive_ones() {
+        let dir = std::env::temp_dir().join(format!("y86-{}-source", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let outer = dir.join("outer.ydb");
+        let inner = dir.join("inner.ydb");
+        fs::write(&inner, "set %rbx = 7\n").unwrap();
+        let source_inner = format!("source {}", inner.display());
+        fs::write(&outer, format!("set %rcx = 3\n{}\n", source_inner)).unwrap();
+        let mut state = program();
+        command(&mut state, &format!("source {}", outer.display())).unwrap();
+        assert_eq!(state.get_register(Register::RRCX as u8), 3);
+        assert_eq!(state.get_register(Register::RRBX as u8), 7);
+        assert!(state.get_scripts().is_empty());
+        fs::write(&inner, format!("source {}\n", outer.display())).unwrap();
+        let e = command(&mut state, &format!("source {}", outer.display()))
+            .err()
+            .unwrap();
+        assert!(e.to_string().contains("recursive source of"));
+        // Both scripts are done with, so either can run again
+        assert!(state.get_scripts().is_empty());
+        fs::write(&inner, "set %rbx = 9\n").unwrap();
+        command(&mut state, &source_inner).unwrap();
+        assert_eq!(state.get_register(Register::RRBX as u8), 9);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,584 @@
+use super::instructions::{ICode, Instruction, CC_OVERFLOW_MASK, CC_SIGN_MASK, CC_ZERO_MASK};
+use super::print::register_id;
+use super::{load, State, StopReason};
+use crate::framing::{read_message, write_message};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::error::Error;
+use std::io::{BufRead, Write};
+use std::path::Path;
+
+// The only thread of a Y86 program
+const THREAD_ID: u64 = 1;
+
+// The variables reference of the registers scope
+const REGISTERS_REFERENCE: u64 = 1;
+
+const REGISTER_NAMES: [&str; 15] = [
+    "rax", "rcx", "rdx", "rbx", "rsp", "rbp", "rsi", "rdi", "r8", "r9", "r10", "r11", "r12", "r13",
+    "r14",
+];
+
+/// Encodes bytes as base64, the way memory is sent to the client
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut res = String::new();
+    for chunk in bytes.chunks(3) {
+        let bits = chunk.iter().enumerate().fold(0u32, |bits, (i, &byte)| {
+            bits | (byte as u32) << (16 - 8 * i)
+        });
+        for i in 0..4 {
+            if i <= chunk.len() {
+                res.push(ALPHABET[(bits >> (18 - 6 * i) & 0x3F) as usize] as char);
+            } else {
+                res.push('=');
+            }
+        }
+    }
+    res
+}
+
+/// Whether two paths name the same source file
+fn same_file(a: &str, b: &str) -> bool {
+    match (Path::new(a).canonicalize(), Path::new(b).canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+/// Parses a memory reference, an address written in hex or decimal
+fn parse_reference(reference: &str) -> Option<u64> {
+    match reference.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => reference.parse::<u64>().ok(),
+    }
+}
+
+/// A debugging session with a client such as an editor
+/// state: the program, once launched
+/// breakpoints: the ids of the breakpoints set in each source file, by
+/// the path the client uses for it
+/// stop_on_entry: whether to stop at the first instruction once the
+/// client is done configuring
+struct Session<W: Write> {
+    output: W,
+    seq: u64,
+    state: Option<State>,
+    breakpoints: HashMap<String, Vec<usize>>,
+    stop_on_entry: bool,
+}
+
+impl<W: Write> Session<W> {
+    fn send(&mut self, mut message: Value) -> Result<(), Box<dyn Error>> {
+        self.seq += 1;
+        message["seq"] = json!(self.seq);
+        write_message(&mut self.output, &message)
+    }
+
+    fn respond(&mut self, request: &Value, body: Value) -> Result<(), Box<dyn Error>> {
+        self.send(json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "success": true,
+            "command": request["command"],
+            "body": body,
+        }))
+    }
+
+    fn fail(&mut self, request: &Value, message: String) -> Result<(), Box<dyn Error>> {
+        self.send(json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "success": false,
+            "command": request["command"],
+            "message": message,
+        }))
+    }
+
+    fn event(&mut self, event: &str, body: Value) -> Result<(), Box<dyn Error>> {
+        self.send(json!({ "type": "event", "event": event, "body": body }))
+    }
+
+    fn state(&self) -> Result<&State, Box<dyn Error>> {
+        self.state
+            .as_ref()
+            .ok_or_else(|| "No program was launched".into())
+    }
+
+    /// Answers a request, returning false once the session is over
+    fn handle(&mut self, request: &Value) -> Result<bool, Box<dyn Error>> {
+        let args = &request["arguments"];
+        match request["command"].as_str().unwrap_or("") {
+            "initialize" => self.respond(
+                request,
+                json!({
+                    "supportsConfigurationDoneRequest": true,
+                    "supportsReadMemoryRequest": true,
+                }),
+            )?,
+            "launch" => {
+                let program = args["program"].as_str().ok_or("launch needs a program")?;
+                self.state = Some(load(program)?);
+                // The breakpoints of an earlier launch went with its state
+                self.breakpoints.clear();
+                self.stop_on_entry = args["stopOnEntry"].as_bool().unwrap_or(false);
+                self.respond(request, json!({}))?;
+                self.event("initialized", json!({}))?;
+            }
+            "setBreakpoints" => {
+                let body = self.set_breakpoints(args)?;
+                self.respond(request, body)?;
+            }
+            "configurationDone" => {
+                self.respond(request, json!({}))?;
+                if self.stop_on_entry {
+                    self.stopped("entry", None)?;
+                } else {
+                    self.resume(|_, _| false)?;
+                }
+            }
+            "threads" => self.respond(
+                request,
+                json!({ "threads": [{ "id": THREAD_ID, "name": "main" }] }),
+            )?,
+            "stackTrace" => {
+                let body = self.stack_trace()?;
+                self.respond(request, body)?;
+            }
+            "scopes" => self.respond(
+                request,
+                json!({ "scopes": [{
+                    "name": "Registers",
+                    "variablesReference": REGISTERS_REFERENCE,
+                    "expensive": false,
+                }]}),
+            )?,
+            "variables" => {
+                let body = self.variables()?;
+                self.respond(request, body)?;
+            }
+            "readMemory" => {
+                let body = self.read_memory(args)?;
+                self.respond(request, body)?;
+            }
+            "continue" => {
+                self.respond(request, json!({ "allThreadsContinued": true }))?;
+                self.resume(|_, _| false)?;
+            }
+            "next" | "stepIn" => {
+                self.respond(request, json!({}))?;
+                let state = self.state()?;
+                let start = state
+                    .line_at(state.get_pc())
+                    .map(|line| (line.file.clone(), line.line));
+                let depth = state.get_frames().len();
+                // next stays in the same call, stepIn also stops in
+                // functions it calls
+                let over = request["command"] == "next";
+                self.resume(move |_, state| {
+                    let line = state
+                        .line_at(state.get_pc())
+                        .map(|line| (line.file.clone(), line.line));
+                    let changed = start.is_none() || line.is_some() && line != start;
+                    changed && (!over || state.get_frames().len() <= depth)
+                })?;
+            }
+            "stepOut" => {
+                self.respond(request, json!({}))?;
+                let depth = self.state()?.get_frames().len();
+                self.resume(move |instr, state| {
+                    instr.get_icode() == ICode::IRET && state.get_frames().len() < depth
+                })?;
+            }
+            "disconnect" | "terminate" => {
+                self.respond(request, json!({}))?;
+                return Ok(false);
+            }
+            command => self.fail(request, format!("Unsupported request {}", command))?,
+        }
+        Ok(true)
+    }
+
+    /// Sets the breakpoints of a source file, each on the first line at
+    /// or after the one asked for that emitted code
+    fn set_breakpoints(&mut self, args: &Value) -> Result<Value, Box<dyn Error>> {
+        let path = args["source"]["path"]
+            .as_str()
+            .ok_or("setBreakpoints needs a source path")?
+            .to_string();
+        let requested: Vec<u64> = args["breakpoints"]
+            .as_array()
+            .map(|breakpoints| {
+                breakpoints
+                    .iter()
+                    .filter_map(|breakpoint| breakpoint["line"].as_u64())
+                    .collect()
+            })
+            .unwrap_or_default();
+        let previous = self.breakpoints.remove(&path).unwrap_or_default();
+        if let Some(state) = self.state.as_mut() {
+            for id in previous {
+                state.get_breakpoints_mut().delete(id);
+            }
+        }
+        let mut ids = vec![];
+        let mut breakpoints = vec![];
+        for line in requested {
+            let found = self.state.as_ref().and_then(|state| {
+                state
+                    .get_lines()
+                    .iter()
+                    .filter(|info| info.line as u64 >= line && same_file(&info.file, &path))
+                    .min_by_key(|info| (info.line, info.address))
+                    .map(|info| (info.address, info.line))
+            });
+            match (self.state.as_mut(), found) {
+                (Some(state), Some((address, line))) => {
+                    ids.push(state.get_breakpoints_mut().add(address, None, false)?);
+                    breakpoints.push(json!({ "verified": true, "line": line }));
+                }
+                _ => breakpoints.push(json!({
+                    "verified": false,
+                    "line": line,
+                    "message": "No code at or after this line",
+                })),
+            }
+        }
+        self.breakpoints.insert(path, ids);
+        Ok(json!({ "breakpoints": breakpoints }))
+    }
+
+    /// Describes a frame by the address it is at
+    fn frame(&self, id: usize, address: u64) -> Result<Value, Box<dyn Error>> {
+        let state = self.state()?;
+        let name = state
+            .describe_address(address)
+            .unwrap_or_else(|| format!("0x{:x}", address));
+        let mut frame = json!({
+            "id": id,
+            "name": name,
+            "line": 0,
+            "column": 0,
+            "instructionPointerReference": format!("0x{:x}", address),
+        });
+        if let Some(line) = state.line_at(address) {
+            let path = Path::new(&line.file);
+            frame["source"] = json!({
+                "name": path.file_name().map(|name| name.to_string_lossy().to_string()),
+                "path": path.canonicalize().unwrap_or_else(|_| path.to_path_buf()),
+            });
+            frame["line"] = json!(line.line);
+            frame["column"] = json!(1);
+        }
+        Ok(frame)
+    }
+
+    /// The PC, then the call of every frame that has not returned yet,
+    /// innermost first
+    fn stack_trace(&self) -> Result<Value, Box<dyn Error>> {
+        let state = self.state()?;
+        let mut addresses = vec![state.get_pc()];
+        addresses.extend(state.get_frames().iter().rev().map(|frame| frame.call_site));
+        let frames = addresses
+            .iter()
+            .enumerate()
+            .map(|(id, &address)| self.frame(id, address))
+            .collect::<Result<Vec<Value>, Box<dyn Error>>>()?;
+        Ok(json!({ "stackFrames": frames, "totalFrames": frames.len() }))
+    }
+
+    /// The registers, the PC, the condition codes and the status
+    fn variables(&self) -> Result<Value, Box<dyn Error>> {
+        let state = self.state()?;
+        let variable = |name: &str, value: String| json!({ "name": name, "value": value, "variablesReference": 0 });
+        let mut variables: Vec<Value> = REGISTER_NAMES
+            .iter()
+            .map(|name| {
+                let value = state.get_register(register_id(name).unwrap());
+                variable(&format!("%{}", name), format!("0x{:x}", value))
+            })
+            .collect();
+        variables.push(variable("%pc", format!("0x{:x}", state.get_pc())));
+        let cc = state.get_condition_code();
+        for (name, mask) in [
+            ("ZF", CC_ZERO_MASK),
+            ("SF", CC_SIGN_MASK),
+            ("OF", CC_OVERFLOW_MASK),
+        ] {
+            variables.push(variable(name, ((cc & mask != 0) as u8).to_string()));
+        }
+        variables.push(variable("stat", state.status().to_string()));
+        Ok(json!({ "variables": variables }))
+    }
+
+    /// Reads memory, reporting the bytes past the end as unreadable
+    fn read_memory(&self, args: &Value) -> Result<Value, Box<dyn Error>> {
+        let state = self.state()?;
+        let reference = args["memoryReference"]
+            .as_str()
+            .and_then(parse_reference)
+            .ok_or("Invalid memory reference")?;
+        let address = reference.wrapping_add(args["offset"].as_i64().unwrap_or(0) as u64);
+        let count = args["count"].as_u64().unwrap_or(0);
+        let bytes: Vec<u8> = (0..count)
+            .map_while(|i| state.peek_byte(address.checked_add(i)?).ok())
+            .collect();
+        Ok(json!({
+            "address": format!("0x{:x}", address),
+            "data": base64(&bytes),
+            "unreadableBytes": count - bytes.len() as u64,
+        }))
+    }
+
+    fn stopped(&mut self, reason: &str, text: Option<String>) -> Result<(), Box<dyn Error>> {
+        self.event(
+            "stopped",
+            json!({
+                "reason": reason,
+                "text": text,
+                "threadId": THREAD_ID,
+                "allThreadsStopped": true,
+            }),
+        )
+    }
+
+    /// Runs the program with State::run_until, then tells the client
+    /// why it stopped
+    /// done: given the instruction just executed and the resulting state
+    fn resume<F>(&mut self, done: F) -> Result<(), Box<dyn Error>>
+    where
+        F: FnMut(&Instruction, &State) -> bool,
+    {
+        let state = self.state.as_mut().ok_or("No program was launched")?;
+        let (reason, text) = match state.track_changes(|state| state.run_until(done)) {
+            StopReason::Done => ("step", None),
+            StopReason::Breakpoint(_) => ("breakpoint", None),
+            StopReason::Watchpoint(..) => ("data breakpoint", None),
+            stop @ StopReason::StepLimit(_) => ("pause", Some(stop)),
+            StopReason::Halted => {
+                self.event("exited", json!({ "exitCode": 0 }))?;
+                return self.event("terminated", json!({}));
+            }
+            stop => ("exception", Some(stop)),
+        };
+        self.stopped(reason, text.map(|stop| stop.to_string()))
+    }
+}
+
+/// Runs a debugging session over input and output until the client
+/// disconnects
+pub fn serve<R: BufRead, W: Write>(mut input: R, output: W) -> Result<(), Box<dyn Error>> {
+    let mut session = Session {
+        output,
+        seq: 0,
+        state: None,
+        breakpoints: HashMap::new(),
+        stop_on_entry: false,
+    };
+    while let Some(request) = read_message(&mut input)? {
+        if request["type"] != "request" {
+            continue;
+        }
+        match session.handle(&request) {
+            Ok(true) => (),
+            Ok(false) => break,
+            Err(e) => session.fail(&request, e.to_string())?,
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    const PROGRAM: &str = "    irmovq stack, %rsp
+    irmovq $5, %rax
+    call double
+    halt
+double:
+    addq %rax, %rax
+    ret
+    .pos 0x100
+stack:
+";
+
+    /// A source file of its own for each test, removed once it is done
+    struct Source(PathBuf);
+
+    impl Source {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("y86-{}-{}.ys", std::process::id(), name));
+            std::fs::write(&path, PROGRAM).unwrap();
+            Source(path)
+        }
+
+        fn path(&self) -> String {
+            self.0.to_string_lossy().to_string()
+        }
+    }
+
+    impl Drop for Source {
+        fn drop(&mut self) {
+            std::fs::remove_file(&self.0).ok();
+        }
+    }
+
+    /// Runs a session answering requests, each given as its command and
+    /// arguments, and returns every message sent back
+    fn session(requests: &[(&str, Value)]) -> Vec<Value> {
+        let mut input = vec![];
+        for (seq, (command, arguments)) in requests.iter().enumerate() {
+            let request = json!({
+                "seq": seq + 1,
+                "type": "request",
+                "command": command,
+                "arguments": arguments,
+            });
+            write_message(&mut input, &request).unwrap();
+        }
+        let mut output = vec![];
+        serve(&input[..], &mut output).unwrap();
+        let mut output = &output[..];
+        let mut messages = vec![];
+        while let Some(message) = read_message(&mut output).unwrap() {
+            messages.push(message);
+        }
+        messages
+    }
+
+    fn response<'a>(messages: &'a [Value], command: &str) -> &'a Value {
+        messages
+            .iter()
+            .find(|message| message["type"] == "response" && message["command"] == command)
+            .unwrap()
+    }
+
+    fn events<'a>(messages: &'a [Value], event: &str) -> Vec<&'a Value> {
+        messages
+            .iter()
+            .filter(|message| message["event"] == event)
+            .collect()
+    }
+
+    #[test]
+    fn encodes_memory_as_base64() {
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"f"), "Zg==");
+        assert_eq!(base64(b"fo"), "Zm8=");
+        assert_eq!(base64(b"foo"), "Zm9v");
+        assert_eq!(base64(&[0xff, 0xfe, 0xfd, 0xfc]), "//79/A==");
+    }
+
+    #[test]
+    fn stops_on_entry_then_at_breakpoints() {
+        let source = Source::new("dap-breakpoints");
+        let messages = session(&[
+            ("initialize", json!({})),
+            (
+                "launch",
+                json!({ "program": source.path(), "stopOnEntry": true }),
+            ),
+            (
+                "setBreakpoints",
+                json!({
+                    "source": { "path": source.path() },
+                    "breakpoints": [{ "line": 5 }, { "line": 20 }],
+                }),
+            ),
+            ("configurationDone", json!({})),
+            ("continue", json!({})),
+            ("stackTrace", json!({ "threadId": 1 })),
+            ("variables", json!({ "variablesReference": 1 })),
+            ("continue", json!({})),
+            ("disconnect", json!({})),
+        ]);
+        let seqs: Vec<u64> = messages
+            .iter()
+            .map(|message| message["seq"].as_u64().unwrap())
+            .collect();
+        assert_eq!(seqs, (1..=messages.len() as u64).collect::<Vec<u64>>());
+        assert_eq!(
+            response(&messages, "initialize")["body"]["supportsReadMemoryRequest"],
+            true
+        );
+        assert_eq!(events(&messages, "initialized").len(), 1);
+        // A breakpoint moves to the next line with code, or is left
+        // unverified if there is none
+        assert_eq!(
+            response(&messages, "setBreakpoints")["body"]["breakpoints"],
+            json!([
+                { "verified": true, "line": 6 },
+                {
+                    "verified": false,
+                    "line": 20,
+                    "message": "No code at or after this line",
+                },
+            ])
+        );
+        let stopped = events(&messages, "stopped");
+        assert_eq!(stopped.len(), 2);
+        assert_eq!(stopped[0]["body"]["reason"], "entry");
+        assert_eq!(stopped[1]["body"]["reason"], "breakpoint");
+        let frames = &response(&messages, "stackTrace")["body"]["stackFrames"];
+        assert_eq!(frames.as_array().unwrap().len(), 2);
+        assert_eq!(frames[0]["name"], "double");
+        assert_eq!(frames[0]["line"], 6);
+        assert_eq!(frames[1]["line"], 3);
+        let variables = &response(&messages, "variables")["body"]["variables"];
+        assert_eq!(
+            variables[0],
+            json!({ "name": "%rax", "value": "0x5", "variablesReference": 0 })
+        );
+        assert_eq!(events(&messages, "exited")[0]["body"]["exitCode"], 0);
+        assert_eq!(events(&messages, "terminated").len(), 1);
+        assert_eq!(response(&messages, "disconnect")["success"], true);
+    }
+
+    #[test]
+    fn reads_memory_up_to_the_end_of_the_program() {
+        let source = Source::new("dap-memory");
+        let messages = session(&[
+            ("launch", json!({ "program": source.path() })),
+            (
+                "readMemory",
+                json!({ "memoryReference": "0x0", "offset": 1, "count": 2 }),
+            ),
+            (
+                "readMemory",
+                json!({ "memoryReference": "0xfffffffffffffffe", "count": 4 }),
+            ),
+            ("readMemory", json!({ "memoryReference": "nowhere" })),
+        ]);
+        let reads: Vec<&Value> = messages
+            .iter()
+            .filter(|message| message["command"] == "readMemory")
+            .collect();
+        // irmovq stack, %rsp starts 30 f4
+        assert_eq!(
+            reads[0]["body"],
+            json!({ "address": "0x1", "data": "9AA=", "unreadableBytes": 0 })
+        );
+        assert_eq!(
+            reads[1]["body"],
+            json!({ "address": "0xfffffffffffffffe", "data": "", "unreadableBytes": 4 })
+        );
+        assert_eq!(reads[2]["success"], false);
+        assert_eq!(reads[2]["message"], "Invalid memory reference");
+    }
+
+    #[test]
+    fn fails_requests_it_cannot_answer() {
+        let messages = session(&[
+            ("stackTrace", json!({})),
+            ("evaluate", json!({ "expression": "%rax" })),
+            ("launch", json!({})),
+        ]);
+        assert_eq!(messages.len(), 3);
+        assert!(messages.iter().all(|message| message["success"] == false));
+        assert_eq!(messages[0]["message"], "No program was launched");
+        assert_eq!(messages[1]["message"], "Unsupported request evaluate");
+        assert_eq!(messages[2]["message"], "launch needs a program");
+    }
+}
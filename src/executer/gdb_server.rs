@@ -0,0 +1,593 @@
+use super::instructions::{DivideByZero, InvalidICode};
+use super::{State, StopReason};
+use std::error::Error;
+use std::io::{self, BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+/// Describes the registers of a Y86 program to gdb, in the order `g`
+/// sends them: the 15 registers, the PC and the condition codes
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.y86.core">
+    <reg name="rax" bitsize="64" type="int64" regnum="0"/>
+    <reg name="rcx" bitsize="64" type="int64"/>
+    <reg name="rdx" bitsize="64" type="int64"/>
+    <reg name="rbx" bitsize="64" type="int64"/>
+    <reg name="rsp" bitsize="64" type="data_ptr"/>
+    <reg name="rbp" bitsize="64" type="data_ptr"/>
+    <reg name="rsi" bitsize="64" type="int64"/>
+    <reg name="rdi" bitsize="64" type="int64"/>
+    <reg name="r8" bitsize="64" type="int64"/>
+    <reg name="r9" bitsize="64" type="int64"/>
+    <reg name="r10" bitsize="64" type="int64"/>
+    <reg name="r11" bitsize="64" type="int64"/>
+    <reg name="r12" bitsize="64" type="int64"/>
+    <reg name="r13" bitsize="64" type="int64"/>
+    <reg name="r14" bitsize="64" type="int64"/>
+    <reg name="pc" bitsize="64" type="code_ptr"/>
+    <flags id="y86_cc" size="4">
+      <field name="ZF" start="0" end="0"/>
+      <field name="SF" start="1" end="1"/>
+      <field name="OF" start="2" end="2"/>
+    </flags>
+    <reg name="cc" bitsize="32" type="y86_cc"/>
+  </feature>
+</target>
+"#;
+
+// Register numbers of the PC and the condition codes, after the 15
+// registers
+const PC_REGISTER: usize = 15;
+const CC_REGISTER: usize = 16;
+
+// How many instructions run between checks for gdb interrupting
+const INTERRUPT_CHECK: u64 = 4096;
+
+// Signals reported to gdb for why the program stopped
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGFPE: u8 = 8;
+const SIGSEGV: u8 = 11;
+
+/// Why the program stopped running
+enum Stop {
+    Signal(u8),
+    Halted,
+}
+
+impl Stop {
+    /// The stop reply packet for gdb
+    fn reply(&self) -> String {
+        match self {
+            Stop::Signal(signal) => format!("S{:02x}", signal),
+            Stop::Halted => "W00".to_string(),
+        }
+    }
+}
+
+/// A connection to gdb, exchanging packets of the form `$data#checksum`
+struct Connection {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl Connection {
+    fn new(stream: TcpStream) -> io::Result<Self> {
+        Ok(Connection {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: stream,
+        })
+    }
+
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        let mut byte = [0];
+        match self.reader.read(&mut byte)? {
+            0 => Ok(None),
+            _ => Ok(Some(byte[0])),
+        }
+    }
+
+    /// Waits for the next packet, acknowledging it
+    /// Returns None once gdb disconnects
+    fn receive(&mut self) -> io::Result<Option<String>> {
+        loop {
+            // Acknowledgements and interrupts sent while the program
+            // was stopped carry nothing to do
+            match self.read_byte()? {
+                Some(b'$') => (),
+                Some(_) => continue,
+                None => return Ok(None),
+            }
+            let mut data = vec![];
+            loop {
+                match self.read_byte()? {
+                    Some(b'#') => break,
+                    Some(byte) => data.push(byte),
+                    None => return Ok(None),
+                }
+            }
+            let mut checksum = [0; 2];
+            self.reader.read_exact(&mut checksum)?;
+            let expected = std::str::from_utf8(&checksum)
+                .ok()
+                .and_then(|checksum| u8::from_str_radix(checksum, 16).ok());
+            if expected != Some(checksum_of(&data)) {
+                self.writer.write_all(b"-")?;
+                continue;
+            }
+            self.writer.write_all(b"+")?;
+            return Ok(Some(String::from_utf8_lossy(&data).to_string()));
+        }
+    }
+
+    /// Sends a packet, escaping the characters that frame packets
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        let mut escaped = vec![];
+        for &byte in data.as_bytes() {
+            match byte {
+                b'$' | b'#' | b'}' | b'*' => escaped.extend_from_slice(&[b'}', byte ^ 0x20]),
+                _ => escaped.push(byte),
+            }
+        }
+        let mut packet = vec![b'$'];
+        packet.extend_from_slice(&escaped);
+        packet.extend_from_slice(format!("#{:02x}", checksum_of(&escaped)).as_bytes());
+        self.writer.write_all(&packet)?;
+        self.writer.flush()
+    }
+
+    /// Whether gdb sent an interrupt while the program was running
+    fn interrupted(&mut self) -> io::Result<bool> {
+        self.reader.get_ref().set_nonblocking(true)?;
+        let pending = match self.reader.fill_buf() {
+            Ok(buffer) => buffer.first().copied(),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => None,
+            Err(e) => return Err(e),
+        };
+        self.reader.get_ref().set_nonblocking(false)?;
+        if pending == Some(0x03) {
+            self.reader.consume(1);
+            return Ok(true);
+        }
+        Ok(false)
+    }
+}
+
+fn checksum_of(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte))
+}
+
+/// Writes value as size bytes of little-endian hex, the way gdb
+/// exchanges registers
+fn hex_le(value: u64, size: usize) -> String {
+    (0..size)
+        .map(|i| format!("{:02x}", (value >> (8 * i)) & 0xFF))
+        .collect()
+}
+
+/// Reads little-endian hex as written by hex_le
+fn parse_hex_le(hex: &str) -> Option<u64> {
+    if !hex.len().is_multiple_of(2) || hex.len() > 16 {
+        return None;
+    }
+    let mut value = 0;
+    for i in (0..hex.len()).step_by(2).rev() {
+        value = (value << 8) | u64::from_str_radix(hex.get(i..i + 2)?, 16).ok()?;
+    }
+    Some(value)
+}
+
+/// Reads hex written two digits per byte, such as the data of an `M`
+/// packet
+fn parse_hex_bytes(hex: &str) -> Option<Vec<u8>> {
+    let hex = hex.as_bytes();
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    hex.chunks(2)
+        .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok())
+        .collect()
+}
+
+fn parse_hex(hex: &str) -> Option<u64> {
+    u64::from_str_radix(hex, 16).ok()
+}
+
+/// The size in bytes gdb expects a register to have
+fn register_size(register: usize) -> usize {
+    if register == CC_REGISTER {
+        4
+    } else {
+        8
+    }
+}
+
+fn get_register(state: &State, register: usize) -> u64 {
+    match register {
+        PC_REGISTER => state.get_pc(),
+        CC_REGISTER => state.get_condition_code() as u64,
+        _ => state.get_register(register as u8),
+    }
+}
+
+fn set_register(state: &mut State, register: usize, value: u64) {
+    match register {
+        PC_REGISTER => state.set_pc(value),
+        CC_REGISTER => state.set_condition_code(value as u8),
+        _ => state.set_register(register as u8, value),
+    }
+}
+
+/// The signal gdb is told of for an error that stopped the program
+fn signal(e: &(dyn Error + 'static)) -> u8 {
+    if e.is::<InvalidICode>() {
+        SIGILL
+    } else if e.is::<DivideByZero>() {
+        SIGFPE
+    } else {
+        SIGSEGV
+    }
+}
+
+/// How gdb is told why State::run_until stopped
+fn stop(reason: StopReason) -> Stop {
+    match reason {
+        StopReason::Halted => Stop::Halted,
+        StopReason::Error(e) => Stop::Signal(signal(e.as_ref())),
+        StopReason::StepLimit(_) => Stop::Signal(SIGINT),
+        _ => Stop::Signal(SIGTRAP),
+    }
+}
+
+/// Executes the instruction at the PC, unless it is a halt
+fn step(state: &mut State) -> Stop {
+    stop(state.run_until(|_, _| true))
+}
+
+/// Runs the program until it reaches a breakpoint, cannot go on or
+/// gdb interrupts it
+fn resume(state: &mut State, connection: &mut Connection) -> io::Result<Stop> {
+    let mut steps: u64 = 0;
+    let mut interrupted = Ok(false);
+    let reason = state.run_until(|_, _| {
+        steps += 1;
+        if steps.is_multiple_of(INTERRUPT_CHECK) {
+            interrupted = connection.interrupted();
+        }
+        !matches!(interrupted, Ok(false))
+    });
+    match (reason, interrupted?) {
+        (StopReason::Done, true) => Ok(Stop::Signal(SIGINT)),
+        (reason, _) => Ok(stop(reason)),
+    }
+}
+
+/// Answers a packet from gdb, an empty reply meaning it is not
+/// supported
+fn reply(packet: &str, state: &mut State, connection: &mut Connection) -> io::Result<String> {
+    const ERROR: &str = "E01";
+    let args = packet.get(1..).unwrap_or("");
+    let reply = match packet.chars().next() {
+        Some('?') => Stop::Signal(SIGTRAP).reply(),
+        Some('g') => (0..=CC_REGISTER)
+            .map(|register| hex_le(get_register(state, register), register_size(register)))
+            .collect(),
+        Some('G') => {
+            // Nothing is written unless every register can be
+            let mut values = vec![];
+            let mut offset = 0;
+            for register in 0..=CC_REGISTER {
+                let len = register_size(register) * 2;
+                match args.get(offset..offset + len).and_then(parse_hex_le) {
+                    Some(value) => values.push(value),
+                    None => return Ok(ERROR.to_string()),
+                }
+                offset += len;
+            }
+            for (register, value) in values.into_iter().enumerate() {
+                set_register(state, register, value);
+            }
+            "OK".to_string()
+        }
+        Some('p') => match parse_hex(args) {
+            Some(register) if register as usize <= CC_REGISTER => {
+                let register = register as usize;
+                hex_le(get_register(state, register), register_size(register))
+            }
+            _ => ERROR.to_string(),
+        },
+        Some('P') => {
+            let split = args.split_once('=').and_then(|(register, value)| {
+                Some((parse_hex(register)? as usize, parse_hex_le(value)?))
+            });
+            match split {
+                Some((register, value)) if register <= CC_REGISTER => {
+                    set_register(state, register, value);
+                    "OK".to_string()
+                }
+                _ => ERROR.to_string(),
+            }
+        }
+        Some('m') => {
+            let range = args
+                .split_once(',')
+                .and_then(|(address, len)| Some((parse_hex(address)?, parse_hex(len)?)));
+            match range {
+                Some((address, len)) => {
+                    let bytes: String = (0..len)
+                        .map_while(|i| state.peek_byte(address.checked_add(i)?).ok())
+                        .map(|byte| format!("{:02x}", byte))
+                        .collect();
+                    if bytes.is_empty() && len > 0 {
+                        ERROR.to_string()
+                    } else {
+                        bytes
+                    }
+                }
+                None => ERROR.to_string(),
+            }
+        }
+        Some('M') => {
+            let write = args.split_once(':').and_then(|(range, data)| {
+                let (address, len) = range.split_once(',')?;
+                Some((parse_hex(address)?, parse_hex(len)?, data))
+            });
+            match write {
+                Some((address, len, data)) => {
+                    // Nothing is written unless all of it can be
+                    let bytes = parse_hex_bytes(data).filter(|bytes| bytes.len() as u64 == len);
+                    let in_bounds = |state: &State| {
+                        (0..len).all(|i| {
+                            address
+                                .checked_add(i)
+                                .is_some_and(|address| state.peek_byte(address).is_ok())
+                        })
+                    };
+                    match bytes {
+                        Some(bytes) if in_bounds(state) => {
+                            for (address, byte) in (address..).zip(bytes) {
+                                state.poke_byte(address, byte).ok();
+                            }
+                            "OK".to_string()
+                        }
+                        _ => ERROR.to_string(),
+                    }
+                }
+                _ => ERROR.to_string(),
+            }
+        }
+        Some('s') | Some('c') => {
+            if let Some(address) = parse_hex(args) {
+                state.set_pc(address);
+            }
+            let stop = if packet.starts_with('s') {
+                step(state)
+            } else {
+                resume(state, connection)?
+            };
+            stop.reply()
+        }
+        Some('Z') | Some('z') => {
+            let breakpoint = args.split(',').collect::<Vec<&str>>();
+            match breakpoint.as_slice() {
+                ["0", address, _] => match parse_hex(address) {
+                    Some(address) => {
+                        let breakpoints = state.get_breakpoints_mut();
+                        if packet.starts_with('Z') {
+                            if !breakpoints.is_set(address) {
+                                breakpoints.add(address, None, false).ok();
+                            }
+                        } else {
+                            breakpoints.delete_at(address);
+                        }
+                        "OK".to_string()
+                    }
+                    None => ERROR.to_string(),
+                },
+                // Only software breakpoints are supported
+                _ => String::new(),
+            }
+        }
+        Some('H') => "OK".to_string(),
+        Some('q') => query(args),
+        _ => String::new(),
+    };
+    Ok(reply)
+}
+
+/// Answers the general queries gdb makes when it connects
+fn query(query: &str) -> String {
+    let features = "Xfer:features:read:target.xml:";
+    if let Some(range) = query.strip_prefix(features) {
+        let range = range.split_once(',').and_then(|(offset, len)| {
+            Some((parse_hex(offset)? as usize, parse_hex(len)? as usize))
+        });
+        return match range {
+            Some((offset, len)) if offset <= TARGET_XML.len() => {
+                let end = TARGET_XML.len().min(offset.saturating_add(len));
+                let more = if end < TARGET_XML.len() { 'm' } else { 'l' };
+                format!("{}{}", more, &TARGET_XML[offset..end])
+            }
+            _ => "E01".to_string(),
+        };
+    }
+    match query.split(':').next().unwrap_or("") {
+        "Supported" => "PacketSize=4000;qXfer:features:read+".to_string(),
+        "Attached" => "1".to_string(),
+        "C" => "QC1".to_string(),
+        "fThreadInfo" => "m1".to_string(),
+        "sThreadInfo" => "l".to_string(),
+        _ => String::new(),
+    }
+}
+
+/// Waits for gdb to connect to address, then lets it debug the program
+/// in state until it detaches, kills the program or disconnects
+/// address: where to listen, such as `127.0.0.1:1234`
+pub fn serve(state: &mut State, address: &str) -> Result<(), Box<dyn Error>> {
+    let listener = TcpListener::bind(address)?;
+    println!("## Waiting for gdb on {}", listener.local_addr()?);
+    let (stream, peer) = listener.accept()?;
+    println!("## gdb connected from {}", peer);
+    let mut connection = Connection::new(stream)?;
+    while let Some(packet) = connection.receive()? {
+        match packet.as_str() {
+            "D" => {
+                connection.send("OK")?;
+                break;
+            }
+            "k" => break,
+            _ => {
+                let reply = reply(&packet, state, &mut connection)?;
+                connection.send(&reply)?;
+            }
+        }
+    }
+    println!("## gdb disconnected");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A connection to a gdb played by the test, through the stream
+    /// returned along with it
+    fn connect() -> (Connection, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let gdb = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        (Connection::new(stream).unwrap(), gdb)
+    }
+
+    /// irmovq $0x5, %rax; subq %rax, %rax; halt
+    fn program() -> State {
+        let mut bytes = vec![0; 0x20];
+        bytes[..10].copy_from_slice(&[0x30, 0xf0, 5, 0, 0, 0, 0, 0, 0, 0]);
+        bytes[10..12].copy_from_slice(&[0x61, 0x00]);
+        State::from_bytes(bytes)
+    }
+
+    fn read_exactly(gdb: &mut TcpStream, len: usize) -> String {
+        let mut buffer = vec![0; len];
+        gdb.read_exact(&mut buffer).unwrap();
+        String::from_utf8(buffer).unwrap()
+    }
+
+    #[test]
+    fn converts_little_endian_hex() {
+        assert_eq!(hex_le(0x1234, 8), "3412000000000000");
+        assert_eq!(hex_le(0x5, 4), "05000000");
+        assert_eq!(parse_hex_le("3412000000000000"), Some(0x1234));
+        assert_eq!(parse_hex_le("ffffffffffffffff"), Some(u64::MAX));
+        assert_eq!(parse_hex_le("123"), None);
+        assert_eq!(parse_hex_le("000000000000000000"), None);
+        assert_eq!(parse_hex_le("zz"), None);
+        assert_eq!(parse_hex_bytes("00ff10"), Some(vec![0, 0xff, 0x10]));
+        assert_eq!(parse_hex_bytes("0"), None);
+    }
+
+    #[test]
+    fn acknowledges_packets_with_a_valid_checksum() {
+        let (mut connection, mut gdb) = connect();
+        gdb.write_all(b"+$g#00$g#67").unwrap();
+        assert_eq!(connection.receive().unwrap().as_deref(), Some("g"));
+        assert_eq!(read_exactly(&mut gdb, 2), "-+");
+        drop(gdb);
+        assert_eq!(connection.receive().unwrap(), None);
+    }
+
+    #[test]
+    fn escapes_the_characters_that_frame_packets() {
+        let (mut connection, mut gdb) = connect();
+        connection.send("a$b#").unwrap();
+        let escaped = "a}\u{4}b}\u{3}";
+        let expected = format!("${}#{:02x}", escaped, checksum_of(escaped.as_bytes()));
+        assert_eq!(read_exactly(&mut gdb, expected.len()), expected);
+    }
+
+    #[test]
+    fn reads_and_writes_registers_and_memory() {
+        let (mut connection, _gdb) = connect();
+        let mut state = program();
+        let mut reply =
+            |packet: &str, state: &mut State| reply(packet, state, &mut connection).unwrap();
+        assert_eq!(reply("P0=2a00000000000000", &mut state), "OK");
+        assert_eq!(reply("p0", &mut state), "2a00000000000000");
+        assert_eq!(reply("p10", &mut state), "00000000");
+        assert_eq!(reply("p11", &mut state), "E01");
+        let registers = reply("g", &mut state);
+        assert_eq!(registers.len(), 16 * 16 + 8);
+        assert!(registers.starts_with("2a00000000000000"));
+        assert_eq!(reply("G00", &mut state), "E01");
+        // A packet cut short changes none of the registers
+        let cut = format!("G{}", "1".repeat(16 * 16));
+        assert_eq!(reply(&cut, &mut state), "E01");
+        assert_eq!(state.get_register(0), 0x2a);
+        assert_eq!(
+            reply(&format!("G{}", "0".repeat(16 * 16 + 8)), &mut state),
+            "OK"
+        );
+        assert_eq!(state.get_register(0), 0);
+        assert_eq!(reply("m0,2", &mut state), "30f0");
+        assert_eq!(reply("M18,2:abcd", &mut state), "OK");
+        assert_eq!(reply("m18,3", &mut state), "abcd00");
+        // Reads stop at the end of memory, writes past it do nothing
+        assert_eq!(reply("m1e,4", &mut state), "0000");
+        assert_eq!(reply("m20,1", &mut state), "E01");
+        assert_eq!(reply("M1f,2:1111", &mut state), "E01");
+        assert_eq!(reply("m1e,2", &mut state), "0000");
+        assert_eq!(reply("M0,2:11", &mut state), "E01");
+    }
+
+    #[test]
+    fn steps_and_continues_to_breakpoints() {
+        let (mut connection, _gdb) = connect();
+        let mut state = program();
+        let mut reply =
+            |packet: &str, state: &mut State| reply(packet, state, &mut connection).unwrap();
+        assert_eq!(reply("?", &mut state), "S05");
+        assert_eq!(reply("s", &mut state), "S05");
+        assert_eq!(state.get_pc(), 0xa);
+        assert_eq!(reply("Z0,a,1", &mut state), "OK");
+        // Continuing from an address runs the program again from there
+        assert_eq!(reply("c0", &mut state), "S05");
+        assert_eq!(state.get_pc(), 0xa);
+        assert_eq!(state.get_register(0), 5);
+        assert_eq!(reply("z0,a,1", &mut state), "OK");
+        assert_eq!(reply("c0", &mut state), "W00");
+        assert_eq!(state.get_pc(), 0xc);
+        assert_eq!(state.get_register(0), 0);
+        // Only software breakpoints are supported
+        assert_eq!(reply("Z1,a,1", &mut state), "");
+    }
+
+    #[test]
+    fn stops_a_running_program_when_gdb_interrupts() {
+        let (mut connection, mut gdb) = connect();
+        // jmp 0x0, forever
+        let mut state = State::from_bytes(vec![0x70, 0, 0, 0, 0, 0, 0, 0, 0]);
+        gdb.write_all(&[0x03]).unwrap();
+        assert_eq!(reply("c", &mut state, &mut connection).unwrap(), "S02");
+        assert_eq!(state.get_pc(), 0);
+    }
+
+    #[test]
+    fn describes_the_target_to_gdb() {
+        assert_eq!(
+            query("Supported:xmlRegisters=i386"),
+            "PacketSize=4000;qXfer:features:read+"
+        );
+        assert_eq!(query("Attached"), "1");
+        assert_eq!(query("Unknown"), "");
+        let start = query("Xfer:features:read:target.xml:0,10");
+        assert_eq!(start, format!("m{}", &TARGET_XML[..0x10]));
+        let end = query(&format!("Xfer:features:read:target.xml:{:x},1000", 0x10));
+        assert_eq!(end, format!("l{}", &TARGET_XML[0x10..]));
+        assert_eq!(query("Xfer:features:read:target.xml:ffff,10"), "E01");
+        let all = query("Xfer:features:read:target.xml:0,ffffffffffffffff");
+        assert_eq!(all, format!("l{}", TARGET_XML));
+    }
+}
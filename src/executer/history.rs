@@ -0,0 +1,179 @@
+use super::Frame;
+use std::collections::VecDeque;
+
+// How many instructions can be stepped back over unless told otherwise
+pub const DEFAULT_LIMIT: usize = 10000;
+
+/// What an executed instruction changed, enough to put it back
+/// memory: the address and previous value of every quad it wrote, in
+/// the order they were written
+struct Undo {
+    registers: Vec<u64>,
+    condition_code: u8,
+    program_counter: u64,
+    frames: Vec<Frame>,
+    memory: Vec<(u64, u64)>,
+}
+
+/// The undo log of the instructions executed so far, oldest first
+/// limit: how many instructions are kept, older ones are forgotten
+/// current: the instruction being executed, kept whatever the limit so
+/// that it can be rolled back if it fails
+pub struct History {
+    entries: VecDeque<Undo>,
+    limit: usize,
+    current: Option<Undo>,
+}
+
+/// The state an instruction was executed from, as restored by undo
+pub struct Restore {
+    pub registers: Vec<u64>,
+    pub condition_code: u8,
+    pub program_counter: u64,
+    pub frames: Vec<Frame>,
+    pub memory: Vec<(u64, u64)>,
+}
+
+impl Undo {
+    fn restore(self) -> Restore {
+        Restore {
+            registers: self.registers,
+            condition_code: self.condition_code,
+            program_counter: self.program_counter,
+            frames: self.frames,
+            memory: self.memory.into_iter().rev().collect(),
+        }
+    }
+}
+
+impl Default for History {
+    fn default() -> Self {
+        History {
+            entries: VecDeque::new(),
+            limit: DEFAULT_LIMIT,
+            current: None,
+        }
+    }
+}
+
+impl History {
+    /// Starts recording an instruction executed from the given state
+    pub fn begin(
+        &mut self,
+        registers: &[u64],
+        condition_code: u8,
+        program_counter: u64,
+        frames: &[Frame],
+    ) {
+        self.current = Some(Undo {
+            registers: registers.to_vec(),
+            condition_code,
+            program_counter,
+            frames: frames.to_vec(),
+            memory: vec![],
+        });
+    }
+
+    /// Stops recording the current instruction, adding it to the log
+    /// unless recording is off
+    pub fn end(&mut self) {
+        let entry = match self.current.take() {
+            Some(entry) => entry,
+            None => return,
+        };
+        if self.limit == 0 {
+            return;
+        }
+        while self.entries.len() >= self.limit {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(entry);
+    }
+
+    /// Stops recording the current instruction without adding it to the
+    /// log, returning what it changed so that it can be rolled back
+    pub fn abort(&mut self) -> Option<Restore> {
+        self.current.take().map(Undo::restore)
+    }
+
+    /// Remembers the value a quad held before the current instruction
+    /// overwrote it
+    pub fn write(&mut self, address: u64, old: u64) {
+        if let Some(entry) = &mut self.current {
+            entry.memory.push((address, old));
+        }
+    }
+
+    /// Takes the newest entry off the log, with the memory writes in
+    /// the order they must be undone
+    pub fn undo(&mut self) -> Option<Restore> {
+        self.current = None;
+        self.entries.pop_back().map(Undo::restore)
+    }
+
+    /// How many instructions can currently be stepped back over
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// How many instructions are kept
+    pub fn get_limit(&self) -> usize {
+        self.limit
+    }
+
+    /// Changes how many instructions are kept, forgetting the oldest
+    /// ones if there are too many, 0 turns recording off
+    pub fn set_limit(&mut self, limit: usize) {
+        self.limit = limit;
+        while self.entries.len() > limit {
+            self.entries.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_the_current_instruction_with_recording_off() {
+        let mut history = History::default();
+        history.set_limit(0);
+        history.begin(&[1, 2], 0, 0x10, &[]);
+        history.write(0x100, 7);
+        history.write(0x108, 8);
+        let restore = history.abort().unwrap();
+        assert_eq!(restore.registers, vec![1, 2]);
+        assert_eq!(restore.program_counter, 0x10);
+        assert_eq!(restore.memory, vec![(0x108, 8), (0x100, 7)]);
+        history.begin(&[], 0, 0, &[]);
+        history.end();
+        assert_eq!(history.len(), 0);
+        assert!(history.undo().is_none());
+    }
+
+    #[test]
+    fn forgets_the_oldest_instructions_past_the_limit() {
+        let mut history = History::default();
+        history.set_limit(2);
+        for pc in 0..3 {
+            history.begin(&[], 0, pc, &[]);
+            history.end();
+        }
+        assert_eq!(history.len(), 2);
+        assert_eq!(history.undo().unwrap().program_counter, 2);
+        assert_eq!(history.undo().unwrap().program_counter, 1);
+        assert!(history.undo().is_none());
+    }
+
+    #[test]
+    fn leaves_an_aborted_instruction_out_of_the_log() {
+        let mut history = History::default();
+        history.begin(&[], 0, 0, &[]);
+        history.end();
+        history.begin(&[], 0, 1, &[]);
+        assert_eq!(history.abort().unwrap().program_counter, 1);
+        assert_eq!(history.len(), 1);
+        assert!(history.abort().is_none());
+    }
+}
@@ -1,11 +1,52 @@
 use super::State;
+use lazy_static::lazy_static;
 use num_derive::FromPrimitive;
 use num_traits::FromPrimitive;
+use std::collections::HashMap;
 use std::error::Error;
 
-const CC_ZERO_MASK: u8 = 0x1;
-const CC_SIGN_MASK: u8 = 0x2;
+pub const CC_ZERO_MASK: u8 = 0x1;
+pub const CC_SIGN_MASK: u8 = 0x2;
+pub const CC_OVERFLOW_MASK: u8 = 0x4;
 
+lazy_static! {
+    static ref MNEMONICS: HashMap<u8, &'static str> = vec![
+        ((ICode::IHALT as u8) << 4, "halt"),
+        ((ICode::INOP as u8) << 4, "nop"),
+        ((ICode::IRRMVXX as u8) << 4, "rrmovq"),
+        ((ICode::IRRMVXX as u8) << 4 | 1, "cmovle"),
+        ((ICode::IRRMVXX as u8) << 4 | 2, "cmovl"),
+        ((ICode::IRRMVXX as u8) << 4 | 3, "cmove"),
+        ((ICode::IRRMVXX as u8) << 4 | 4, "cmovne"),
+        ((ICode::IRRMVXX as u8) << 4 | 5, "cmovge"),
+        ((ICode::IRRMVXX as u8) << 4 | 6, "cmovg"),
+        ((ICode::IRMMOVQ as u8) << 4, "rmmovq"),
+        ((ICode::IMRMOVQ as u8) << 4, "mrmovq"),
+        ((ICode::IIRMOVQ as u8) << 4, "irmovq"),
+        ((ICode::IOPQ as u8) << 4, "addq"),
+        ((ICode::IOPQ as u8) << 4 | 1, "subq"),
+        ((ICode::IOPQ as u8) << 4 | 2, "andq"),
+        ((ICode::IOPQ as u8) << 4 | 3, "xorq"),
+        ((ICode::IOPQ as u8) << 4 | 4, "mulq"),
+        ((ICode::IOPQ as u8) << 4 | 5, "divq"),
+        ((ICode::IOPQ as u8) << 4 | 6, "modq"),
+        ((ICode::IJXX as u8) << 4, "jmp"),
+        ((ICode::IJXX as u8) << 4 | 1, "jle"),
+        ((ICode::IJXX as u8) << 4 | 2, "jl"),
+        ((ICode::IJXX as u8) << 4 | 3, "je"),
+        ((ICode::IJXX as u8) << 4 | 4, "jne"),
+        ((ICode::IJXX as u8) << 4 | 5, "jge"),
+        ((ICode::IJXX as u8) << 4 | 6, "jg"),
+        ((ICode::ICALL as u8) << 4, "call"),
+        ((ICode::IRET as u8) << 4, "ret"),
+        ((ICode::IPUSHQ as u8) << 4, "pushq"),
+        ((ICode::IPOPQ as u8) << 4, "popq")
+    ]
+    .into_iter()
+    .collect();
+}
+
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Copy, Clone, FromPrimitive, PartialEq)]
 pub enum ICode {
     IHALT = 0x0,
@@ -24,6 +65,7 @@ pub enum ICode {
     ITOOSHORT = 0x11,
 }
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Copy, Clone, FromPrimitive)]
 pub enum Register {
     RRAX = 0x0,
@@ -44,6 +86,30 @@ pub enum Register {
     RNONE = 0xF,
 }
 
+impl Register {
+    /// The register as written in assembly, such as `%rax`
+    pub fn name(self) -> &'static str {
+        match self {
+            Register::RRAX => "%rax",
+            Register::RRCX => "%rcx",
+            Register::RRDX => "%rdx",
+            Register::RRBX => "%rbx",
+            Register::RRSP => "%rsp",
+            Register::RRBP => "%rbp",
+            Register::RRSI => "%rsi",
+            Register::RRDI => "%rdi",
+            Register::RR8 => "%r8",
+            Register::RR9 => "%r9",
+            Register::RR10 => "%r10",
+            Register::RR11 => "%r11",
+            Register::RR12 => "%r12",
+            Register::RR13 => "%r13",
+            Register::RR14 => "%r14",
+            Register::RNONE => "WAT",
+        }
+    }
+}
+
 pub struct Instruction {
     icode: ICode,
     ifun: u8,
@@ -74,23 +140,45 @@ impl Error for InvalidICode {
     }
 }
 
+/// A divq or modq whose divisor is zero
+/// location: the address of the instruction
+#[derive(Debug, Clone)]
+pub struct DivideByZero {
+    pub location: u64,
+}
+
+impl std::fmt::Display for DivideByZero {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Division by zero at 0x{:x}", self.location)
+    }
+}
+
+impl Error for DivideByZero {}
+
 impl Instruction {
     pub fn new(state: &State) -> Result<Self, Box<dyn Error>> {
-        let icode_ifun = state.read_byte(state.get_pc());
+        state.check_executable(state.get_pc())?;
+        Self::decode(state, state.get_pc())
+    }
+
+    /// Decodes the instruction at address without executing it, so that
+    /// memory can be shown as instructions
+    pub fn decode(state: &State, address: u64) -> Result<Self, Box<dyn Error>> {
+        let icode_ifun = state.peek_byte(address)?;
         let icode = (icode_ifun >> 4) & 0x0F;
         match icode {
-            code if code == ICode::IHALT as u8 => Self::from_halt(state),
-            code if code == ICode::INOP as u8 => Self::from_nop(state),
-            code if code == ICode::IRRMVXX as u8 => Self::from_rrmovxx(state),
-            code if code == ICode::IMRMOVQ as u8 => Self::from_mrmovq(state),
-            code if code == ICode::IRMMOVQ as u8 => Self::from_rmmovq(state),
-            code if code == ICode::IIRMOVQ as u8 => Self::from_irmovq(state),
-            code if code == ICode::IJXX as u8 => Self::from_jmp(state),
-            code if code == ICode::ICALL as u8 => Self::from_call(state),
-            code if code == ICode::IRET as u8 => Self::from_ret(state),
-            code if code == ICode::IPOPQ as u8 => Self::from_pop(state),
-            code if code == ICode::IPUSHQ as u8 => Self::from_push(state),
-            code if code == ICode::IOPQ as u8 => Self::from_opq(state),
+            code if code == ICode::IHALT as u8 => Self::from_halt(state, address),
+            code if code == ICode::INOP as u8 => Self::from_nop(state, address),
+            code if code == ICode::IRRMVXX as u8 => Self::from_rrmovxx(state, address),
+            code if code == ICode::IMRMOVQ as u8 => Self::from_mrmovq(state, address),
+            code if code == ICode::IRMMOVQ as u8 => Self::from_rmmovq(state, address),
+            code if code == ICode::IIRMOVQ as u8 => Self::from_irmovq(state, address),
+            code if code == ICode::IJXX as u8 => Self::from_jmp(state, address),
+            code if code == ICode::ICALL as u8 => Self::from_call(state, address),
+            code if code == ICode::IRET as u8 => Self::from_ret(state, address),
+            code if code == ICode::IPOPQ as u8 => Self::from_pop(state, address),
+            code if code == ICode::IPUSHQ as u8 => Self::from_push(state, address),
+            code if code == ICode::IOPQ as u8 => Self::from_opq(state, address),
             _ => Err(InvalidICode.into()),
         }
     }
@@ -122,6 +210,52 @@ impl Instruction {
         }
     }
 
+    /// The instruction as it would be written in assembly, or "(bad)" for
+    /// a function code that does not exist
+    pub fn text(&self) -> String {
+        let code = self.icode;
+        let ifun = self.ifun;
+        let icode_ifun = (code as u8) << 4 | ifun;
+        let mut curr = match MNEMONICS.get(&icode_ifun) {
+            Some(name) => name.to_string(),
+            None => return "(bad)".to_string(),
+        };
+        match code {
+            ICode::IIRMOVQ => {
+                curr.push_str(&std::format!(
+                    " $0x{:x}, {:}",
+                    self.val_c.unwrap(),
+                    self.r_b.unwrap().name()
+                ));
+            }
+            ICode::IPUSHQ | ICode::IPOPQ => {
+                curr.push_str(&std::format!(" {:}", self.r_a.unwrap().name()))
+            }
+            ICode::IJXX | ICode::ICALL => {
+                curr.push_str(&std::format!(" 0x{:x}", self.val_c.unwrap()))
+            }
+            ICode::IRMMOVQ => curr.push_str(&std::format!(
+                " {:}, 0x{:x}({:})",
+                self.r_a.unwrap().name(),
+                self.val_c.unwrap(),
+                self.r_b.unwrap().name()
+            )),
+            ICode::IMRMOVQ => curr.push_str(&std::format!(
+                " 0x{:x}({:}), {:}",
+                self.val_c.unwrap(),
+                self.r_b.unwrap().name(),
+                self.r_a.unwrap().name()
+            )),
+            ICode::IRRMVXX | ICode::IOPQ => curr.push_str(&std::format!(
+                " {:}, {:}",
+                self.r_a.unwrap().name(),
+                self.r_b.unwrap().name()
+            )),
+            _ => (),
+        }
+        curr
+    }
+
     pub fn get_icode(&self) -> ICode {
         self.icode
     }
@@ -142,36 +276,38 @@ impl Instruction {
         self.r_b
     }
 
-    fn get_icode_ifun(state: &State) -> (u8, u8) {
-        let icode_ifun = state.read_byte(state.get_pc());
+    fn get_icode_ifun(state: &State, address: u64) -> Result<(u8, u8), Box<dyn Error>> {
+        let icode_ifun = state.peek_byte(address)?;
         let icode = icode_ifun >> 4 & 0x0F;
         let ifun = icode_ifun & 0x0F;
-        (icode, ifun)
+        Ok((icode, ifun))
     }
 
-    fn get_registers(state: &State) -> (u8, u8) {
-        let ra_rb = state.read_byte(state.get_pc() + 1);
+    fn get_registers(state: &State, address: u64) -> Result<(u8, u8), Box<dyn Error>> {
+        let ra_rb = state.peek_byte(address + 1)?;
         let ra = ra_rb >> 4 & 0x0F;
         let rb = ra_rb & 0x0F;
-        (ra, rb)
+        Ok((ra, rb))
     }
 
     fn cond(ifun: u8, cond_code: u8) -> bool {
+        let zero = cond_code & CC_ZERO_MASK != 0;
+        let less = (cond_code & CC_SIGN_MASK != 0) != (cond_code & CC_OVERFLOW_MASK != 0);
         match ifun {
             0 => true,
-            1 if (cond_code & CC_ZERO_MASK != 0 || cond_code & CC_SIGN_MASK != 0) => true,
-            2 if (cond_code & CC_SIGN_MASK != 0) => true,
-            3 if (cond_code & CC_ZERO_MASK != 0) => true,
-            4 if (cond_code & CC_ZERO_MASK == 0) => true,
-            5 if (cond_code & CC_SIGN_MASK == 0) => true,
-            6 if (cond_code & CC_SIGN_MASK == 0 && cond_code & CC_ZERO_MASK == 0) => true,
+            1 => less || zero,
+            2 => less,
+            3 => zero,
+            4 => !zero,
+            5 => !less,
+            6 => !less && !zero,
             _ => false,
         }
     }
 
-    pub fn from_halt(state: &State) -> Result<Self, Box<dyn Error>> {
-        let (icode, ifun) = Self::get_icode_ifun(state);
-        let val_p = state.get_pc() + 1;
+    pub fn from_halt(state: &State, address: u64) -> Result<Self, Box<dyn Error>> {
+        let (icode, ifun) = Self::get_icode_ifun(state, address)?;
+        let val_p = address + 1;
         Ok(Instruction {
             icode: FromPrimitive::from_u8(icode).unwrap(),
             ifun,
@@ -179,12 +315,12 @@ impl Instruction {
             r_b: None,
             val_c: None,
             val_p,
-            location: state.get_pc(),
+            location: address,
         })
     }
-    pub fn from_nop(state: &State) -> Result<Self, Box<dyn Error>> {
-        let (icode, ifun) = Self::get_icode_ifun(state);
-        let val_p = state.get_pc() + 1;
+    pub fn from_nop(state: &State, address: u64) -> Result<Self, Box<dyn Error>> {
+        let (icode, ifun) = Self::get_icode_ifun(state, address)?;
+        let val_p = address + 1;
         Ok(Instruction {
             icode: FromPrimitive::from_u8(icode).unwrap(),
             ifun,
@@ -192,13 +328,13 @@ impl Instruction {
             r_b: None,
             val_c: None,
             val_p,
-            location: state.get_pc(),
+            location: address,
         })
     }
-    pub fn from_rrmovxx(state: &State) -> Result<Self, Box<dyn Error>> {
-        let (icode, ifun) = Self::get_icode_ifun(state);
-        let (r_a, r_b) = Self::get_registers(state);
-        let val_p = state.get_pc() + 2;
+    pub fn from_rrmovxx(state: &State, address: u64) -> Result<Self, Box<dyn Error>> {
+        let (icode, ifun) = Self::get_icode_ifun(state, address)?;
+        let (r_a, r_b) = Self::get_registers(state, address)?;
+        let val_p = address + 2;
         Ok(Instruction {
             icode: FromPrimitive::from_u8(icode).unwrap(),
             ifun,
@@ -206,14 +342,14 @@ impl Instruction {
             r_b: Some(FromPrimitive::from_u8(r_b).unwrap()),
             val_c: None,
             val_p,
-            location: state.get_pc(),
+            location: address,
         })
     }
-    pub fn from_rmmovq(state: &State) -> Result<Self, Box<dyn Error>> {
-        let (icode, ifun) = Self::get_icode_ifun(state);
-        let (r_a, r_b) = Self::get_registers(state);
-        let val_c = state.read_le(state.get_pc() + 2)?;
-        let val_p = state.get_pc() + 10;
+    pub fn from_rmmovq(state: &State, address: u64) -> Result<Self, Box<dyn Error>> {
+        let (icode, ifun) = Self::get_icode_ifun(state, address)?;
+        let (r_a, r_b) = Self::get_registers(state, address)?;
+        let val_c = state.peek_le(address + 2)?;
+        let val_p = address + 10;
         Ok(Instruction {
             icode: FromPrimitive::from_u8(icode).unwrap(),
             ifun,
@@ -221,14 +357,14 @@ impl Instruction {
             r_b: Some(FromPrimitive::from_u8(r_b).unwrap()),
             val_c: Some(val_c),
             val_p,
-            location: state.get_pc(),
+            location: address,
         })
     }
-    pub fn from_mrmovq(state: &State) -> Result<Self, Box<dyn Error>> {
-        let (icode, ifun) = Self::get_icode_ifun(state);
-        let (r_a, r_b) = Self::get_registers(state);
-        let val_c = state.read_le(state.get_pc() + 2)?;
-        let val_p = state.get_pc() + 10;
+    pub fn from_mrmovq(state: &State, address: u64) -> Result<Self, Box<dyn Error>> {
+        let (icode, ifun) = Self::get_icode_ifun(state, address)?;
+        let (r_a, r_b) = Self::get_registers(state, address)?;
+        let val_c = state.peek_le(address + 2)?;
+        let val_p = address + 10;
         Ok(Instruction {
             icode: FromPrimitive::from_u8(icode).unwrap(),
             ifun,
@@ -236,16 +372,16 @@ impl Instruction {
             r_b: Some(FromPrimitive::from_u8(r_b).unwrap()),
             val_c: Some(val_c),
             val_p,
-            location: state.get_pc(),
+            location: address,
         })
     }
-    pub fn from_irmovq(state: &State) -> Result<Self, Box<dyn Error>> {
-        let (icode, ifun) = Self::get_icode_ifun(state);
-        let val_c = state.read_le(state.get_pc() + 2)?;
-        let registers = state.read_byte(state.get_pc() + 1);
+    pub fn from_irmovq(state: &State, address: u64) -> Result<Self, Box<dyn Error>> {
+        let (icode, ifun) = Self::get_icode_ifun(state, address)?;
+        let val_c = state.peek_le(address + 2)?;
+        let registers = state.peek_byte(address + 1)?;
         let r_a = registers >> 4 & 0x0F;
         let r_b = registers & 0x0F;
-        let val_p = state.get_pc() + 10;
+        let val_p = address + 10;
         Ok(Instruction {
             icode: FromPrimitive::from_u8(icode).unwrap(),
             ifun,
@@ -253,13 +389,13 @@ impl Instruction {
             r_b: Some(FromPrimitive::from_u8(r_b).unwrap()),
             val_c: Some(val_c),
             val_p,
-            location: state.get_pc(),
+            location: address,
         })
     }
-    pub fn from_jmp(state: &State) -> Result<Self, Box<dyn Error>> {
-        let (icode, ifun) = Self::get_icode_ifun(state);
-        let val_c = state.read_le(state.get_pc() + 1)?;
-        let val_p = state.get_pc() + 9;
+    pub fn from_jmp(state: &State, address: u64) -> Result<Self, Box<dyn Error>> {
+        let (icode, ifun) = Self::get_icode_ifun(state, address)?;
+        let val_c = state.peek_le(address + 1)?;
+        let val_p = address + 9;
         Ok(Instruction {
             icode: FromPrimitive::from_u8(icode).unwrap(),
             ifun,
@@ -267,13 +403,13 @@ impl Instruction {
             r_b: None,
             val_c: Some(val_c),
             val_p,
-            location: state.get_pc(),
+            location: address,
         })
     }
-    pub fn from_call(state: &State) -> Result<Self, Box<dyn Error>> {
-        let (icode, ifun) = Self::get_icode_ifun(state);
-        let val_c = state.read_le(state.get_pc() + 1)?;
-        let val_p = state.get_pc() + 9;
+    pub fn from_call(state: &State, address: u64) -> Result<Self, Box<dyn Error>> {
+        let (icode, ifun) = Self::get_icode_ifun(state, address)?;
+        let val_c = state.peek_le(address + 1)?;
+        let val_p = address + 9;
         Ok(Instruction {
             icode: FromPrimitive::from_u8(icode).unwrap(),
             ifun,
@@ -281,12 +417,12 @@ impl Instruction {
             r_b: None,
             val_c: Some(val_c),
             val_p,
-            location: state.get_pc(),
+            location: address,
         })
     }
-    pub fn from_ret(state: &State) -> Result<Self, Box<dyn Error>> {
-        let (icode, ifun) = Self::get_icode_ifun(state);
-        let val_p = state.get_pc() + 1;
+    pub fn from_ret(state: &State, address: u64) -> Result<Self, Box<dyn Error>> {
+        let (icode, ifun) = Self::get_icode_ifun(state, address)?;
+        let val_p = address + 1;
         Ok(Instruction {
             icode: FromPrimitive::from_u8(icode).unwrap(),
             ifun,
@@ -294,13 +430,13 @@ impl Instruction {
             r_b: None,
             val_c: None,
             val_p,
-            location: state.get_pc(),
+            location: address,
         })
     }
-    pub fn from_pop(state: &State) -> Result<Self, Box<dyn Error>> {
-        let (icode, ifun) = Self::get_icode_ifun(state);
-        let (r_a, r_b) = Self::get_registers(state);
-        let val_p = state.get_pc() + 2;
+    pub fn from_pop(state: &State, address: u64) -> Result<Self, Box<dyn Error>> {
+        let (icode, ifun) = Self::get_icode_ifun(state, address)?;
+        let (r_a, r_b) = Self::get_registers(state, address)?;
+        let val_p = address + 2;
         Ok(Instruction {
             icode: FromPrimitive::from_u8(icode).unwrap(),
             ifun,
@@ -308,13 +444,13 @@ impl Instruction {
             r_b: Some(FromPrimitive::from_u8(r_b).unwrap()),
             val_c: None,
             val_p,
-            location: state.get_pc(),
+            location: address,
         })
     }
-    pub fn from_push(state: &State) -> Result<Self, Box<dyn Error>> {
-        let (icode, ifun) = Self::get_icode_ifun(state);
-        let (r_a, r_b) = Self::get_registers(state);
-        let val_p = state.get_pc() + 2;
+    pub fn from_push(state: &State, address: u64) -> Result<Self, Box<dyn Error>> {
+        let (icode, ifun) = Self::get_icode_ifun(state, address)?;
+        let (r_a, r_b) = Self::get_registers(state, address)?;
+        let val_p = address + 2;
         Ok(Instruction {
             icode: FromPrimitive::from_u8(icode).unwrap(),
             ifun,
@@ -322,13 +458,13 @@ impl Instruction {
             r_b: Some(FromPrimitive::from_u8(r_b).unwrap()),
             val_c: None,
             val_p,
-            location: state.get_pc(),
+            location: address,
         })
     }
-    pub fn from_opq(state: &State) -> Result<Self, Box<dyn Error>> {
-        let (icode, ifun) = Self::get_icode_ifun(state);
-        let (r_a, r_b) = Self::get_registers(state);
-        let val_p = state.get_pc() + 2;
+    pub fn from_opq(state: &State, address: u64) -> Result<Self, Box<dyn Error>> {
+        let (icode, ifun) = Self::get_icode_ifun(state, address)?;
+        let (r_a, r_b) = Self::get_registers(state, address)?;
+        let val_p = address + 2;
         Ok(Instruction {
             icode: FromPrimitive::from_u8(icode).unwrap(),
             ifun,
@@ -336,7 +472,7 @@ impl Instruction {
             r_b: Some(FromPrimitive::from_u8(r_b).unwrap()),
             val_c: None,
             val_p,
-            location: state.get_pc(),
+            location: address,
         })
     }
 
@@ -418,23 +554,30 @@ impl Instruction {
     pub fn execute_opq(&self, state: &mut State) -> Result<(), Box<dyn Error>> {
         let ra_val = state.get_register(self.get_r_a().unwrap() as u8) as i64;
         let rb_val = state.get_register(self.get_r_b().unwrap() as u8) as i64;
-        let res: i64 = match self.ifun {
-            0 => ra_val + rb_val,
-            1 => rb_val - ra_val,
-            2 => rb_val & ra_val,
-            3 => rb_val ^ ra_val,
-            4 => rb_val * ra_val,
-            5 => rb_val / ra_val,
-            6 => rb_val % ra_val,
-            _ => 0,
+        if (self.ifun == 5 || self.ifun == 6) && ra_val == 0 {
+            let location = self.location;
+            return Err(DivideByZero { location }.into());
+        }
+        let (res, overflow): (i64, bool) = match self.ifun {
+            0 => rb_val.overflowing_add(ra_val),
+            1 => rb_val.overflowing_sub(ra_val),
+            2 => (rb_val & ra_val, false),
+            3 => (rb_val ^ ra_val, false),
+            4 => rb_val.overflowing_mul(ra_val),
+            5 => rb_val.overflowing_div(ra_val),
+            6 => rb_val.overflowing_rem(ra_val),
+            _ => (0, false),
         };
+        let mut cond_code = 0;
         if res == 0 {
-            state.set_condition_code(CC_ZERO_MASK);
+            cond_code |= CC_ZERO_MASK;
         } else if res < 0 {
-            state.set_condition_code(CC_SIGN_MASK);
-        } else {
-            state.set_condition_code(0);
+            cond_code |= CC_SIGN_MASK;
+        }
+        if overflow {
+            cond_code |= CC_OVERFLOW_MASK;
         }
+        state.set_condition_code(cond_code);
         state.set_register(self.get_r_b().unwrap() as u8, res as u64);
         state.set_pc(self.get_val_p());
         Ok(())
@@ -446,3 +589,56 @@ impl Instruction {
         unimplemented!("")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn irmovq(register: u8, value: i64) -> Vec<u8> {
+        let mut bytes = vec![0x30, 0xf0 | register];
+        bytes.extend_from_slice(&value.to_le_bytes());
+        bytes
+    }
+
+    /// Compares b with a the way `subq %rax, %rbx` does, then runs
+    /// `cmovl %rdx, %rcx` with %rdx holding 1 and `jl 0x100`
+    fn compare(a: i64, b: i64) -> State {
+        let mut bytes = [irmovq(0, a), irmovq(3, b), irmovq(2, 1)].concat();
+        bytes.extend_from_slice(&[0x61, 0x03, 0x22, 0x21, 0x72, 0, 1, 0, 0, 0, 0, 0, 0]);
+        let mut state = State::from_bytes(bytes);
+        for _ in 0..6 {
+            let instr = Instruction::new(&state).unwrap();
+            instr.execute(&mut state).unwrap();
+        }
+        state
+    }
+
+    #[test]
+    fn compares_signed_values_even_when_subtracting_overflows() {
+        let pairs = [
+            (1, 2),
+            (2, 1),
+            (3, 3),
+            (-1, 1),
+            (1, i64::MIN),
+            (-1, i64::MAX),
+            (i64::MIN, 0),
+            (i64::MAX, i64::MIN),
+            (i64::MIN, i64::MAX),
+        ];
+        for (a, b) in pairs {
+            let state = compare(a, b);
+            let cond_code = state.get_condition_code();
+            let overflow = b.checked_sub(a).is_none();
+            assert_eq!(cond_code & CC_OVERFLOW_MASK != 0, overflow, "{} {}", a, b);
+            // jle, jl, je, jne, jge and jg
+            let expected = [b <= a, b < a, b == a, b != a, b >= a, b > a];
+            for (ifun, expected) in (1..=6).zip(expected) {
+                assert_eq!(Instruction::cond(ifun, cond_code), expected, "{} {}", a, b);
+            }
+            assert_eq!(state.get_register(1), (b < a) as u64, "cmovl {} {}", a, b);
+            let pc = if b < a { 0x100 } else { 0x2b };
+            assert_eq!(state.get_pc(), pc, "jl {} {}", a, b);
+        }
+    }
+}
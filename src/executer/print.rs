@@ -1,128 +1,436 @@
-use super::instructions::{ICode, Instruction, Register};
-use super::State;
-use lazy_static::lazy_static;
+use super::instructions::{Instruction, Register, CC_OVERFLOW_MASK, CC_SIGN_MASK, CC_ZERO_MASK};
+use super::{OutOfBoundsError, State};
+use crate::symbol_table::SymbolKind;
 use num_traits::FromPrimitive;
-use std::collections::HashMap;
-
-lazy_static! {
-    static ref MAP: HashMap<u8, &'static str> = vec![
-        ((ICode::IHALT as u8) << 4, "halt"),
-        ((ICode::INOP as u8) << 4, "nop"),
-        ((ICode::IRRMVXX as u8) << 4 | 0, "rrmovq"),
-        ((ICode::IRRMVXX as u8) << 4 | 1, "cmovle"),
-        ((ICode::IRRMVXX as u8) << 4 | 2, "cmovl"),
-        ((ICode::IRRMVXX as u8) << 4 | 3, "cmove"),
-        ((ICode::IRRMVXX as u8) << 4 | 4, "cmovne"),
-        ((ICode::IRRMVXX as u8) << 4 | 5, "cmovge"),
-        ((ICode::IRRMVXX as u8) << 4 | 6, "cmovg"),
-        ((ICode::IRMMOVQ as u8) << 4, "rmmovq"),
-        ((ICode::IMRMOVQ as u8) << 4, "mrmovq"),
-        ((ICode::IIRMOVQ as u8) << 4, "irmovq"),
-        ((ICode::IOPQ as u8) << 4, "addq"),
-        ((ICode::IOPQ as u8) << 4 | 1, "subq"),
-        ((ICode::IOPQ as u8) << 4 | 2, "andq"),
-        ((ICode::IOPQ as u8) << 4 | 3, "xorq"),
-        ((ICode::IOPQ as u8) << 4 | 4, "mulq"),
-        ((ICode::IOPQ as u8) << 4 | 5, "divq"),
-        ((ICode::IOPQ as u8) << 4 | 6, "modq"),
-        ((ICode::IJXX as u8) << 4, "jmp"),
-        ((ICode::IJXX as u8) << 4 | 1, "jle"),
-        ((ICode::IJXX as u8) << 4 | 2, "jl"),
-        ((ICode::IJXX as u8) << 4 | 3, "je"),
-        ((ICode::IJXX as u8) << 4 | 4, "jne"),
-        ((ICode::IJXX as u8) << 4 | 5, "jge"),
-        ((ICode::IJXX as u8) << 4 | 6, "jg"),
-        ((ICode::ICALL as u8) << 4, "call"),
-        ((ICode::IRET as u8) << 4, "ret"),
-        ((ICode::IPUSHQ as u8) << 4, "pushq"),
-        ((ICode::IPOPQ as u8) << 4, "popq")
+use std::collections::VecDeque;
+use std::error::Error;
+use std::io::IsTerminal;
+
+/// Finds the id of a register by its name, without the leading %
+pub fn register_id(name: &str) -> Option<u8> {
+    (0..15).find(|&id| Register::from_u8(id).unwrap().name()[1..] == *name)
+}
+
+pub fn print_instruction(instr: &Instruction) {
+    println!("    {:}   #PC = 0x{:x}", instr.text(), instr.get_location());
+}
+
+/// Prints every register, the PC, the condition codes and the status
+/// signed: shows registers in signed decimal instead of hex
+/// Values that changed since the previous stop are marked with a `*`,
+/// highlighted as well on a terminal
+pub fn print_all_registers(state: &State, signed: bool) {
+    let highlight = std::io::stdout().is_terminal();
+    let mark = |changed: bool, text: String| match (changed, highlight) {
+        (false, _) => text,
+        (true, false) => std::format!("{:} *", text),
+        (true, true) => std::format!("\x1b[1;31m{:} *\x1b[0m", text),
+    };
+    for id in 0..15 {
+        let value = state.get_register(id);
+        let text = if signed {
+            std::format!("{:>20}", value as i64)
+        } else {
+            std::format!("0x{:016x}", value)
+        };
+        let changed = value != state.get_previous_registers()[id as usize];
+        println!(
+            "       {:<6}{:}",
+            Register::from_u8(id).unwrap().name(),
+            mark(changed, text)
+        );
+    }
+    println!(
+        "       {:<6}{:}",
+        "%pc",
+        address_label(state, state.get_pc())
+    );
+    let cc = state.get_condition_code();
+    let previous = state.get_previous_condition_code();
+    let flags: Vec<String> = [
+        ("ZF", CC_ZERO_MASK),
+        ("SF", CC_SIGN_MASK),
+        ("OF", CC_OVERFLOW_MASK),
     ]
-    .into_iter()
+    .iter()
+    .map(|&(name, mask)| {
+        let text = std::format!("{:}={:}", name, (cc & mask != 0) as u8);
+        mark((cc ^ previous) & mask != 0, text)
+    })
     .collect();
+    println!("       {:<6}{:}", "flags", flags.join(" "));
+    println!("       {:<6}{:}", "stat", state.status());
+}
+
+pub fn print_memory_quad_value(state: &State, address: u64) {
+    let section = match state.section_at(address) {
+        Some(section) => std::format!("   ({:})", section.name),
+        None => String::new(),
+    };
+    println!(
+        "      #M_8[0x{:x}]  = 0x{:x}{:}",
+        address,
+        state.peek_le(address).unwrap(),
+        section
+    );
 }
 
-pub fn print_register(register: Register) -> &'static str {
-    match register {
-        Register::RRAX => "%rax",
-        Register::RRCX => "%rcx",
-        Register::RRDX => "%rdx",
-        Register::RRBX => "%rbx",
-        Register::RRSP => "%rsp",
-        Register::RRBP => "%rbp",
-        Register::RRSI => "%rsi",
-        Register::RRDI => "%rdi",
-        Register::RR8 => "%r8",
-        Register::RR9 => "%r9",
-        Register::RR10 => "%r10",
-        Register::RR11 => "%r11",
-        Register::RR12 => "%r12",
-        Register::RR13 => "%r13",
-        Register::RR14 => "%r14",
-        Register::RNONE => "WAT",
+/// How examine shows each unit of memory
+#[derive(Clone, Copy, PartialEq)]
+pub enum Format {
+    Hex,
+    Decimal,
+    Signed,
+    Char,
+    Instruction,
+}
+
+/// An address along with the label it falls under, if any
+pub fn address_label(state: &State, address: u64) -> String {
+    match state.describe_address(address) {
+        Some(name) => std::format!("0x{:x} <{}>", address, name),
+        None => std::format!("0x{:x}", address),
     }
 }
 
-pub fn print_instruction(instr: &Instruction) {
-    let code = instr.get_icode();
-    let ifun = instr.get_ifun();
-    let icode_ifun = (code as u8) << 4 | ifun;
-    let mut curr = std::format!("    {:}", MAP.get(&icode_ifun).unwrap()); // Remove unwrap
-    match code {
-        ICode::IIRMOVQ => {
-            curr.push_str(&std::format!(
-                " $0x{:x}, {:}",
-                instr.get_val_c().unwrap(),
-                print_register(instr.get_r_b().unwrap())
-            ));
-        }
-        ICode::IPUSHQ | ICode::IPOPQ => curr.push_str(&std::format!(
-            " {:}",
-            print_register(instr.get_r_a().unwrap())
-        )),
-        ICode::IJXX | ICode::ICALL => {
-            curr.push_str(&std::format!(" 0x{:x}", instr.get_val_c().unwrap()))
-        }
-        ICode::IRMMOVQ => curr.push_str(&std::format!(
-            " {:}, 0x{:x}({:})",
-            print_register(instr.get_r_a().unwrap()),
-            instr.get_val_c().unwrap(),
-            print_register(instr.get_r_b().unwrap())
-        )),
-        ICode::IMRMOVQ => curr.push_str(&std::format!(
-            " 0x{:x}({:}), {:}",
-            instr.get_val_c().unwrap(),
-            print_register(instr.get_r_b().unwrap()),
-            print_register(instr.get_r_a().unwrap())
-        )),
-        ICode::IRRMVXX | ICode::IOPQ => curr.push_str(&std::format!(
-            " {:}, {:}",
-            print_register(instr.get_r_a().unwrap()),
-            print_register(instr.get_r_b().unwrap())
-        )),
-        _ => (),
-    }
-    curr.push_str(&std::format!("   #PC = 0x{:x}", instr.get_location()));
-    println!("{:}", curr);
-}
-
-pub fn print_all_registers(state: &State) {
-    (0..14)
-        .into_iter()
-        .for_each(|id| print_register_val(state, id));
+/// Reads size bytes at address as a little-endian value
+fn peek_unit(state: &State, address: u64, size: u64) -> Result<u64, Box<dyn Error>> {
+    let mut res = 0;
+    for i in 0..size {
+        let byte = address.checked_add(i).ok_or(OutOfBoundsError(address))?;
+        res |= (state.peek_byte(byte)? as u64) << (8 * i);
+    }
+    Ok(res)
 }
 
-pub fn print_memory_quad_value(state: &State, address: u64) {
+fn char_text(byte: u8) -> String {
+    match byte {
+        b'\'' => "'\\''".to_string(),
+        b'\\' => "'\\\\'".to_string(),
+        0x20..=0x7e => std::format!("'{:}'", byte as char),
+        _ => std::format!("'\\x{:02x}'", byte),
+    }
+}
+
+fn unit_text(value: u64, size: u64, format: Format) -> String {
+    let bits = size * 8;
+    match format {
+        Format::Decimal => std::format!("{:}", value),
+        Format::Signed => std::format!("{:}", ((value << (64 - bits)) as i64) >> (64 - bits)),
+        Format::Char => std::format!("{:} {:}", value, char_text(value as u8)),
+        _ => std::format!("0x{:0width$x}", value, width = size as usize * 2),
+    }
+}
+
+/// Decodes the instruction at address as text, along with its length,
+/// showing a byte that starts no instruction as `.byte`
+fn decode_text(state: &State, address: u64) -> Result<(String, u64), Box<dyn Error>> {
+    let byte = state.peek_byte(address)?;
+    Ok(match Instruction::decode(state, address) {
+        Ok(instr) => (instr.text(), instr.get_val_p() - address),
+        Err(_) => (std::format!(".byte 0x{:02x}", byte), 1),
+    })
+}
+
+/// Prints the instruction at address, marked with `b` when a breakpoint
+/// is set on it and `=>` when it is the next to execute
+/// Returns the length of the instruction
+fn print_decoded(state: &State, address: u64) -> Result<u64, Box<dyn Error>> {
+    let (text, len) = decode_text(state, address)?;
     println!(
-        "      #M_8[0x{:x}]  = 0x{:x}",
-        address,
-        state.read_le(address).unwrap()
+        "    {:}{:} {:}:\t{:}",
+        if state.get_breakpoints().is_set(address) {
+            "b"
+        } else {
+            " "
+        },
+        if address == state.get_pc() {
+            "=>"
+        } else {
+            "  "
+        },
+        address_label(state, address),
+        text
     );
+    Ok(len)
 }
 
-pub fn print_register_val(state: &State, val: u8) {
+/// Decodes count instructions around address, about half of them
+/// before, returning the address and text of each
+/// Instructions vary in length, so the ones before address are found by
+/// decoding forward from the start of its section, realigning on code
+/// labels, and starting at address itself if that does not lead to it
+pub fn disassemble(
+    state: &State,
+    address: u64,
+    count: u64,
+) -> Result<Vec<(u64, String)>, Box<dyn Error>> {
+    state.peek_byte(address)?;
+    let before = (count / 2) as usize;
+    let labels: Vec<u64> = state
+        .get_symbols()
+        .iter()
+        .filter(|symbol| symbol.kind == SymbolKind::Code)
+        .map(|symbol| symbol.address)
+        .collect();
+    let mut starts = VecDeque::new();
+    let mut current = state.section_at(address).map_or(0, |section| section.start);
+    while current < address {
+        starts.push_back(current);
+        if starts.len() > before {
+            starts.pop_front();
+        }
+        let next = current + decode_text(state, current).map_or(1, |(_, len)| len);
+        current = labels
+            .iter()
+            .copied()
+            .filter(|&label| label > current && label < next)
+            .min()
+            .unwrap_or(next);
+    }
+    let mut current = match starts.front() {
+        Some(&first) if current == address => first,
+        _ => address,
+    };
+    let mut res = vec![];
+    for _ in 0..count {
+        if state.peek_byte(current).is_err() {
+            break;
+        }
+        let (text, len) = decode_text(state, current)?;
+        res.push((current, text));
+        current += len;
+    }
+    Ok(res)
+}
+
+/// Prints count instructions around address, about half of them before
+pub fn print_disassembly(state: &State, address: u64, count: u64) -> Result<(), Box<dyn Error>> {
+    for (address, _) in disassemble(state, address, count)? {
+        print_decoded(state, address)?;
+    }
+    Ok(())
+}
+
+/// Prints count units of memory starting at address
+/// size: the size of a unit in bytes, unused for instructions
+/// format: how to show each unit
+pub fn print_memory(
+    state: &State,
+    address: u64,
+    count: u64,
+    size: u64,
+    format: Format,
+) -> Result<(), Box<dyn Error>> {
+    if format == Format::Instruction {
+        let mut address = address;
+        for _ in 0..count {
+            let len = print_decoded(state, address)?;
+            address = address.checked_add(len).ok_or(OutOfBoundsError(address))?;
+        }
+        return Ok(());
+    }
+    let per_line = match size {
+        8 => 2,
+        4 => 4,
+        _ => 8,
+    };
+    let mut line = String::new();
+    for i in 0..count {
+        let unit = match i
+            .checked_mul(size)
+            .and_then(|offset| address.checked_add(offset))
+        {
+            Some(unit) => unit,
+            None => {
+                println!("{:}", line);
+                return Err(OutOfBoundsError(address).into());
+            }
+        };
+        if i % per_line == 0 {
+            if !line.is_empty() {
+                println!("{:}", line);
+            }
+            line = std::format!("      {:}:", address_label(state, unit));
+        }
+        let value = match peek_unit(state, unit, size) {
+            Ok(value) => value,
+            Err(e) => {
+                println!("{:}", line);
+                return Err(e);
+            }
+        };
+        line.push('\t');
+        line.push_str(&unit_text(value, size, format));
+    }
+    if !line.is_empty() {
+        println!("{:}", line);
+    }
+    Ok(())
+}
+
+/// Shows len bytes starting at address as hex, 16 to a line, followed
+/// by the printable ones as ASCII
+pub fn hexdump(state: &State, address: u64, len: u64) -> Result<Vec<String>, Box<dyn Error>> {
+    state.peek_byte(address)?;
+    let bytes: Vec<u8> = (0..len)
+        .map_while(|i| state.peek_byte(address.checked_add(i)?).ok())
+        .collect();
+    let mut res = vec![];
+    for (row, chunk) in bytes.chunks(16).enumerate() {
+        let mut hex = String::new();
+        for (i, byte) in chunk.iter().enumerate() {
+            if i == 8 {
+                hex.push(' ');
+            }
+            hex.push_str(&std::format!("{:02x} ", byte));
+        }
+        let ascii: String = chunk
+            .iter()
+            .map(|&byte| match byte {
+                0x20..=0x7e => byte as char,
+                _ => '.',
+            })
+            .collect();
+        res.push(std::format!(
+            "0x{:08x}  {:<49} |{:}|",
+            address + row as u64 * 16,
+            hex,
+            ascii
+        ));
+    }
+    Ok(res)
+}
+
+pub fn print_hexdump(state: &State, address: u64, len: u64) -> Result<(), Box<dyn Error>> {
+    for line in hexdump(state, address, len)? {
+        println!("      {:}", line);
+    }
+    Ok(())
+}
+
+pub fn print_sections(state: &State) {
+    if state.get_sections().is_empty() {
+        println!("      No section map loaded");
+    }
+    for section in state.get_sections() {
+        // A section map can hold empty sections, which have no last byte
+        let range = match section.size {
+            0 => format!("0x{:x} (empty)", section.start),
+            size => format!(
+                "0x{:x}-0x{:x}",
+                section.start,
+                section.start.saturating_add(size - 1)
+            ),
+        };
+        println!("      {:<10} {}  {:}", section.name, range, section.flags());
+    }
+}
+
+pub fn print_symbols(state: &State) {
+    if state.get_symbols().is_empty() {
+        println!("      No symbol table loaded");
+    }
+    for symbol in state.get_symbols() {
+        println!(
+            "      0x{:<8x} {:<9} {:<16} {:}",
+            symbol.address,
+            symbol.kind.name(),
+            symbol.name,
+            symbol.location()
+        );
+    }
+}
+
+/// Prints the source around the line that emitted address, if known
+/// context: how many lines to show on either side
+/// around: a line of the same file to center on instead
+/// Returns whether any source was known for address
+pub fn print_source(state: &State, address: u64, context: usize, around: Option<usize>) -> bool {
+    let current = match state.line_at(address) {
+        Some(line) => line,
+        None => return false,
+    };
+    let center = around.unwrap_or(current.line);
+    let first = center.saturating_sub(context).max(1);
+    println!("    {:}:{:}", current.file, center);
+    for number in first..=center.saturating_add(context) {
+        if let Some(text) = state.source_line(&current.file, number) {
+            let marker = if number == current.line { "=>" } else { "  " };
+            println!("    {:} {:<5} {:}", marker, number, text);
+        }
+    }
+    true
+}
+
+pub fn print_backtrace(state: &State) {
+    let describe = |address: u64| match state.describe_address(address) {
+        Some(name) => std::format!(" in {:}", name),
+        None => String::new(),
+    };
     println!(
-        "       #R[{:}] = 0x{:x}",
-        print_register(FromPrimitive::from_u8(val).unwrap()),
-        state.get_register(val)
+        "    #0  0x{:x}{:}",
+        state.get_pc(),
+        describe(state.get_pc())
     );
+    for (depth, frame) in state.get_frames().iter().rev().enumerate() {
+        let mut line = std::format!(
+            "    #{:<2} 0x{:x}{:}, called at 0x{:x}",
+            depth + 1,
+            frame.return_address,
+            describe(frame.return_address),
+            frame.call_site
+        );
+        match state.peek_le(frame.slot) {
+            Ok(stored) if stored == frame.return_address => (),
+            Ok(stored) => line.push_str(&std::format!(
+                "  <- corrupted: M_8[0x{:x}] = 0x{:x}",
+                frame.slot,
+                stored
+            )),
+            Err(e) => line.push_str(&std::format!("  <- {:}", e)),
+        }
+        println!("{:}", line);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::line_table::LineInfo;
+
+    #[test]
+    fn reads_units_little_endian() {
+        let state = State::from_bytes(vec![0x01, 0x02, 0x03, 0x04]);
+        assert_eq!(peek_unit(&state, 0, 4).unwrap(), 0x04030201);
+        assert_eq!(peek_unit(&state, 2, 2).unwrap(), 0x0403);
+        assert!(peek_unit(&state, 2, 4).is_err());
+    }
+
+    #[test]
+    fn reports_memory_past_the_end_of_the_address_space() {
+        let state = State::from_bytes(vec![0; 0x10]);
+        let e = peek_unit(&state, 0xfffffffffffffffa, 8).err().unwrap();
+        assert_eq!(e.to_string(), "Address 0xfffffffffffffffa is out of bounds");
+        for format in [Format::Hex, Format::Instruction] {
+            assert!(print_memory(&state, 0xfffffffffffffffa, 2, 8, format).is_err());
+        }
+        assert!(print_memory(&state, 0x8, u64::MAX, 8, Format::Hex).is_err());
+        assert_eq!(hexdump(&state, 0x8, u64::MAX).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn lists_source_around_any_line_number() {
+        let mut state = State::from_bytes(vec![0x10, 0x00]);
+        state.set_lines(vec![LineInfo {
+            address: 0,
+            size: 1,
+            file: "missing.ys".to_string(),
+            line: 1,
+            text: "nop".to_string(),
+        }]);
+        assert!(print_source(&state, 0, 3, None));
+        assert!(print_source(&state, 0, 3, Some(usize::MAX)));
+        assert!(!print_source(&state, 1, 3, None));
+    }
 }
@@ -0,0 +1,330 @@
+use super::instructions::{Instruction, Register, CC_OVERFLOW_MASK, CC_SIGN_MASK, CC_ZERO_MASK};
+use num_traits::FromPrimitive;
+use serde_json::json;
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+const CSV_HEADER: &str = "step,pc,instruction,registers,memory,zf,sf,of,stat";
+
+/// How a trace writes each instruction
+/// JsonLines: one JSON object per line
+/// Csv: one row per instruction after a header, with the register and
+/// memory writes of a row separated by `;`
+#[derive(Clone, Copy, PartialEq)]
+pub enum TraceFormat {
+    JsonLines,
+    Csv,
+}
+
+impl TraceFormat {
+    /// The format a trace file is written in, going by its extension:
+    /// CSV for `.csv`, JSON Lines otherwise
+    pub fn from_file_name(file_name: &str) -> Self {
+        match Path::new(file_name).extension() {
+            Some(extension) if extension == "csv" => TraceFormat::Csv,
+            _ => TraceFormat::JsonLines,
+        }
+    }
+}
+
+/// Where the executer writes every instruction it executes, along with
+/// what the instruction changed
+/// range: only instructions at an address in start..end are written,
+/// every instruction still counts as a step
+/// registers, memory: the writes of the instruction being executed, in
+/// the order they happened
+/// recording: whether an instruction is being executed, so that writes
+/// by the debugger itself are left out
+pub struct Trace {
+    output: Box<dyn Write>,
+    format: TraceFormat,
+    range: Option<(u64, u64)>,
+    registers: Vec<(u8, u64)>,
+    memory: Vec<(u64, u64)>,
+    recording: bool,
+    steps: u64,
+}
+
+/// Quotes a CSV field if it needs it
+fn csv_field(text: &str) -> String {
+    if text.contains([',', '"', '\n']) {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text.to_string()
+    }
+}
+
+fn register_name(id: u8) -> &'static str {
+    Register::from_u8(id).map_or("?", |register| register.name())
+}
+
+impl Trace {
+    /// output: where the trace goes
+    /// format: how each instruction is written
+    /// range: the addresses of the instructions to write, all if None
+    pub fn new(
+        mut output: Box<dyn Write>,
+        format: TraceFormat,
+        range: Option<(u64, u64)>,
+    ) -> Result<Self, Box<dyn Error>> {
+        if format == TraceFormat::Csv {
+            writeln!(output, "{}", CSV_HEADER)?;
+        }
+        Ok(Trace {
+            output,
+            format,
+            range,
+            registers: vec![],
+            memory: vec![],
+            recording: false,
+            steps: 0,
+        })
+    }
+
+    /// Creates a trace writing to file_name, in the format its extension
+    /// calls for
+    pub fn create(file_name: &str, range: Option<(u64, u64)>) -> Result<Self, Box<dyn Error>> {
+        let file = BufWriter::new(File::create(file_name)?);
+        Self::new(
+            Box::new(file),
+            TraceFormat::from_file_name(file_name),
+            range,
+        )
+    }
+
+    /// Starts recording the writes of an instruction
+    pub fn begin(&mut self) {
+        self.registers.clear();
+        self.memory.clear();
+        self.recording = true;
+    }
+
+    /// Records a register written by the current instruction
+    pub fn register(&mut self, id: u8, value: u64) {
+        if self.recording {
+            self.registers.push((id, value));
+        }
+    }
+
+    /// Records a quad written by the current instruction
+    pub fn memory(&mut self, address: u64, value: u64) {
+        if self.recording {
+            self.memory.push((address, value));
+        }
+    }
+
+    /// Stops recording, dropping the writes of an instruction that
+    /// failed and was undone
+    pub fn abort(&mut self) {
+        self.recording = false;
+    }
+
+    /// Writes the instruction just executed, unless it is out of range
+    /// condition_code: the condition codes after it
+    /// status: the status of the program after it
+    pub fn step(
+        &mut self,
+        instr: &Instruction,
+        condition_code: u8,
+        status: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        self.recording = false;
+        self.steps += 1;
+        let location = instr.get_location();
+        if let Some((start, end)) = self.range {
+            if location < start || location >= end {
+                return Ok(());
+            }
+        }
+        let flag = |mask: u8| (condition_code & mask != 0) as u8;
+        let (zf, sf, of) = (
+            flag(CC_ZERO_MASK),
+            flag(CC_SIGN_MASK),
+            flag(CC_OVERFLOW_MASK),
+        );
+        match self.format {
+            TraceFormat::JsonLines => {
+                let registers: Vec<_> = self
+                    .registers
+                    .iter()
+                    .map(|&(id, value)| {
+                        json!({ "register": register_name(id), "value": format!("0x{:x}", value) })
+                    })
+                    .collect();
+                let memory: Vec<_> = self
+                    .memory
+                    .iter()
+                    .map(|&(address, value)| {
+                        json!({
+                            "address": format!("0x{:x}", address),
+                            "value": format!("0x{:x}", value),
+                        })
+                    })
+                    .collect();
+                let line = json!({
+                    "step": self.steps,
+                    "pc": format!("0x{:x}", location),
+                    "instruction": instr.text(),
+                    "registers": registers,
+                    "memory": memory,
+                    "cc": { "ZF": zf, "SF": sf, "OF": of },
+                    "stat": status,
+                });
+                writeln!(self.output, "{}", line)?;
+            }
+            TraceFormat::Csv => {
+                let registers: Vec<String> = self
+                    .registers
+                    .iter()
+                    .map(|&(id, value)| format!("{}=0x{:x}", register_name(id), value))
+                    .collect();
+                let memory: Vec<String> = self
+                    .memory
+                    .iter()
+                    .map(|&(address, value)| format!("0x{:x}=0x{:x}", address, value))
+                    .collect();
+                writeln!(
+                    self.output,
+                    "{},0x{:x},{},{},{},{},{},{},{}",
+                    self.steps,
+                    location,
+                    csv_field(&instr.text()),
+                    registers.join(";"),
+                    memory.join(";"),
+                    zf,
+                    sf,
+                    of,
+                    status
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes out whatever is still buffered
+    pub fn flush(&mut self) -> Result<(), Box<dyn Error>> {
+        self.output.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{State, StopReason};
+    use super::*;
+    use std::cell::RefCell;
+    use std::io;
+    use std::rc::Rc;
+
+    /// A trace output that can still be read once the trace owns it
+    #[derive(Clone, Default)]
+    struct Output(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Output {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// irmovq $0x5, %rax; rmmovq %rax, 0x40(%rcx); subq %rax, %rax; halt
+    fn program() -> State {
+        let mut bytes = vec![0; 0x48];
+        bytes[..10].copy_from_slice(&[0x30, 0xf0, 5, 0, 0, 0, 0, 0, 0, 0]);
+        bytes[10..20].copy_from_slice(&[0x40, 0x01, 0x40, 0, 0, 0, 0, 0, 0, 0]);
+        bytes[20..22].copy_from_slice(&[0x61, 0x00]);
+        State::from_bytes(bytes)
+    }
+
+    /// Runs the program to its halt with a trace in format, returning what
+    /// was written
+    fn trace(format: TraceFormat, range: Option<(u64, u64)>) -> String {
+        let output = Output::default();
+        let mut state = program();
+        let trace = Trace::new(Box::new(output.clone()), format, range).unwrap();
+        state.set_trace(Some(trace)).unwrap();
+        assert!(matches!(state.run_until(|_, _| false), StopReason::Halted));
+        state.set_trace(None).unwrap();
+        let text = String::from_utf8(output.0.borrow().clone()).unwrap();
+        text
+    }
+
+    #[test]
+    fn writes_one_json_object_per_instruction() {
+        let text = trace(TraceFormat::JsonLines, None);
+        let lines: Vec<serde_json::Value> = text
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0]["step"], 1);
+        assert_eq!(lines[0]["pc"], "0x0");
+        assert_eq!(lines[0]["instruction"], "irmovq $0x5, %rax");
+        assert_eq!(
+            lines[0]["registers"],
+            json!([{ "register": "%rax", "value": "0x5" }])
+        );
+        assert_eq!(
+            lines[1]["memory"],
+            json!([{ "address": "0x40", "value": "0x5" }])
+        );
+        assert_eq!(lines[1]["registers"], json!([]));
+        assert_eq!(lines[2]["cc"], json!({ "ZF": 1, "SF": 0, "OF": 0 }));
+        assert_eq!(lines[1]["stat"], "AOK");
+        // The status is the one the program is left in
+        assert_eq!(lines[2]["stat"], "HLT");
+    }
+
+    #[test]
+    fn writes_one_csv_row_per_instruction() {
+        let text = trace(TraceFormat::Csv, None);
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(
+            lines,
+            vec![
+                CSV_HEADER,
+                "1,0x0,\"irmovq $0x5, %rax\",%rax=0x5,,0,0,0,AOK",
+                "2,0xa,\"rmmovq %rax, 0x40(%rcx)\",,0x40=0x5,0,0,0,AOK",
+                "3,0x14,\"subq %rax, %rax\",%rax=0x0,,1,0,0,HLT",
+            ]
+        );
+    }
+
+    #[test]
+    fn counts_steps_outside_of_its_range() {
+        let text = trace(TraceFormat::Csv, Some((0xa, 0x14)));
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[1].starts_with("2,0xa,"));
+    }
+
+    /// Fails every write, like a full disk
+    struct Failing;
+
+    impl Write for Failing {
+        fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+            Err(io::Error::other("disk full"))
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn stops_tracing_without_stopping_the_program() {
+        let mut state = program();
+        let trace = Trace::new(Box::new(Failing), TraceFormat::JsonLines, None).unwrap();
+        state.set_trace(Some(trace)).unwrap();
+        assert!(matches!(state.run_until(|_, _| false), StopReason::Halted));
+        assert_eq!(state.get_register(Register::RRAX as u8), 0);
+        let e = state.take_trace_error().unwrap();
+        assert_eq!(e.to_string(), "Stopped tracing: disk full");
+        assert!(state.take_trace_error().is_none());
+    }
+}
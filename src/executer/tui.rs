@@ -0,0 +1,543 @@
+use super::instructions::{Instruction, Register, CC_OVERFLOW_MASK, CC_SIGN_MASK, CC_ZERO_MASK};
+use super::print::{address_label, disassemble, hexdump};
+use super::{State, StopReason};
+use crossterm::cursor::{Hide, MoveTo, Show};
+use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use crossterm::style::{Attribute, Color, Print, SetAttribute, SetForegroundColor};
+use crossterm::terminal::{self, Clear, ClearType, EnterAlternateScreen, LeaveAlternateScreen};
+use crossterm::{execute, queue};
+use num_traits::FromPrimitive;
+use std::error::Error;
+use std::io::{self, stdout, Write};
+
+// The registers pane: 15 registers two to a row, %pc, the flags and
+// the status, within a border
+const REGISTERS_HEIGHT: u16 = 12;
+
+// The memory pane: 4 rows of 16 bytes, within a border
+const MEMORY_HEIGHT: u16 = 6;
+const MEMORY_ROWS: u64 = 4;
+
+const KEYS: &str = " s step  n next  c continue  b break  up/down select  pgup/pgdn memory  q quit";
+
+/// How a piece of text in a pane is shown
+#[derive(Clone, Copy, PartialEq)]
+enum Look {
+    Plain,
+    Changed,
+    Current,
+    Breakpoint,
+}
+
+/// A line of a pane, made of pieces that are shown differently
+type Line = Vec<(String, Look)>;
+
+fn plain(text: String) -> Line {
+    vec![(text, Look::Plain)]
+}
+
+/// A rectangle of the screen, border included
+struct Area {
+    x: u16,
+    y: u16,
+    width: u16,
+    height: u16,
+}
+
+impl Area {
+    /// How many lines fit inside the border
+    fn rows(&self) -> u64 {
+        self.height.saturating_sub(2) as u64
+    }
+}
+
+fn set_look<W: Write>(out: &mut W, look: Look) -> io::Result<()> {
+    match look {
+        Look::Plain => queue!(out, SetAttribute(Attribute::Reset)),
+        Look::Changed => queue!(
+            out,
+            SetForegroundColor(Color::Red),
+            SetAttribute(Attribute::Bold)
+        ),
+        Look::Current => queue!(out, SetAttribute(Attribute::Reverse)),
+        Look::Breakpoint => queue!(out, SetForegroundColor(Color::Red)),
+    }
+}
+
+/// Writes pieces at the cursor, cut or padded to exactly width columns
+fn draw_line<W: Write>(out: &mut W, line: &[(String, Look)], width: usize) -> io::Result<()> {
+    let mut left = width;
+    for (text, look) in line {
+        let text: String = text.chars().take(left).collect();
+        left -= text.chars().count();
+        set_look(out, *look)?;
+        queue!(out, Print(text))?;
+    }
+    // The current line is highlighted all the way across
+    if line.last().map(|(_, look)| *look) != Some(Look::Current) {
+        set_look(out, Look::Plain)?;
+    }
+    queue!(out, Print(" ".repeat(left)))?;
+    set_look(out, Look::Plain)
+}
+
+/// Draws a border around area with title on it, and lines inside
+fn draw_pane<W: Write>(out: &mut W, area: &Area, title: &str, lines: &[Line]) -> io::Result<()> {
+    if area.width < 2 || area.height < 2 {
+        return Ok(());
+    }
+    let inner = area.width as usize - 2;
+    let title: String = format!(" {} ", title).chars().take(inner).collect();
+    let top = format!("┌{}{}┐", title, "─".repeat(inner - title.chars().count()));
+    queue!(out, MoveTo(area.x, area.y), Print(top))?;
+    for row in 0..area.rows() as u16 {
+        queue!(out, MoveTo(area.x, area.y + 1 + row), Print("│"))?;
+        let line = lines.get(row as usize).map_or(&[][..], |line| &line[..]);
+        draw_line(out, line, inner)?;
+        queue!(out, Print("│"))?;
+    }
+    let bottom = format!("└{}┘", "─".repeat(inner));
+    queue!(out, MoveTo(area.x, area.y + area.height - 1), Print(bottom))
+}
+
+/// Everything shown besides the state of the program
+/// cursor: the instruction selected in the disassembly, which the
+/// source follows and breakpoints are toggled on
+/// memory: the first address shown in the memory pane
+/// message: what the last key did
+struct Screen<'a> {
+    state: &'a mut State,
+    file_name: &'a str,
+    cursor: u64,
+    memory: u64,
+    message: String,
+}
+
+impl<'a> Screen<'a> {
+    fn disassembly(&self, rows: u64) -> Vec<Line> {
+        let instructions = match disassemble(self.state, self.cursor, rows) {
+            Ok(instructions) => instructions,
+            Err(e) => return vec![plain(e.to_string())],
+        };
+        let pc = self.state.get_pc();
+        instructions
+            .into_iter()
+            .map(|(address, text)| {
+                let mark = if self.state.get_breakpoints().is_set(address) {
+                    ("b".to_string(), Look::Breakpoint)
+                } else {
+                    (" ".to_string(), Look::Plain)
+                };
+                let text = format!("{}: {}", address_label(self.state, address), text);
+                if address == pc {
+                    vec![mark, (format!("=> {}", text), Look::Current)]
+                } else if address == self.cursor {
+                    vec![mark, (format!(" > {}", text), Look::Plain)]
+                } else {
+                    vec![mark, (format!("   {}", text), Look::Plain)]
+                }
+            })
+            .collect()
+    }
+
+    fn source(&self, rows: u64) -> Vec<Line> {
+        let selected = match self.state.line_at(self.cursor) {
+            Some(line) => line,
+            None => return vec![plain(format!("No source for 0x{:x}", self.cursor))],
+        };
+        let current = self
+            .state
+            .line_at(self.state.get_pc())
+            .filter(|line| line.file == selected.file)
+            .map(|line| line.line);
+        let first = selected.line.saturating_sub(rows as usize / 2).max(1);
+        let mut res = vec![];
+        for number in first..first + rows as usize {
+            let text = match self.state.source_line(&selected.file, number) {
+                Some(text) => text.replace('\t', "    "),
+                None => break,
+            };
+            let line = if Some(number) == current {
+                vec![(format!("=> {:<5} {}", number, text), Look::Current)]
+            } else if number == selected.line {
+                plain(format!(" > {:<5} {}", number, text))
+            } else {
+                plain(format!("   {:<5} {}", number, text))
+            };
+            res.push(line);
+        }
+        res
+    }
+
+    fn registers(&self) -> Vec<Line> {
+        let state = &self.state;
+        let look = |changed: bool| if changed { Look::Changed } else { Look::Plain };
+        let mut res = vec![];
+        for row in 0..8 {
+            let mut line = vec![];
+            for id in [row, row + 8].iter().copied().filter(|&id| id < 15) {
+                let value = state.get_register(id);
+                let changed = value != state.get_previous_registers()[id as usize];
+                let name = Register::from_u8(id).unwrap().name();
+                line.push((format!("{:<5}", name), Look::Plain));
+                line.push((format!("0x{:016x}", value), look(changed)));
+                line.push(("  ".to_string(), Look::Plain));
+            }
+            res.push(line);
+        }
+        res.push(plain(format!(
+            "{:<5}{}",
+            "%pc",
+            address_label(state, state.get_pc())
+        )));
+        let cc = state.get_condition_code();
+        let changed = cc ^ state.get_previous_condition_code();
+        let mut flags = vec![];
+        for &(name, mask) in [
+            ("ZF", CC_ZERO_MASK),
+            ("SF", CC_SIGN_MASK),
+            ("OF", CC_OVERFLOW_MASK),
+        ]
+        .iter()
+        {
+            let text = format!("{}={}", name, (cc & mask != 0) as u8);
+            flags.push((text, look(changed & mask != 0)));
+            flags.push((" ".to_string(), Look::Plain));
+        }
+        flags.push((format!("   stat {}", state.status()), Look::Plain));
+        res.push(flags);
+        res
+    }
+
+    /// The quads from the stack pointer up, with the return addresses
+    /// of the calls that have not returned yet pointed out
+    fn stack(&self, rows: u64) -> Vec<Line> {
+        let state = &self.state;
+        let rsp = state.get_register(Register::RRSP as u8);
+        let rbp = state.get_register(Register::RRBP as u8);
+        let mut res = vec![];
+        for row in 0..rows {
+            let address = rsp.wrapping_add(row * 8);
+            let value = match state.peek_le(address) {
+                Ok(value) => value,
+                Err(_) => break,
+            };
+            let mark = match address {
+                _ if row == 0 => "rsp",
+                _ if address == rbp => "rbp",
+                _ => "",
+            };
+            let mut text = format!("{:<4}0x{:04x}  0x{:016x}", mark, address, value);
+            let frame = state
+                .get_frames()
+                .iter()
+                .find(|frame| frame.slot == address);
+            if let Some(frame) = frame {
+                let to = address_label(state, frame.return_address);
+                text.push_str(&format!("  return to {}", to));
+            }
+            res.push(plain(text));
+        }
+        if res.is_empty() {
+            res.push(plain(format!("%rsp 0x{:x} is outside memory", rsp)));
+        }
+        res
+    }
+
+    fn memory(&self) -> Vec<Line> {
+        match hexdump(self.state, self.memory, MEMORY_ROWS * 16) {
+            Ok(lines) => lines.into_iter().map(plain).collect(),
+            Err(e) => vec![plain(e.to_string())],
+        }
+    }
+
+    fn draw<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let (width, height) = terminal::size()?;
+        queue!(out, Clear(ClearType::All), MoveTo(0, 0))?;
+        let status = format!(
+            " {}  pc 0x{:x}  {}  {}",
+            self.file_name,
+            self.state.get_pc(),
+            self.state.status(),
+            self.message
+        );
+        draw_line(out, &[(status, Look::Current)], width as usize)?;
+        let main = height.saturating_sub(2 + MEMORY_HEIGHT);
+        let right = (width * 9 / 20).max(52).min(width);
+        let left = width - right;
+        let disassembly = Area {
+            x: 0,
+            y: 1,
+            width: left,
+            height: main / 2,
+        };
+        let source = Area {
+            x: 0,
+            y: 1 + main / 2,
+            width: left,
+            height: main - main / 2,
+        };
+        let registers = Area {
+            x: left,
+            y: 1,
+            width: right,
+            height: REGISTERS_HEIGHT.min(main),
+        };
+        let stack = Area {
+            x: left,
+            y: 1 + registers.height,
+            width: right,
+            height: main - registers.height,
+        };
+        let memory = Area {
+            x: 0,
+            y: 1 + main,
+            width,
+            height: MEMORY_HEIGHT,
+        };
+        let disassembly_title = format!("Disassembly 0x{:x}", self.cursor);
+        let lines = self.disassembly(disassembly.rows());
+        draw_pane(out, &disassembly, &disassembly_title, &lines)?;
+        let source_title = match self.state.line_at(self.cursor) {
+            Some(line) => format!("Source {}:{}", line.file, line.line),
+            None => "Source".to_string(),
+        };
+        draw_pane(out, &source, &source_title, &self.source(source.rows()))?;
+        draw_pane(out, &registers, "Registers", &self.registers())?;
+        draw_pane(out, &stack, "Stack", &self.stack(stack.rows()))?;
+        let memory_title = format!("Memory 0x{:x}", self.memory);
+        draw_pane(out, &memory, &memory_title, &self.memory())?;
+        queue!(out, MoveTo(0, height.saturating_sub(1)))?;
+        draw_line(out, &plain(KEYS.to_string()), width as usize)?;
+        out.flush()
+    }
+
+    /// Runs the program with State::run_until, then says why it
+    /// stopped unless it was done
+    /// done: given the instruction just executed and the resulting state
+    fn resume<F>(&mut self, done: F)
+    where
+        F: FnMut(&Instruction, &State) -> bool,
+    {
+        let stop = self.state.track_changes(|state| state.run_until(done));
+        self.message = match stop {
+            StopReason::Done => String::new(),
+            StopReason::Breakpoint(_) => format!("Breakpoint at 0x{:x}", self.state.get_pc()),
+            stop => stop.to_string(),
+        };
+        if let Some(e) = self.state.take_trace_error() {
+            self.message = e.to_string();
+        }
+        self.cursor = self.state.get_pc();
+    }
+
+    /// Selects the instruction before or after the cursor
+    fn select(&mut self, forward: bool) {
+        let around = match disassemble(self.state, self.cursor, 3) {
+            Ok(around) => around,
+            Err(_) => return,
+        };
+        let index = match around
+            .iter()
+            .position(|&(address, _)| address == self.cursor)
+        {
+            Some(index) => index,
+            None => return,
+        };
+        let next = if forward {
+            around.get(index + 1)
+        } else {
+            index.checked_sub(1).and_then(|index| around.get(index))
+        };
+        if let Some(&(address, _)) = next {
+            self.cursor = address;
+        }
+    }
+
+    fn toggle_breakpoint(&mut self) {
+        let breakpoints = self.state.get_breakpoints_mut();
+        if breakpoints.is_set(self.cursor) {
+            breakpoints.delete_at(self.cursor);
+            self.message = format!("Deleted the breakpoints at 0x{:x}", self.cursor);
+            return;
+        }
+        self.message = match breakpoints.add(self.cursor, None, false) {
+            Ok(id) => format!("Breakpoint {} at 0x{:x}", id, self.cursor),
+            Err(e) => e.to_string(),
+        };
+    }
+
+    /// Acts on a key, returning false once it quits
+    fn key(&mut self, key: KeyEvent) -> bool {
+        let control = key.modifiers.contains(KeyModifiers::CONTROL);
+        match key.code {
+            KeyCode::Char('q') | KeyCode::Esc => return false,
+            KeyCode::Char('c') if control => return false,
+            KeyCode::Char('s') | KeyCode::F(11) => self.resume(|_, _| true),
+            KeyCode::Char('n') | KeyCode::F(10) => {
+                let val_p = match Instruction::new(self.state) {
+                    Ok(instr) => instr.get_val_p(),
+                    Err(e) => {
+                        self.message = e.to_string();
+                        return true;
+                    }
+                };
+                self.resume(|_, state| state.get_pc() == val_p)
+            }
+            KeyCode::Char('c') | KeyCode::F(5) => self.resume(|_, _| false),
+            KeyCode::Char('b') | KeyCode::F(9) => self.toggle_breakpoint(),
+            KeyCode::Up | KeyCode::Char('k') => self.select(false),
+            KeyCode::Down | KeyCode::Char('j') => self.select(true),
+            KeyCode::PageUp => self.memory = self.memory.saturating_sub(MEMORY_ROWS * 16),
+            KeyCode::PageDown => self.memory = self.memory.saturating_add(MEMORY_ROWS * 16),
+            _ => (),
+        }
+        true
+    }
+
+    fn event_loop<W: Write>(&mut self, out: &mut W) -> Result<(), Box<dyn Error>> {
+        loop {
+            self.draw(out)?;
+            if let Event::Key(key) = event::read()? {
+                if key.kind != KeyEventKind::Release && !self.key(key) {
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+/// Debugs state full screen until the user quits
+/// file_name: the name the program was loaded from, for the title
+pub fn run(state: &mut State, file_name: &str) -> Result<(), Box<dyn Error>> {
+    // Memory starts out on the data, if the program has any
+    let memory = state
+        .get_sections()
+        .iter()
+        .find(|section| !section.executable && section.size > 0)
+        .map_or(0, |section| section.start);
+    let mut screen = Screen {
+        cursor: state.get_pc(),
+        state,
+        file_name,
+        memory,
+        message: String::new(),
+    };
+    let mut out = stdout();
+    terminal::enable_raw_mode()?;
+    execute!(out, EnterAlternateScreen, Hide)?;
+    let res = screen.event_loop(&mut out);
+    execute!(out, Show, LeaveAlternateScreen)?;
+    terminal::disable_raw_mode()?;
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// irmovq $0x100, %rsp; call 0x14; halt; then at 0x14
+    /// irmovq $0x5, %rax; ret
+    fn program() -> State {
+        let mut bytes = vec![0; 0x108];
+        bytes[..10].copy_from_slice(&[0x30, 0xf4, 0, 1, 0, 0, 0, 0, 0, 0]);
+        bytes[10..19].copy_from_slice(&[0x80, 0x14, 0, 0, 0, 0, 0, 0, 0]);
+        bytes[0x14..0x1e].copy_from_slice(&[0x30, 0xf0, 5, 0, 0, 0, 0, 0, 0, 0]);
+        bytes[0x1e] = 0x90;
+        State::from_bytes(bytes)
+    }
+
+    fn screen(state: &mut State) -> Screen<'_> {
+        Screen {
+            cursor: state.get_pc(),
+            state,
+            file_name: "test.yo",
+            memory: 0,
+            message: String::new(),
+        }
+    }
+
+    fn press(screen: &mut Screen, code: KeyCode) -> bool {
+        screen.key(KeyEvent::new(code, KeyModifiers::NONE))
+    }
+
+    #[test]
+    fn steps_over_and_into_calls() {
+        let mut state = program();
+        let mut screen = screen(&mut state);
+        assert!(press(&mut screen, KeyCode::Char('s')));
+        assert_eq!(screen.cursor, 0xa);
+        assert!(press(&mut screen, KeyCode::Char('n')));
+        assert_eq!(screen.state.get_pc(), 0x13);
+        assert_eq!(screen.state.get_register(Register::RRAX as u8), 5);
+        assert_eq!(screen.message, "");
+        assert!(press(&mut screen, KeyCode::Char('c')));
+        assert_eq!(screen.message, StopReason::Halted.to_string());
+        // The program only goes back to where it was through undo
+        assert!(screen.state.undo_instruction());
+        assert_eq!(screen.state.get_pc(), 0x1e);
+    }
+
+    #[test]
+    fn toggles_breakpoints_on_the_selected_instruction() {
+        let mut state = program();
+        let mut screen = screen(&mut state);
+        press(&mut screen, KeyCode::Down);
+        press(&mut screen, KeyCode::Down);
+        assert_eq!(screen.cursor, 0x13);
+        press(&mut screen, KeyCode::Up);
+        assert_eq!(screen.cursor, 0xa);
+        press(&mut screen, KeyCode::Char('b'));
+        assert_eq!(screen.message, "Breakpoint 1 at 0xa");
+        assert!(screen.state.get_breakpoints().is_set(0xa));
+        press(&mut screen, KeyCode::Char('c'));
+        assert_eq!(screen.message, "Breakpoint at 0xa");
+        assert_eq!(screen.cursor, 0xa);
+        press(&mut screen, KeyCode::Char('b'));
+        assert_eq!(screen.message, "Deleted the breakpoints at 0xa");
+        assert!(!screen.state.get_breakpoints().is_set(0xa));
+    }
+
+    #[test]
+    fn pages_through_memory_and_quits() {
+        let mut state = program();
+        let mut screen = screen(&mut state);
+        press(&mut screen, KeyCode::PageUp);
+        assert_eq!(screen.memory, 0);
+        press(&mut screen, KeyCode::PageDown);
+        assert_eq!(screen.memory, MEMORY_ROWS * 16);
+        assert!(!press(&mut screen, KeyCode::Char('q')));
+        let control_c = KeyEvent::new(KeyCode::Char('c'), KeyModifiers::CONTROL);
+        assert!(!screen.key(control_c));
+    }
+
+    /// What draw_line shows for line, without the escape codes that
+    /// style it
+    fn shown(line: Line, width: usize) -> String {
+        let mut out = vec![];
+        draw_line(&mut out, &line, width).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        let mut res = String::new();
+        let mut escaped = false;
+        for c in text.chars() {
+            match c {
+                '\x1b' => escaped = true,
+                'm' if escaped => escaped = false,
+                c if !escaped => res.push(c),
+                _ => (),
+            }
+        }
+        res
+    }
+
+    #[test]
+    fn pads_and_cuts_lines_to_the_width_of_a_pane() {
+        assert_eq!(shown(plain("registers".to_string()), 4), "regi");
+        assert_eq!(shown(plain("ab".to_string()), 5), "ab   ");
+        let line = vec![
+            ("b".to_string(), Look::Breakpoint),
+            ("=> 0x0".to_string(), Look::Current),
+        ];
+        assert_eq!(shown(line, 9), "b=> 0x0  ");
+    }
+}
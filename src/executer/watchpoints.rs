@@ -0,0 +1,204 @@
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+
+/// Which accesses a watchpoint stops on: `watch`, `rwatch` or `awatch`
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Kind {
+    Write,
+    Read,
+    Access,
+}
+
+impl Kind {
+    fn name(self) -> &'static str {
+        match self {
+            Kind::Write => "write",
+            Kind::Read => "read",
+            Kind::Access => "access",
+        }
+    }
+}
+
+/// Stops execution when the program touches the bytes from address to
+/// last, inclusive
+struct Watchpoint {
+    id: usize,
+    address: u64,
+    last: u64,
+    kind: Kind,
+}
+
+/// A watched access made by the program
+/// kind: Read or Write, the access itself
+/// old, new: the quad before and after the access, equal for reads
+#[derive(Debug, PartialEq)]
+pub struct Hit {
+    pub id: usize,
+    pub kind: Kind,
+    pub address: u64,
+    pub old: u64,
+    pub new: u64,
+}
+
+impl Display for Hit {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self.kind {
+            Kind::Write => write!(
+                f,
+                "Watchpoint {}, write M_8[0x{:x}]: 0x{:x} -> 0x{:x}",
+                self.id, self.address, self.old, self.new
+            ),
+            _ => write!(
+                f,
+                "Watchpoint {}, read M_8[0x{:x}] = 0x{:x}",
+                self.id, self.address, self.new
+            ),
+        }
+    }
+}
+
+/// The watchpoints of a program being debugged
+/// hits: the watched accesses since the last time they were taken
+#[derive(Default)]
+pub struct Watchpoints {
+    list: Vec<Watchpoint>,
+    last_id: usize,
+    hits: Vec<Hit>,
+}
+
+impl Watchpoints {
+    /// Adds a watchpoint, returning its id
+    /// address: the first byte to watch
+    /// len: how many bytes to watch, at least 1 and not past the end of
+    /// the address space
+    /// kind: which accesses to stop on
+    pub fn add(&mut self, address: u64, len: u64, kind: Kind) -> Result<usize, Box<dyn Error>> {
+        if len == 0 {
+            return Err("A watchpoint must cover at least one byte".into());
+        }
+        let last = address
+            .checked_add(len - 1)
+            .ok_or("A watchpoint cannot go past the end of memory")?;
+        self.last_id += 1;
+        let id = self.last_id;
+        self.list.push(Watchpoint {
+            id,
+            address,
+            last,
+            kind,
+        });
+        Ok(id)
+    }
+
+    /// Deletes every watchpoint starting at address
+    pub fn delete_at(&mut self, address: u64) {
+        self.list.retain(|watchpoint| watchpoint.address != address);
+    }
+
+    /// Records the program reading the quad at address
+    pub fn read(&mut self, address: u64, value: u64) {
+        self.record(Kind::Read, address, value, value);
+    }
+
+    /// Records the program writing the quad at address
+    pub fn write(&mut self, address: u64, old: u64, new: u64) {
+        self.record(Kind::Write, address, old, new);
+    }
+
+    fn record(&mut self, kind: Kind, address: u64, old: u64, new: u64) {
+        for watchpoint in &self.list {
+            let overlaps =
+                address <= watchpoint.last && watchpoint.address <= address.saturating_add(7);
+            if overlaps && (watchpoint.kind == kind || watchpoint.kind == Kind::Access) {
+                self.hits.push(Hit {
+                    id: watchpoint.id,
+                    kind,
+                    address,
+                    old,
+                    new,
+                });
+            }
+        }
+    }
+
+    /// Takes the accesses recorded so far, none if no watchpoint was
+    /// touched
+    pub fn take_hits(&mut self) -> Vec<Hit> {
+        std::mem::take(&mut self.hits)
+    }
+
+    /// Prints every watchpoint
+    pub fn print(&self) {
+        if self.list.is_empty() {
+            println!("      No watchpoints");
+        }
+        for watchpoint in &self.list {
+            println!(
+                "      {:<3} 0x{:x}-0x{:x} {}",
+                watchpoint.id,
+                watchpoint.address,
+                watchpoint.last,
+                watchpoint.kind.name()
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_only_the_accesses_each_watchpoint_stops_on() {
+        let mut watchpoints = Watchpoints::default();
+        let write = watchpoints.add(0x100, 8, Kind::Write).unwrap();
+        let access = watchpoints.add(0x104, 1, Kind::Access).unwrap();
+        watchpoints.read(0x100, 7);
+        assert_eq!(
+            watchpoints.take_hits(),
+            vec![Hit {
+                id: access,
+                kind: Kind::Read,
+                address: 0x100,
+                old: 7,
+                new: 7,
+            }]
+        );
+        watchpoints.write(0xf9, 1, 2);
+        let ids: Vec<usize> = watchpoints.take_hits().iter().map(|hit| hit.id).collect();
+        assert_eq!(ids, vec![write]);
+        watchpoints.write(0xf8, 1, 2);
+        watchpoints.write(0x108, 1, 2);
+        assert!(watchpoints.take_hits().is_empty());
+    }
+
+    #[test]
+    fn rejects_empty_ranges_and_ranges_that_wrap() {
+        let mut watchpoints = Watchpoints::default();
+        assert!(watchpoints.add(0x100, 0, Kind::Write).is_err());
+        assert!(watchpoints.add(u64::MAX, 2, Kind::Write).is_err());
+        assert!(watchpoints.add(u64::MAX, 1, Kind::Write).is_ok());
+        watchpoints.write(u64::MAX - 3, 0, 0);
+        assert_eq!(watchpoints.take_hits().len(), 1);
+    }
+
+    #[test]
+    fn describes_hits() {
+        let write = Hit {
+            id: 1,
+            kind: Kind::Write,
+            address: 0x10,
+            old: 1,
+            new: 2,
+        };
+        assert_eq!(
+            write.to_string(),
+            "Watchpoint 1, write M_8[0x10]: 0x1 -> 0x2"
+        );
+        let read = Hit {
+            kind: Kind::Read,
+            ..write
+        };
+        assert_eq!(read.to_string(), "Watchpoint 1, read M_8[0x10] = 0x2");
+    }
+}
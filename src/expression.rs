@@ -0,0 +1,507 @@
+use crate::number_parser;
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+
+/// Resolves the names an expression refers to
+pub trait Context {
+    /// Returns the value of a named symbol, if it is known
+    fn symbol(&self, name: &str) -> Option<u64>;
+
+    /// Returns the value of a register written as %name or $name, if
+    /// there is such a register
+    fn register(&self, _name: &str) -> Option<u64> {
+        None
+    }
+
+    /// Returns the quad at address, for `M[address]`
+    fn memory(&self, _address: u64) -> Result<u64, Box<dyn Error>> {
+        Err(error("Memory cannot be read here".to_string()))
+    }
+}
+
+/// A parsed integer expression
+/// Arithmetic wraps around on u64, comparisons and logical operators
+/// evaluate to 1 or 0
+#[derive(Debug, Clone)]
+pub enum Expr {
+    Number(u64),
+    Symbol(String),
+    Register(String),
+    Memory(Box<Expr>),
+    Unary(UnaryOp, Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UnaryOp {
+    Negate,
+    Not,
+    Complement,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinaryOp {
+    Mul,
+    Div,
+    Rem,
+    Add,
+    Sub,
+    Shl,
+    Shr,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Eq,
+    Ne,
+    BitAnd,
+    BitXor,
+    BitOr,
+    And,
+    Or,
+}
+
+#[derive(Debug)]
+pub struct ExpressionError(String);
+
+impl Error for ExpressionError {}
+
+impl Display for ExpressionError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Parses then evaluates an expression in one go
+/// input: the expression text
+/// context: resolves the symbols used in the expression
+pub fn evaluate(input: &str, context: &dyn Context) -> Result<u64, Box<dyn Error>> {
+    Expr::parse(input)?.eval(context)
+}
+
+impl Expr {
+    /// Parses input into an expression, failing if anything is left over
+    pub fn parse(input: &str) -> Result<Self, Box<dyn Error>> {
+        let tokens = tokenize(input)?;
+        if tokens.len() > MAX_TOKENS {
+            return Err(error("Expression too long".to_string()));
+        }
+        let mut parser = ExprParser {
+            tokens,
+            pos: 0,
+            depth: 0,
+        };
+        let expr = parser.parse_binary(0)?;
+        match parser.tokens.get(parser.pos) {
+            None => Ok(expr),
+            Some(token) => Err(error(format!("Unexpected {} in expression", token))),
+        }
+    }
+
+    /// Evaluates the expression, failing on unknown symbols or
+    /// division by zero
+    pub fn eval(&self, context: &dyn Context) -> Result<u64, Box<dyn Error>> {
+        let val = match self {
+            Expr::Number(val) => *val,
+            Expr::Symbol(name) => match context.symbol(name) {
+                Some(val) => val,
+                None => return Err(error(format!("Undefined symbol {}", name))),
+            },
+            Expr::Register(name) => match context.register(name) {
+                Some(val) => val,
+                None => return Err(error(format!("Unknown register %{}", name))),
+            },
+            Expr::Memory(address) => context.memory(address.eval(context)?)?,
+            Expr::Unary(op, inner) => {
+                let val = inner.eval(context)?;
+                match op {
+                    UnaryOp::Negate => val.wrapping_neg(),
+                    UnaryOp::Not => (val == 0) as u64,
+                    UnaryOp::Complement => !val,
+                }
+            }
+            Expr::Binary(BinaryOp::And, lhs, rhs) => {
+                (lhs.eval(context)? != 0 && rhs.eval(context)? != 0) as u64
+            }
+            Expr::Binary(BinaryOp::Or, lhs, rhs) => {
+                (lhs.eval(context)? != 0 || rhs.eval(context)? != 0) as u64
+            }
+            Expr::Binary(op, lhs, rhs) => apply(*op, lhs.eval(context)?, rhs.eval(context)?)?,
+        };
+        Ok(val)
+    }
+}
+
+fn apply(op: BinaryOp, lhs: u64, rhs: u64) -> Result<u64, Box<dyn Error>> {
+    let val = match op {
+        BinaryOp::Mul => lhs.wrapping_mul(rhs),
+        BinaryOp::Div | BinaryOp::Rem if rhs == 0 => {
+            return Err(error("Division by zero in expression".to_string()))
+        }
+        BinaryOp::Div => lhs / rhs,
+        BinaryOp::Rem => lhs % rhs,
+        BinaryOp::Add => lhs.wrapping_add(rhs),
+        BinaryOp::Sub => lhs.wrapping_sub(rhs),
+        BinaryOp::Shl => lhs.checked_shl(rhs as u32).unwrap_or(0),
+        BinaryOp::Shr => lhs.checked_shr(rhs as u32).unwrap_or(0),
+        BinaryOp::Lt => (lhs < rhs) as u64,
+        BinaryOp::Le => (lhs <= rhs) as u64,
+        BinaryOp::Gt => (lhs > rhs) as u64,
+        BinaryOp::Ge => (lhs >= rhs) as u64,
+        BinaryOp::Eq => (lhs == rhs) as u64,
+        BinaryOp::Ne => (lhs != rhs) as u64,
+        BinaryOp::BitAnd => lhs & rhs,
+        BinaryOp::BitXor => lhs ^ rhs,
+        BinaryOp::BitOr => lhs | rhs,
+        BinaryOp::And => (lhs != 0 && rhs != 0) as u64,
+        BinaryOp::Or => (lhs != 0 || rhs != 0) as u64,
+    };
+    Ok(val)
+}
+
+fn error(message: String) -> Box<dyn Error> {
+    Box::new(ExpressionError(message))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(u64),
+    Ident(String),
+    Register(String),
+    Op(&'static str),
+}
+
+impl Display for Token {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Number(val) => write!(f, "0x{:x}", val),
+            Token::Ident(name) => write!(f, "{}", name),
+            Token::Register(name) => write!(f, "%{}", name),
+            Token::Op(op) => write!(f, "'{}'", op),
+        }
+    }
+}
+
+// Longer operators come first so that "<<" is not read as two "<"
+const OPERATORS: [&str; 24] = [
+    "<<", ">>", "<=", ">=", "==", "!=", "&&", "||", "*", "/", "%", "+", "-", "<", ">", "&", "^",
+    "|", "!", "~", "(", ")", "[", "]",
+];
+
+fn is_ident_start(c: char) -> bool {
+    c.is_ascii_alphabetic() || c == '_' || c == '.'
+}
+
+fn is_ident_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '.'
+}
+
+fn follows_operand(tokens: &[Token]) -> bool {
+    match tokens.last() {
+        Some(Token::Op(op)) => *op == ")" || *op == "]",
+        Some(_) => true,
+        None => false,
+    }
+}
+
+fn tokenize(input: &str) -> Result<Vec<Token>, Box<dyn Error>> {
+    let mut tokens = vec![];
+    let mut rest = input.trim_start();
+    while let Some(c) = rest.chars().next() {
+        let len = if c.is_ascii_digit() {
+            let len = rest
+                .find(|c: char| !c.is_ascii_alphanumeric())
+                .unwrap_or(rest.len());
+            tokens.push(Token::Number(number_parser::parse_num(&rest[..len])?));
+            len
+        } else if (c == '$' || c == '%' && !follows_operand(&tokens))
+            && rest[1..].starts_with(is_ident_start)
+        {
+            // %name is a register unless it follows an operand, where
+            // % is the remainder operator, $name always is
+            let len = rest[1..]
+                .find(|c: char| !is_ident_char(c))
+                .map_or(rest.len(), |len| len + 1);
+            tokens.push(Token::Register(rest[1..len].to_string()));
+            len
+        } else if is_ident_start(c) {
+            let len = rest.find(|c: char| !is_ident_char(c)).unwrap_or(rest.len());
+            tokens.push(Token::Ident(rest[..len].to_string()));
+            len
+        } else {
+            match OPERATORS.iter().find(|op| rest.starts_with(*op)) {
+                Some(op) => {
+                    tokens.push(Token::Op(op));
+                    op.len()
+                }
+                None => return Err(error(format!("Unexpected '{}' in expression", c))),
+            }
+        };
+        rest = rest[len..].trim_start();
+    }
+    Ok(tokens)
+}
+
+// Binding power of each binary operator, loosest first
+const PRECEDENCE: [&[(&str, BinaryOp)]; 10] = [
+    &[("||", BinaryOp::Or)],
+    &[("&&", BinaryOp::And)],
+    &[("|", BinaryOp::BitOr)],
+    &[("^", BinaryOp::BitXor)],
+    &[("&", BinaryOp::BitAnd)],
+    &[("==", BinaryOp::Eq), ("!=", BinaryOp::Ne)],
+    &[
+        ("<", BinaryOp::Lt),
+        ("<=", BinaryOp::Le),
+        (">", BinaryOp::Gt),
+        (">=", BinaryOp::Ge),
+    ],
+    &[("<<", BinaryOp::Shl), (">>", BinaryOp::Shr)],
+    &[("+", BinaryOp::Add), ("-", BinaryOp::Sub)],
+    &[
+        ("*", BinaryOp::Mul),
+        ("/", BinaryOp::Div),
+        ("%", BinaryOp::Rem),
+    ],
+];
+
+// Parentheses, M[] and unary operators nest no deeper than this, and
+// operators chain no longer than the tokens allow, so that parsing and
+// evaluating cannot run out of stack
+const MAX_DEPTH: usize = 64;
+const MAX_TOKENS: usize = 0x400;
+
+struct ExprParser {
+    tokens: Vec<Token>,
+    pos: usize,
+    depth: usize,
+}
+
+impl ExprParser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn eat(&mut self, op: &str) -> bool {
+        if matches!(self.peek(), Some(Token::Op(next)) if *next == op) {
+            self.pos += 1;
+            return true;
+        }
+        false
+    }
+
+    fn expect(&mut self, op: &str) -> Result<(), Box<dyn Error>> {
+        if self.eat(op) {
+            Ok(())
+        } else {
+            Err(error(format!("Expected '{}' in expression", op)))
+        }
+    }
+
+    /// Parses what parse follows, one level deeper
+    fn nested<T>(
+        &mut self,
+        parse: impl FnOnce(&mut Self) -> Result<T, Box<dyn Error>>,
+    ) -> Result<T, Box<dyn Error>> {
+        if self.depth == MAX_DEPTH {
+            return Err(error("Expression nested too deeply".to_string()));
+        }
+        self.depth += 1;
+        let res = parse(self);
+        self.depth -= 1;
+        res
+    }
+
+    fn parse_binary(&mut self, level: usize) -> Result<Expr, Box<dyn Error>> {
+        if level == PRECEDENCE.len() {
+            return self.parse_unary();
+        }
+        let mut lhs = self.parse_binary(level + 1)?;
+        loop {
+            let op = match self.peek() {
+                Some(Token::Op(op)) => PRECEDENCE[level].iter().find(|(s, _)| s == op),
+                _ => None,
+            };
+            match op {
+                Some(&(_, op)) => {
+                    self.pos += 1;
+                    let rhs = self.parse_binary(level + 1)?;
+                    lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+                }
+                None => return Ok(lhs),
+            }
+        }
+    }
+
+    fn parse_unary(&mut self) -> Result<Expr, Box<dyn Error>> {
+        // A unary + changes nothing
+        while self.eat("+") {}
+        let op = match self.peek() {
+            Some(Token::Op("-")) => Some(UnaryOp::Negate),
+            Some(Token::Op("!")) => Some(UnaryOp::Not),
+            Some(Token::Op("~")) => Some(UnaryOp::Complement),
+            _ => None,
+        };
+        match op {
+            Some(op) => {
+                self.pos += 1;
+                let operand = self.nested(Self::parse_unary)?;
+                Ok(Expr::Unary(op, Box::new(operand)))
+            }
+            None => self.parse_primary(),
+        }
+    }
+
+    fn parse_primary(&mut self) -> Result<Expr, Box<dyn Error>> {
+        match self.next() {
+            Some(Token::Number(val)) => Ok(Expr::Number(val)),
+            Some(Token::Ident(name)) if name == "M" && self.eat("[") => {
+                let address = self.nested(|parser| parser.parse_binary(0))?;
+                self.expect("]")?;
+                Ok(Expr::Memory(Box::new(address)))
+            }
+            Some(Token::Ident(name)) => Ok(Expr::Symbol(name)),
+            Some(Token::Register(name)) => Ok(Expr::Register(name)),
+            Some(Token::Op("(")) => {
+                let expr = self.nested(|parser| parser.parse_binary(0))?;
+                self.expect(")")?;
+                Ok(expr)
+            }
+            Some(token) => Err(error(format!("Unexpected {} in expression", token))),
+            None => Err(error("Unexpected end of expression".to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Knows a few symbols and registers, with every quad of memory
+    /// holding twice its address
+    struct Values;
+
+    impl Context for Values {
+        fn symbol(&self, name: &str) -> Option<u64> {
+            match name {
+                "size" => Some(8),
+                ".Lbase" => Some(0x100),
+                _ => None,
+            }
+        }
+
+        fn register(&self, name: &str) -> Option<u64> {
+            match name {
+                "rax" => Some(3),
+                _ => None,
+            }
+        }
+
+        fn memory(&self, address: u64) -> Result<u64, Box<dyn Error>> {
+            Ok(address * 2)
+        }
+    }
+
+    fn eval(input: &str) -> u64 {
+        evaluate(input, &Values).unwrap()
+    }
+
+    fn eval_error(input: &str) -> String {
+        evaluate(input, &Values).err().unwrap().to_string()
+    }
+
+    #[test]
+    fn follows_operator_precedence() {
+        assert_eq!(eval("1 + 2 * 3"), 7);
+        assert_eq!(eval("(1 + 2) * 3"), 9);
+        assert_eq!(eval("10 - 4 - 3"), 3);
+        assert_eq!(eval("100 / 10 / 5"), 2);
+        assert_eq!(eval("1 << 2 + 1"), 8);
+        assert_eq!(eval("1 | 2 ^ 3 & 6"), 1);
+        assert_eq!(eval("1 + 1 == 2 && 3 > 2"), 1);
+        assert_eq!(eval("0 || 2 < 1 || 4 >= 4"), 1);
+        assert_eq!(eval("7 % 4 * 2"), 6);
+    }
+
+    #[test]
+    fn applies_unary_operators() {
+        assert_eq!(eval("-1"), u64::MAX);
+        assert_eq!(eval("--5"), 5);
+        assert_eq!(eval("+5"), 5);
+        assert_eq!(eval("!0"), 1);
+        assert_eq!(eval("!7"), 0);
+        assert_eq!(eval("~0"), u64::MAX);
+        assert_eq!(eval("-2 * 3"), 6u64.wrapping_neg());
+        assert_eq!(eval("~0xff & 0x1ff"), 0x100);
+    }
+
+    #[test]
+    fn resolves_symbols_registers_and_memory() {
+        assert_eq!(eval(".Lbase + size * 2"), 0x110);
+        assert_eq!(eval("%rax + $rax"), 6);
+        // % after an operand is the remainder operator
+        assert_eq!(eval("0x13 %size"), 3);
+        assert_eq!(eval("M[size + 8]"), 32);
+        assert_eq!(eval_error("missing + 1"), "Undefined symbol missing");
+        assert_eq!(eval_error("%rbx"), "Unknown register %rbx");
+    }
+
+    #[test]
+    fn wraps_around_on_overflow() {
+        assert_eq!(eval("0xffffffffffffffff + 1"), 0);
+        assert_eq!(eval("0 - 1"), u64::MAX);
+        assert_eq!(eval("0x8000000000000000 * 2"), 0);
+        assert_eq!(eval("1 << 64"), 0);
+        assert_eq!(eval("1 >> 100"), 0);
+        // Numbers that do not fit in 64 bits are still an error
+        assert!(evaluate("0x10000000000000000", &Values).is_err());
+    }
+
+    #[test]
+    fn reports_division_by_zero() {
+        assert_eq!(eval_error("1 / 0"), "Division by zero in expression");
+        assert_eq!(
+            eval_error("1 % (size - 8)"),
+            "Division by zero in expression"
+        );
+        // Logical operators do not evaluate what they can skip
+        assert_eq!(eval("0 && 1 / 0"), 0);
+        assert_eq!(eval("1 || 1 / 0"), 1);
+    }
+
+    #[test]
+    fn rejects_malformed_expressions() {
+        assert_eq!(eval_error("(1 + 2"), "Expected ')' in expression");
+        assert_eq!(eval_error("M[1"), "Expected ']' in expression");
+        assert_eq!(eval_error("1 + 2)"), "Unexpected ')' in expression");
+        assert_eq!(eval_error("1 +"), "Unexpected end of expression");
+        assert_eq!(eval_error(""), "Unexpected end of expression");
+        assert_eq!(eval_error("1 2"), "Unexpected 0x2 in expression");
+        assert_eq!(eval_error("1 @ 2"), "Unexpected '@' in expression");
+        assert_eq!(eval_error("* 2"), "Unexpected '*' in expression");
+    }
+
+    #[test]
+    fn rejects_expressions_nested_too_deeply_or_too_long() {
+        let nested = |open: &str, close: &str, depth| {
+            format!("{}1{}", open.repeat(depth), close.repeat(depth))
+        };
+        assert_eq!(eval(&nested("(", ")", MAX_DEPTH)), 1);
+        assert_eq!(eval(&nested("-~~-", "", MAX_DEPTH / 4)), 1);
+        assert_eq!(eval(&nested("+", "", MAX_TOKENS - 1)), 1);
+        let sum = format!("0{}", "+1".repeat(MAX_TOKENS / 2 - 1));
+        assert_eq!(eval(&sum), MAX_TOKENS as u64 / 2 - 1);
+        let too_deep = "Expression nested too deeply";
+        assert_eq!(eval_error(&nested("(", ")", MAX_DEPTH + 1)), too_deep);
+        assert_eq!(eval_error(&nested("M[", "]", MAX_DEPTH + 1)), too_deep);
+        assert_eq!(eval_error(&nested("-", "", MAX_DEPTH + 1)), too_deep);
+        assert_eq!(eval_error(&nested("~", "", MAX_DEPTH + 1)), too_deep);
+        assert_eq!(eval_error(&format!("{}+1", sum)), "Expression too long");
+    }
+}
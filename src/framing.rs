@@ -0,0 +1,69 @@
+use serde_json::Value;
+use std::error::Error;
+use std::io::{BufRead, Write};
+
+// The largest message read, well past any request a client sends
+const MAX_MESSAGE_LEN: usize = 64 << 20;
+
+/// Reads a message framed by a Content-Length header
+/// Returns None once the client closes the stream
+pub fn read_message<R: BufRead>(input: &mut R) -> Result<Option<Value>, Box<dyn Error>> {
+    let mut len = None;
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim();
+        if header.is_empty() {
+            break;
+        }
+        if let Some(value) = header.strip_prefix("Content-Length:") {
+            len = Some(value.trim().parse::<usize>()?);
+        }
+    }
+    let len = len.ok_or("Message without a Content-Length header")?;
+    if len > MAX_MESSAGE_LEN {
+        return Err(format!("Message of {} bytes is too long", len).into());
+    }
+    let mut body = vec![0; len];
+    input.read_exact(&mut body)?;
+    Ok(Some(serde_json::from_slice(&body)?))
+}
+
+/// Writes message framed by a Content-Length header
+pub fn write_message<W: Write>(output: &mut W, message: &Value) -> Result<(), Box<dyn Error>> {
+    let body = message.to_string();
+    write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    output.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn reads_back_what_it_writes() {
+        let mut buffer = vec![];
+        write_message(&mut buffer, &json!({ "id": 1 })).unwrap();
+        write_message(&mut buffer, &json!([])).unwrap();
+        let mut input = &buffer[..];
+        assert_eq!(read_message(&mut input).unwrap(), Some(json!({ "id": 1 })));
+        assert_eq!(read_message(&mut input).unwrap(), Some(json!([])));
+        assert!(read_message(&mut input).unwrap().is_none());
+    }
+
+    #[test]
+    fn refuses_messages_too_long_to_read() {
+        let header = format!("Content-Length: {}\r\n\r\n", usize::MAX);
+        let e = read_message(&mut header.as_bytes()).err().unwrap();
+        assert_eq!(
+            e.to_string(),
+            format!("Message of {} bytes is too long", usize::MAX)
+        );
+        let e = read_message(&mut &b"\r\n{}"[..]).err().unwrap();
+        assert_eq!(e.to_string(), "Message without a Content-Length header");
+    }
+}
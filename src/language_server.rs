@@ -0,0 +1,709 @@
+use crate::assembler::{
+    self, AssemblerOptions, AssemblyError, Role, Span, SymbolUse, Y86Assembler,
+};
+use crate::framing::{read_message, write_message};
+use crate::symbol_table::{SymbolInfo, SymbolKind};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::error::Error;
+use std::io::{stdin, stdout, BufRead, Write};
+
+// The whole text is sent on every change
+const SYNC_FULL: u64 = 1;
+
+const SEVERITY_ERROR: u64 = 1;
+
+const METHOD_NOT_FOUND: i64 = -32601;
+const REQUEST_FAILED: i64 = -32803;
+
+const COMPLETION_VARIABLE: u64 = 6;
+const COMPLETION_KEYWORD: u64 = 14;
+const COMPLETION_REFERENCE: u64 = 18;
+const COMPLETION_CONSTANT: u64 = 21;
+
+const SYMBOL_FUNCTION: u64 = 12;
+const SYMBOL_VARIABLE: u64 = 13;
+const SYMBOL_CONSTANT: u64 = 14;
+
+/// A source file open in the editor
+/// path: the file the document was opened from
+/// uses: the symbols written in the document, as the assembler found
+/// them
+/// assembly: the program assembled from text, None if it fails to
+/// assemble
+struct Document {
+    path: String,
+    lines: Vec<String>,
+    uses: Vec<SymbolUse>,
+    assembly: Option<Y86Assembler>,
+}
+
+fn is_symbol_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '.'
+}
+
+fn is_numeric(symbol: &SymbolUse) -> bool {
+    symbol.name.starts_with(|c: char| c.is_ascii_digit())
+}
+
+/// The column of a byte offset into line, in UTF-16 code units as
+/// editors count them
+fn column(line: &str, offset: usize) -> usize {
+    line[..offset].encode_utf16().count()
+}
+
+/// The byte offset into line of a column in UTF-16 code units
+fn offset(line: &str, column: usize) -> usize {
+    let mut units = 0;
+    for (offset, c) in line.char_indices() {
+        if units >= column {
+            return offset;
+        }
+        units += c.len_utf16();
+    }
+    line.len()
+}
+
+fn uri_to_path(uri: &str) -> String {
+    let path = uri.strip_prefix("file://").unwrap_or(uri).as_bytes();
+    let mut res = vec![];
+    let mut index = 0;
+    while index < path.len() {
+        let escaped = path
+            .get(index + 1..index + 3)
+            .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+        match escaped {
+            Some(byte) if path[index] == b'%' => {
+                res.push(byte);
+                index += 3;
+            }
+            _ => {
+                res.push(path[index]);
+                index += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&res).to_string()
+}
+
+fn path_to_uri(path: &str) -> String {
+    let mut res = "file://".to_string();
+    for &byte in path.as_bytes() {
+        if byte.is_ascii_alphanumeric() || b"/-_.~".contains(&byte) {
+            res.push(byte as char);
+        } else {
+            res.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    res
+}
+
+impl Document {
+    /// Assembles text, returning the errors along with the document
+    fn new(path: String, text: &str, options: &AssemblerOptions) -> (Self, Vec<Box<dyn Error>>) {
+        let lines: Vec<String> = text.lines().map(str::to_string).collect();
+        let analysis = assembler::analyze(path.clone(), text, options);
+        // Lines copied by a .rept with a counter no longer match the
+        // text they came from
+        let uses = analysis
+            .uses
+            .into_iter()
+            .filter(|symbol| {
+                let span = &symbol.span;
+                let text = lines
+                    .get(span.line - 1)
+                    .and_then(|text| text.get(span.start..span.end));
+                span.file == path && text == Some(&symbol.name[..])
+            })
+            .collect();
+        let document = Document {
+            path,
+            lines,
+            uses,
+            assembly: analysis.assembly,
+        };
+        (document, analysis.errors)
+    }
+
+    fn range(&self, line: usize, start: usize, end: usize) -> Value {
+        let text = self.lines.get(line).map_or("", |text| &text[..]);
+        json!({
+            "start": { "line": line, "character": column(text, start) },
+            "end": { "line": line, "character": column(text, end) },
+        })
+    }
+
+    fn line_range(&self, line: usize) -> Value {
+        let len = self.lines.get(line).map_or(0, |text| text.len());
+        self.range(line, 0, len)
+    }
+
+    /// The error err reported on the line it points at
+    fn diagnostic(&self, err: &(dyn Error + 'static)) -> Value {
+        let (line, message) = match err.downcast_ref::<AssemblyError>() {
+            Some(located) if located.file() == self.path => {
+                (located.line() - 1, located.message().to_string())
+            }
+            _ => (0, err.to_string()),
+        };
+        json!({
+            "range": self.line_range(line),
+            "severity": SEVERITY_ERROR,
+            "source": "y86",
+            "message": message,
+        })
+    }
+
+    /// The symbol written at an LSP position
+    fn symbol_at(&self, position: &Value) -> Option<&SymbolUse> {
+        let line = position["line"].as_u64()? as usize;
+        let character = position["character"].as_u64()? as usize;
+        let at = offset(self.lines.get(line)?, character);
+        self.uses.iter().find(|symbol| {
+            let span = &symbol.span;
+            span.line == line + 1 && span.start <= at && at <= span.end
+        })
+    }
+
+    /// The symbol called name in the assembled program
+    fn symbol(&self, name: &str) -> Option<&SymbolInfo> {
+        self.assembly
+            .as_ref()?
+            .symbols()
+            .iter()
+            .find(|symbol| symbol.name == name)
+    }
+
+    /// The location of span, which may be in a file the document
+    /// includes
+    fn location(&self, uri: &str, span: &Span) -> Value {
+        let range = if span.file == self.path {
+            self.range(span.line - 1, span.start, span.end)
+        } else {
+            // The columns of other files are in bytes, which is as good
+            // as it gets without reading them
+            json!({
+                "start": { "line": span.line - 1, "character": span.start },
+                "end": { "line": span.line - 1, "character": span.end },
+            })
+        };
+        let uri = if span.file == self.path {
+            uri.to_string()
+        } else {
+            path_to_uri(&span.file)
+        };
+        json!({ "uri": uri, "range": range })
+    }
+
+    fn goto_definition(&self, uri: &str, position: &Value) -> Value {
+        let definition = self
+            .symbol_at(position)
+            .and_then(|symbol| symbol.definition.as_ref());
+        match definition {
+            Some(definition) => self.location(uri, definition),
+            None => Value::Null,
+        }
+    }
+
+    fn references(&self, uri: &str, position: &Value, declaration: bool) -> Value {
+        let symbol = match self.symbol_at(position) {
+            Some(symbol) => symbol,
+            None => return json!([]),
+        };
+        let found: Vec<Value> = self
+            .uses
+            .iter()
+            .filter(|other| declaration || !other.is_definition())
+            .filter(|other| match &symbol.definition {
+                Some(definition) => other.definition.as_ref() == Some(definition),
+                None => other.definition.is_none() && other.name == symbol.name,
+            })
+            .map(|other| self.location(uri, &other.span))
+            .collect();
+        json!(found)
+    }
+
+    /// The address and bytes of the line at position, and what the
+    /// symbol under it stands for
+    fn hover(&self, position: &Value) -> Value {
+        let line = position["line"].as_u64().unwrap_or(0) as usize;
+        let mut text = vec![];
+        let written = self.symbol_at(position);
+        if let Some(symbol) = written.and_then(|symbol| self.symbol(&symbol.name)) {
+            text.push(format!(
+                "{} = 0x{:x} ({})",
+                symbol.name,
+                symbol.address,
+                symbol.kind.name()
+            ));
+        }
+        if let Some(assembly) = &self.assembly {
+            let emitted = assembly
+                .lines()
+                .iter()
+                .filter(|info| info.file == self.path && info.line == line + 1);
+            for info in emitted {
+                let start = info.address as usize;
+                let bytes = assembly
+                    .bytes()
+                    .get(start..start + info.size as usize)
+                    .unwrap_or(&[]);
+                let hex: Vec<String> = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+                text.push(format!("0x{:03x}: {}", info.address, hex.join(" ")));
+            }
+        }
+        if text.is_empty() {
+            return Value::Null;
+        }
+        json!({
+            "contents": { "kind": "plaintext", "value": text.join("\n") },
+            "range": self.line_range(line),
+        })
+    }
+
+    /// Mnemonics where an instruction goes, registers and symbols in
+    /// its operands
+    fn completion(&self, position: &Value) -> Value {
+        let line = position["line"].as_u64().unwrap_or(0) as usize;
+        let text = self.lines.get(line).map_or("", |text| &text[..]);
+        let at = offset(text, position["character"].as_u64().unwrap_or(0) as usize);
+        let before = &text[..at];
+        if before.contains('#') {
+            return json!([]);
+        }
+        let start = before
+            .rfind(|c: char| !is_symbol_char(c) && c != '%')
+            .map_or(0, |found| found + 1);
+        let statement = &before[before.find(':').map_or(0, |colon| colon + 1)..];
+        let range = self.range(line, start, at);
+        let item = |label: &str, kind: u64| json!({ "label": label, "kind": kind, "textEdit": { "range": range, "newText": label } });
+        if statement.trim_start().len() == at - start {
+            let items: Vec<Value> = assembler::mnemonics()
+                .into_iter()
+                .map(|mnemonic| item(mnemonic, COMPLETION_KEYWORD))
+                .collect();
+            return json!(items);
+        }
+        let mut items: Vec<Value> = assembler::register_names()
+            .into_iter()
+            .map(|register| item(register, COMPLETION_VARIABLE))
+            .collect();
+        if !before[start..].starts_with('%') {
+            let mut names: Vec<(&str, u64)> = self
+                .uses
+                .iter()
+                .filter(|symbol| symbol.is_definition() && !is_numeric(symbol))
+                .map(|symbol| match symbol.role {
+                    Role::Constant => (&symbol.name[..], COMPLETION_CONSTANT),
+                    _ => (&symbol.name[..], COMPLETION_REFERENCE),
+                })
+                .collect();
+            if let Some(assembly) = &self.assembly {
+                names.extend(assembly.symbols().iter().map(|symbol| match symbol.kind {
+                    SymbolKind::Constant => (&symbol.name[..], COMPLETION_CONSTANT),
+                    _ => (&symbol.name[..], COMPLETION_REFERENCE),
+                }));
+            }
+            names.sort_unstable();
+            names.dedup_by(|a, b| a.0 == b.0);
+            items.extend(names.into_iter().map(|(name, kind)| item(name, kind)));
+        }
+        json!(items)
+    }
+
+    /// The global labels and constants defined in the document
+    fn symbols(&self, uri: &str) -> Value {
+        let symbols: Vec<Value> = self
+            .uses
+            .iter()
+            .filter(|symbol| symbol.is_definition() && !is_numeric(symbol))
+            .filter(|symbol| !symbol.name.starts_with(".L"))
+            .map(|symbol| {
+                let kind = match self.symbol(&symbol.name).map(|info| info.kind) {
+                    _ if symbol.role == Role::Constant => SYMBOL_CONSTANT,
+                    Some(SymbolKind::Constant) => SYMBOL_CONSTANT,
+                    Some(SymbolKind::Data) => SYMBOL_VARIABLE,
+                    _ => SYMBOL_FUNCTION,
+                };
+                let location = self.location(uri, &symbol.span);
+                json!({ "name": symbol.name, "kind": kind, "location": location })
+            })
+            .collect();
+        json!(symbols)
+    }
+}
+
+/// A language server, serving the documents an editor has open
+/// options: how documents are assembled, set by the client through
+/// the `includePaths` and `defines` initialization options
+struct Server<W: Write> {
+    output: W,
+    options: AssemblerOptions,
+    documents: HashMap<String, Document>,
+}
+
+impl<W: Write> Server<W> {
+    fn notify(&mut self, method: &str, params: Value) -> Result<(), Box<dyn Error>> {
+        let message = json!({ "jsonrpc": "2.0", "method": method, "params": params });
+        write_message(&mut self.output, &message)
+    }
+
+    /// Assembles text and publishes everything that went wrong
+    fn open(&mut self, uri: &str, text: &str) -> Result<(), Box<dyn Error>> {
+        let (document, errors) = Document::new(uri_to_path(uri), text, &self.options);
+        let diagnostics: Vec<Value> = errors.iter().map(|e| document.diagnostic(&**e)).collect();
+        self.documents.insert(uri.to_string(), document);
+        self.notify(
+            "textDocument/publishDiagnostics",
+            json!({ "uri": uri, "diagnostics": diagnostics }),
+        )
+    }
+
+    fn initialize(&mut self, params: &Value) -> Result<Value, Box<dyn Error>> {
+        let options = &params["initializationOptions"];
+        let include_paths = options["includePaths"]
+            .as_array()
+            .map_or(&[][..], |v| &v[..]);
+        for path in include_paths.iter().filter_map(Value::as_str) {
+            self.options.include_paths.push(path.into());
+        }
+        let defines = options["defines"].as_array().map_or(&[][..], |v| &v[..]);
+        for definition in defines.iter().filter_map(Value::as_str) {
+            self.options.define(definition)?;
+        }
+        Ok(json!({
+            "capabilities": {
+                "textDocumentSync": SYNC_FULL,
+                "definitionProvider": true,
+                "referencesProvider": true,
+                "hoverProvider": true,
+                "completionProvider": { "triggerCharacters": ["%"] },
+                "documentSymbolProvider": true,
+            },
+            "serverInfo": { "name": "y86-language-server" },
+        }))
+    }
+
+    /// The document a request is about, along with its uri
+    fn document<'a>(&self, params: &'a Value) -> Result<(&'a str, &Document), Box<dyn Error>> {
+        let uri = params["textDocument"]["uri"].as_str().unwrap_or("");
+        match self.documents.get(uri) {
+            Some(document) => Ok((uri, document)),
+            None => Err(format!("{} is not open", uri).into()),
+        }
+    }
+
+    /// Answers a request, None if the method is not supported
+    fn handle(&mut self, method: &str, params: &Value) -> Result<Option<Value>, Box<dyn Error>> {
+        let position = &params["position"];
+        let res = match method {
+            "initialize" => self.initialize(params)?,
+            "shutdown" => Value::Null,
+            "textDocument/definition" => {
+                let (uri, document) = self.document(params)?;
+                document.goto_definition(uri, position)
+            }
+            "textDocument/references" => {
+                let (uri, document) = self.document(params)?;
+                let declaration = params["context"]["includeDeclaration"].as_bool();
+                document.references(uri, position, declaration.unwrap_or(true))
+            }
+            "textDocument/hover" => self.document(params)?.1.hover(position),
+            "textDocument/completion" => self.document(params)?.1.completion(position),
+            "textDocument/documentSymbol" => {
+                let (uri, document) = self.document(params)?;
+                document.symbols(uri)
+            }
+            _ => return Ok(None),
+        };
+        Ok(Some(res))
+    }
+
+    /// Applies a notification, returning false once the client exits
+    fn apply(&mut self, method: &str, params: &Value) -> Result<bool, Box<dyn Error>> {
+        let uri = params["textDocument"]["uri"].as_str().unwrap_or("");
+        match method {
+            "exit" => return Ok(false),
+            "textDocument/didOpen" => {
+                let text = params["textDocument"]["text"].as_str().unwrap_or("");
+                self.open(uri, text)?;
+            }
+            "textDocument/didChange" => {
+                let changes = params["contentChanges"].as_array();
+                let change = changes.and_then(|changes| changes.last());
+                if let Some(text) = change.and_then(|change| change["text"].as_str()) {
+                    self.open(uri, text)?;
+                }
+            }
+            "textDocument/didClose" => {
+                self.documents.remove(uri);
+                self.notify(
+                    "textDocument/publishDiagnostics",
+                    json!({ "uri": uri, "diagnostics": [] }),
+                )?;
+            }
+            _ => {}
+        }
+        Ok(true)
+    }
+}
+
+/// Runs a language server for Y86 source over input and output until
+/// the client exits
+pub fn serve<R: BufRead, W: Write>(mut input: R, output: W) -> Result<(), Box<dyn Error>> {
+    let mut server = Server {
+        output,
+        options: AssemblerOptions::default(),
+        documents: HashMap::new(),
+    };
+    while let Some(message) = read_message(&mut input)? {
+        let method = message["method"].as_str().unwrap_or("");
+        let params = &message["params"];
+        let id = &message["id"];
+        if id.is_null() {
+            if !server.apply(method, params)? {
+                break;
+            }
+            continue;
+        }
+        let (code, message) = match server.handle(method, params) {
+            Ok(Some(result)) => {
+                let response = json!({ "jsonrpc": "2.0", "id": id, "result": result });
+                write_message(&mut server.output, &response)?;
+                continue;
+            }
+            Ok(None) => (METHOD_NOT_FOUND, format!("Unsupported method {}", method)),
+            Err(e) => (REQUEST_FAILED, e.to_string()),
+        };
+        let error = json!({ "code": code, "message": message });
+        let response = json!({ "jsonrpc": "2.0", "id": id, "error": error });
+        write_message(&mut server.output, &response)?;
+    }
+    Ok(())
+}
+
+/// Runs a language server with messages read from stdin and written to
+/// stdout, the way editors start one
+pub fn serve_stdio() -> Result<(), Box<dyn Error>> {
+    serve(stdin().lock(), stdout())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const URI: &str = "file:///y86%20tests/double.ys";
+
+    const TEXT: &str = "main:
+    irmovq $5, %rax
+    call double
+    halt
+double:
+    addq %rax, %rax
+    ret
+";
+
+    /// Runs a server over messages, each given as its method, params and
+    /// id (None for a notification), and returns every message sent back
+    fn session(messages: &[(&str, Value, Option<u64>)]) -> Vec<Value> {
+        let mut input = vec![];
+        for (method, params, id) in messages {
+            let mut message = json!({ "jsonrpc": "2.0", "method": method, "params": params });
+            if let Some(id) = id {
+                message["id"] = json!(id);
+            }
+            write_message(&mut input, &message).unwrap();
+        }
+        let mut output = vec![];
+        serve(&input[..], &mut output).unwrap();
+        let mut output = &output[..];
+        let mut res = vec![];
+        while let Some(message) = read_message(&mut output).unwrap() {
+            res.push(message);
+        }
+        res
+    }
+
+    fn open(text: &str) -> (&'static str, Value, Option<u64>) {
+        let document = json!({ "uri": URI, "languageId": "y86", "version": 1, "text": text });
+        (
+            "textDocument/didOpen",
+            json!({ "textDocument": document }),
+            None,
+        )
+    }
+
+    fn at(
+        method: &'static str,
+        line: u64,
+        character: u64,
+        id: u64,
+    ) -> (&'static str, Value, Option<u64>) {
+        let params = json!({
+            "textDocument": { "uri": URI },
+            "position": { "line": line, "character": character },
+            "context": { "includeDeclaration": false },
+        });
+        (method, params, Some(id))
+    }
+
+    fn result(messages: &[Value], id: u64) -> &Value {
+        let response = messages.iter().find(|message| message["id"] == id).unwrap();
+        &response["result"]
+    }
+
+    fn range(line: u64, start: u64, end: u64) -> Value {
+        json!({
+            "start": { "line": line, "character": start },
+            "end": { "line": line, "character": end },
+        })
+    }
+
+    #[test]
+    fn converts_between_uris_and_paths() {
+        assert_eq!(uri_to_path(URI), "/y86 tests/double.ys");
+        assert_eq!(path_to_uri("/y86 tests/double.ys"), URI);
+        assert_eq!(uri_to_path("file:///a%2"), "/a%2");
+    }
+
+    #[test]
+    fn publishes_diagnostics_as_the_document_changes() {
+        let change = json!({
+            "textDocument": { "uri": URI, "version": 2 },
+            "contentChanges": [{ "text": "main:\n    jmp nowhere\n" }],
+        });
+        let messages = session(&[
+            ("initialize", json!({}), Some(1)),
+            open(TEXT),
+            ("textDocument/didChange", change, None),
+            (
+                "textDocument/didClose",
+                json!({ "textDocument": { "uri": URI } }),
+                None,
+            ),
+            ("shutdown", Value::Null, Some(2)),
+            ("exit", Value::Null, None),
+            ("shutdown", Value::Null, Some(3)),
+        ]);
+        assert_eq!(messages.len(), 5);
+        let capabilities = &result(&messages, 1)["capabilities"];
+        assert_eq!(capabilities["textDocumentSync"], SYNC_FULL);
+        assert_eq!(capabilities["definitionProvider"], true);
+        let diagnostics: Vec<&Value> = messages
+            .iter()
+            .filter(|message| message["method"] == "textDocument/publishDiagnostics")
+            .map(|message| &message["params"]["diagnostics"])
+            .collect();
+        assert_eq!(diagnostics[0], &json!([]));
+        assert_eq!(
+            diagnostics[1],
+            &json!([{
+                "range": range(1, 0, 15),
+                "severity": SEVERITY_ERROR,
+                "source": "y86",
+                "message": "Undefined symbol nowhere",
+            }])
+        );
+        assert_eq!(diagnostics[2], &json!([]));
+        assert_eq!(result(&messages, 2), &Value::Null);
+    }
+
+    #[test]
+    fn reports_an_instruction_still_being_typed() {
+        let messages = session(&[
+            open("main:\n    mrmovq 8(%rsp\n    halt\n"),
+            at("textDocument/hover", 2, 4, 1),
+        ]);
+        assert_eq!(
+            messages[0]["params"]["diagnostics"],
+            json!([{
+                "range": range(1, 0, 17),
+                "severity": SEVERITY_ERROR,
+                "source": "y86",
+                "message": "Missing operand",
+            }])
+        );
+        // The server is still there to answer
+        assert_eq!(result(&messages, 1), &Value::Null);
+    }
+
+    #[test]
+    fn finds_definitions_and_references() {
+        let messages = session(&[
+            open(TEXT),
+            at("textDocument/definition", 2, 10, 1),
+            at("textDocument/references", 4, 2, 2),
+            at("textDocument/definition", 3, 5, 3),
+            at("textDocument/hover", 2, 10, 4),
+            (
+                "textDocument/documentSymbol",
+                json!({ "textDocument": { "uri": URI } }),
+                Some(5),
+            ),
+        ]);
+        assert_eq!(
+            result(&messages, 1),
+            &json!({ "uri": URI, "range": range(4, 0, 6) })
+        );
+        assert_eq!(
+            result(&messages, 2),
+            &json!([{ "uri": URI, "range": range(2, 9, 15) }])
+        );
+        assert_eq!(result(&messages, 3), &Value::Null);
+        let hover = result(&messages, 4)["contents"]["value"].as_str().unwrap();
+        assert_eq!(
+            hover.lines().collect::<Vec<&str>>(),
+            vec!["double = 0x14 (code)", "0x00a: 80 14 00 00 00 00 00 00 00"]
+        );
+        let names: Vec<&Value> = result(&messages, 5)
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|symbol| &symbol["name"])
+            .collect();
+        assert_eq!(names, vec!["main", "double"]);
+    }
+
+    #[test]
+    fn completes_mnemonics_registers_and_symbols() {
+        let messages = session(&[
+            open("main:\n    irm\n    addq %r\n    jmp m\n"),
+            at("textDocument/completion", 1, 7, 1),
+            at("textDocument/completion", 2, 11, 2),
+            at("textDocument/completion", 3, 9, 3),
+        ]);
+        let labels = |id: u64| -> Vec<String> {
+            result(&messages, id)
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|item| item["label"].as_str().unwrap().to_string())
+                .collect()
+        };
+        assert!(labels(1).contains(&"irmovq".to_string()));
+        assert!(!labels(1).contains(&"%rax".to_string()));
+        assert!(labels(2).contains(&"%rax".to_string()));
+        assert!(!labels(2).contains(&"main".to_string()));
+        assert!(labels(3).contains(&"main".to_string()));
+        let first = &result(&messages, 1)[0]["textEdit"]["range"];
+        assert_eq!(first, &range(1, 4, 7));
+    }
+
+    #[test]
+    fn answers_requests_it_cannot_serve_with_errors() {
+        let messages = session(&[
+            ("textDocument/formatting", json!({}), Some(1)),
+            at("textDocument/hover", 0, 0, 2),
+        ]);
+        assert_eq!(messages[0]["error"]["code"], METHOD_NOT_FOUND);
+        assert_eq!(
+            messages[0]["error"]["message"],
+            "Unsupported method textDocument/formatting"
+        );
+        assert_eq!(messages[1]["error"]["code"], REQUEST_FAILED);
+        assert_eq!(
+            messages[1]["error"]["message"],
+            format!("{} is not open", URI)
+        );
+    }
+}
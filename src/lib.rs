@@ -8,4 +8,34 @@ pub mod assembler;
 pub mod executer;
 
 ///Simple number parser, can parse hex and decimal values
-pub mod number_parser;
\ No newline at end of file
+pub mod number_parser;
+
+/// Integer expression parser and evaluator, shared by the assembler
+/// directives and the debugger
+pub mod expression;
+
+/// Relocatable object files, as produced by the assembler and
+/// consumed by the linker
+pub mod object;
+
+/// Links relocatable objects into a single Y86 image
+pub mod linker;
+
+/// Where the sections of a program are laid out, shared by the
+/// assembler, the linker and the executer
+pub mod section_map;
+
+/// Symbol tables, written next to the machine code so the debugger
+/// can refer to labels and constants by name
+pub mod symbol_table;
+
+/// Maps addresses back to the source lines that produced them
+pub mod line_table;
+
+/// Messages framed by a Content-Length header, the transport shared by
+/// the debug adapter and the language server
+pub mod framing;
+
+/// Language server for Y86 source, giving editors diagnostics,
+/// navigation, hover and completion
+pub mod language_server;
@@ -0,0 +1,65 @@
+use crate::number_parser;
+use std::error::Error;
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::{self, BufRead};
+
+/// The source line that emitted size bytes at address
+/// text: the line as written, so it can be shown without the source
+#[derive(Clone, Debug)]
+pub struct LineInfo {
+    pub address: u64,
+    pub size: u64,
+    pub file: String,
+    pub line: usize,
+    pub text: String,
+}
+
+impl LineInfo {
+    /// Whether address falls inside the bytes emitted by the line
+    pub fn contains(&self, address: u64) -> bool {
+        address >= self.address && address - self.address < self.size
+    }
+}
+
+/// Writes a line table, one line per entry with tab separated fields:
+/// ADDRESS SIZE FILE LINE TEXT
+pub fn save_file(lines: &[LineInfo], file_name: String) -> Result<(), Box<dyn Error>> {
+    let mut file = File::create(file_name)?;
+    for line in lines {
+        writeln!(
+            file,
+            "0x{:x}\t0x{:x}\t{}\t{}\t{}",
+            line.address,
+            line.size,
+            line.file,
+            line.line,
+            line.text.replace('\t', "    ")
+        )?;
+    }
+    Ok(())
+}
+
+/// Reads a line table written by save_file
+pub fn from_file(file_name: String) -> Result<Vec<LineInfo>, Box<dyn Error>> {
+    let file = File::open(file_name)?;
+    let mut res = vec![];
+    for line in io::BufReader::new(file).lines() {
+        let line = line?;
+        if line.is_empty() {
+            continue;
+        }
+        let fields: Vec<&str> = line.splitn(5, '\t').collect();
+        match fields.as_slice() {
+            [address, size, file, number, text] => res.push(LineInfo {
+                address: number_parser::parse_num(address)?,
+                size: number_parser::parse_num(size)?,
+                file: file.to_string(),
+                line: number.parse::<usize>()?,
+                text: text.to_string(),
+            }),
+            _ => return Err(format!("Invalid line table line \"{}\"", line).into()),
+        }
+    }
+    Ok(res)
+}
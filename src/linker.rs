@@ -0,0 +1,493 @@
+use crate::assembler::Y86Assembler;
+use crate::object::{Binding, ObjectFile, Target};
+use crate::section_map::{self, SectionInfo};
+use crate::symbol_table::{SymbolInfo, SymbolKind};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+
+/// Combines relocatable objects into a single Y86 image
+/// objects: each object along with the address it should be placed at,
+/// None merges its sections with the same-named sections of the other
+/// objects without a base
+/// placements: explicit addresses for the merged sections
+pub struct Linker {
+    objects: Vec<(ObjectFile, Option<u64>)>,
+    placements: Vec<(String, u64)>,
+}
+
+/// Every problem found while linking, reported together
+#[derive(Debug)]
+pub struct LinkError {
+    errors: Vec<String>,
+}
+
+impl Error for LinkError {}
+
+impl Display for LinkError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.errors.join("\n"))
+    }
+}
+
+// Sections merged from several objects start on a quad boundary
+const ALIGNMENT: u64 = 8;
+
+impl Default for Linker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Linker {
+    /// Creates a linker with no objects
+    pub fn new() -> Self {
+        Linker {
+            objects: vec![],
+            placements: vec![],
+        }
+    }
+
+    /// Adds an object to the link
+    /// object: the ObjectFile to add
+    /// base: the address to place the object at, or None to merge its
+    /// sections with those of the other objects
+    pub fn add_object(&mut self, object: ObjectFile, base: Option<u64>) {
+        self.objects.push((object, base));
+    }
+
+    /// Places a merged section at a fixed address, written as
+    /// NAME=ADDRESS such as `.data=0x400`
+    pub fn place(&mut self, placement: &str) -> Result<(), Box<dyn Error>> {
+        self.placements
+            .push(section_map::parse_placement(placement)?);
+        Ok(())
+    }
+
+    /// Lays out every object, resolves symbols and applies relocations
+    /// Fails listing every invalid object, or every undefined or
+    /// duplicate symbol and every overlapping object
+    pub fn link(&self) -> Result<Y86Assembler, Box<dyn Error>> {
+        let mut errors: Vec<String> = self
+            .objects
+            .iter()
+            .filter_map(|(object, _)| {
+                let error = object.validate().err()?;
+                Some(format!("{}: {}", object.name, error))
+            })
+            .collect();
+        // The rest indexes sections through the objects' own records
+        if !errors.is_empty() {
+            return Err(Box::new(LinkError { errors }));
+        }
+        let (addresses, sections) = self.lay_out();
+        let globals = self.global_symbols(&addresses, &mut errors);
+        self.check_overlaps(&addresses, &mut errors);
+        let size = self
+            .objects
+            .iter()
+            .zip(&addresses)
+            .flat_map(|((object, _), bases)| {
+                object
+                    .sections
+                    .iter()
+                    .zip(bases)
+                    .map(|(s, base)| base + s.size)
+            })
+            .max()
+            .unwrap_or(0);
+        let mut image = vec![0; size as usize];
+        for ((object, _), bases) in self.objects.iter().zip(&addresses) {
+            for (section, &base) in object.sections.iter().zip(bases) {
+                let start = base as usize;
+                image[start..start + section.bytes.len()].copy_from_slice(&section.bytes);
+            }
+            for relocation in &object.relocations {
+                let value = match &relocation.target {
+                    Target::Section(index) => bases[*index],
+                    Target::Symbol(name) => match lookup(object, bases, &globals, name) {
+                        Some(address) => address,
+                        None => {
+                            let error = format!("{}: undefined symbol {}", object.name, name);
+                            if !errors.contains(&error) {
+                                errors.push(error);
+                            }
+                            continue;
+                        }
+                    },
+                };
+                let at = (bases[relocation.section] + relocation.offset) as usize;
+                let value = value.wrapping_add(relocation.addend);
+                for i in 0..8 {
+                    image[at + i] = (value >> (i * 8)) as u8;
+                }
+            }
+        }
+        if !errors.is_empty() {
+            return Err(Box::new(LinkError { errors }));
+        }
+        let symbols = self.symbol_table(&addresses);
+        Ok(Y86Assembler::from_bytes(image, sections, symbols))
+    }
+
+    /// Returns the address of every section of every object, and the
+    /// sections of the linked program
+    fn lay_out(&self) -> (Vec<Vec<u64>>, Vec<SectionInfo>) {
+        let mut res = vec![];
+        let mut merged: Vec<SectionInfo> = vec![];
+        let mut fixed = vec![];
+        for (object, base) in &self.objects {
+            let mut bases = vec![];
+            for section in &object.sections {
+                let info = SectionInfo {
+                    name: section.name.clone(),
+                    start: 0,
+                    size: section.size,
+                    writable: section.writable,
+                    executable: section.executable,
+                };
+                match base {
+                    Some(base) => {
+                        let start = base
+                            + object.sections[..bases.len()]
+                                .iter()
+                                .map(|s| s.size)
+                                .sum::<u64>();
+                        bases.push(start);
+                        fixed.push(SectionInfo { start, ..info });
+                    }
+                    None => {
+                        let group = match merged.iter().position(|s| s.name == section.name) {
+                            Some(group) => group,
+                            None => {
+                                merged.push(SectionInfo { size: 0, ..info });
+                                merged.len() - 1
+                            }
+                        };
+                        let offset = merged[group].size.div_ceil(ALIGNMENT) * ALIGNMENT;
+                        merged[group].size = offset + section.size;
+                        bases.push(offset);
+                    }
+                }
+            }
+            res.push(bases);
+        }
+        let sizes: Vec<(&str, u64)> = merged.iter().map(|s| (&s.name[..], s.size)).collect();
+        let starts = section_map::lay_out(&sizes, &self.placements);
+        for ((object, base), bases) in self.objects.iter().zip(res.iter_mut()) {
+            if base.is_some() {
+                continue;
+            }
+            for (section, offset) in object.sections.iter().zip(bases.iter_mut()) {
+                let group = merged.iter().position(|s| s.name == section.name).unwrap();
+                *offset += starts[group];
+            }
+        }
+        for (section, start) in merged.iter_mut().zip(starts) {
+            section.start = start;
+        }
+        merged.append(&mut fixed);
+        merged.retain(|section| section.size > 0);
+        merged.sort_by_key(|section| section.start);
+        (res, merged)
+    }
+
+    fn global_symbols<'a>(
+        &'a self,
+        addresses: &[Vec<u64>],
+        errors: &mut Vec<String>,
+    ) -> HashMap<&'a str, (u64, &'a str)> {
+        let mut res: HashMap<&str, (u64, &str)> = HashMap::new();
+        for ((object, _), bases) in self.objects.iter().zip(addresses) {
+            let defined = object
+                .symbols
+                .iter()
+                .filter(|symbol| symbol.binding == Binding::Global);
+            for symbol in defined {
+                let section = match symbol.section {
+                    Some(section) => section,
+                    None => continue,
+                };
+                let address = bases[section] + symbol.offset;
+                if let Some((_, previous)) = res.insert(&symbol.name, (address, &object.name)) {
+                    errors.push(format!(
+                        "duplicate symbol {} defined in {} and {}",
+                        symbol.name, previous, object.name
+                    ));
+                }
+            }
+        }
+        res
+    }
+
+    /// Every symbol defined by the objects, sorted by address
+    /// Objects do not record where their symbols were defined
+    fn symbol_table(&self, addresses: &[Vec<u64>]) -> Vec<SymbolInfo> {
+        let mut res = vec![];
+        for ((object, _), bases) in self.objects.iter().zip(addresses) {
+            for symbol in &object.symbols {
+                let section = match symbol.section {
+                    Some(section) => section,
+                    None => continue,
+                };
+                let kind = if object.sections[section].executable {
+                    SymbolKind::Code
+                } else {
+                    SymbolKind::Data
+                };
+                res.push(SymbolInfo {
+                    name: symbol.name.clone(),
+                    address: bases[section] + symbol.offset,
+                    kind,
+                    file: None,
+                    line: None,
+                });
+            }
+        }
+        res.sort_by(|a, b| (a.address, &a.name).cmp(&(b.address, &b.name)));
+        res
+    }
+
+    fn check_overlaps(&self, addresses: &[Vec<u64>], errors: &mut Vec<String>) {
+        let mut placed: Vec<(u64, u64, &str, &str)> = vec![];
+        for ((object, _), bases) in self.objects.iter().zip(addresses) {
+            for (section, &base) in object.sections.iter().zip(bases) {
+                if section.size > 0 {
+                    placed.push((base, base + section.size, &object.name, &section.name));
+                }
+            }
+        }
+        placed.sort();
+        // Compared against the section reaching furthest so far, which
+        // is not always the one just before
+        let mut furthest: Option<(u64, u64, &str, &str)> = None;
+        for (start, end, object, section) in placed {
+            match furthest {
+                Some((prev_start, prev_end, prev_object, prev_section)) if start < prev_end => {
+                    errors.push(format!(
+                        "{} {} (0x{:x}-0x{:x}) overlaps {} {} (0x{:x}-0x{:x})",
+                        prev_object,
+                        prev_section,
+                        prev_start,
+                        prev_end - 1,
+                        object,
+                        section,
+                        start,
+                        end - 1
+                    ));
+                    if end > prev_end {
+                        furthest = Some((start, end, object, section));
+                    }
+                }
+                _ => furthest = Some((start, end, object, section)),
+            }
+        }
+    }
+}
+
+/// Finds name, preferring a symbol of object itself over a global one
+fn lookup(
+    object: &ObjectFile,
+    bases: &[u64],
+    globals: &HashMap<&str, (u64, &str)>,
+    name: &str,
+) -> Option<u64> {
+    let own = object
+        .symbols
+        .iter()
+        .find(|symbol| symbol.name == name && symbol.section.is_some());
+    match own {
+        Some(symbol) => Some(bases[symbol.section.unwrap()] + symbol.offset),
+        None => globals.get(name).map(|&(address, _)| address),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::object::{Relocation, Section, Symbol};
+
+    fn section(name: &str, bytes: Vec<u8>) -> Section {
+        Section {
+            name: name.to_string(),
+            size: bytes.len() as u64,
+            bytes,
+            writable: name != ".text",
+            executable: name == ".text",
+        }
+    }
+
+    fn symbol(name: &str, section: Option<usize>, offset: u64) -> Symbol {
+        Symbol {
+            name: name.to_string(),
+            binding: Binding::Global,
+            section,
+            offset,
+        }
+    }
+
+    fn object(name: &str, sections: Vec<Section>) -> ObjectFile {
+        ObjectFile {
+            name: name.to_string(),
+            sections,
+            symbols: vec![],
+            relocations: vec![],
+        }
+    }
+
+    fn errors(linker: &Linker) -> Vec<String> {
+        match linker.link() {
+            Ok(_) => vec![],
+            Err(e) => e.to_string().lines().map(str::to_string).collect(),
+        }
+    }
+
+    #[test]
+    fn resolves_symbols_across_objects() {
+        // call value; halt
+        let mut main = object(
+            "main.o",
+            vec![section(".text", vec![0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0x00])],
+        );
+        main.symbols.push(symbol("value", None, 0));
+        main.relocations.push(Relocation {
+            section: 0,
+            offset: 1,
+            target: Target::Symbol("value".to_string()),
+            addend: 0,
+        });
+        let mut data = object(
+            "data.o",
+            vec![section(".data", vec![0x2a, 0, 0, 0, 0, 0, 0, 0])],
+        );
+        data.symbols.push(symbol("value", Some(0), 0));
+        let mut linker = Linker::new();
+        linker.add_object(main, None);
+        linker.add_object(data, None);
+        let linked = linker.link().unwrap();
+        // .data follows .text on a quad boundary
+        let bytes = linked.bytes();
+        assert_eq!(bytes[1], 0x10);
+        assert_eq!(bytes[0x10], 0x2a);
+        let value = linked.symbols().iter().find(|s| s.name == "value").unwrap();
+        assert_eq!(value.address, 0x10);
+    }
+
+    #[test]
+    fn applies_section_relocations_with_addend() {
+        let mut main = object(
+            "main.o",
+            vec![section(".text", vec![0; 10]), section(".data", vec![0; 16])],
+        );
+        main.relocations.push(Relocation {
+            section: 0,
+            offset: 2,
+            target: Target::Section(1),
+            addend: 8,
+        });
+        let mut linker = Linker::new();
+        linker.add_object(main, None);
+        let linked = linker.link().unwrap();
+        assert_eq!(linked.bytes()[2], 0x10 + 8);
+    }
+
+    #[test]
+    fn reports_undefined_and_duplicate_symbols() {
+        let mut first = object("first.o", vec![section(".text", vec![0; 10])]);
+        first.symbols.push(symbol("main", Some(0), 0));
+        first.relocations.push(Relocation {
+            section: 0,
+            offset: 2,
+            target: Target::Symbol("missing".to_string()),
+            addend: 0,
+        });
+        let mut second = object("second.o", vec![section(".text", vec![0; 1])]);
+        second.symbols.push(symbol("main", Some(0), 0));
+        let mut linker = Linker::new();
+        linker.add_object(first, None);
+        linker.add_object(second, None);
+        let errors = errors(&linker);
+        assert_eq!(errors.len(), 2);
+        assert!(
+            errors.contains(&"duplicate symbol main defined in first.o and second.o".to_string())
+        );
+        assert!(errors.contains(&"first.o: undefined symbol missing".to_string()));
+    }
+
+    #[test]
+    fn reports_overlap_with_a_section_that_is_not_the_previous_one() {
+        let mut linker = Linker::new();
+        linker.add_object(
+            object("big.o", vec![section(".text", vec![0; 0x100])]),
+            Some(0),
+        );
+        linker.add_object(
+            object("small.o", vec![section(".text", vec![0; 8])]),
+            Some(0x10),
+        );
+        linker.add_object(
+            object("late.o", vec![section(".text", vec![0; 8])]),
+            Some(0x80),
+        );
+        let errors = errors(&linker);
+        assert_eq!(
+            errors,
+            vec![
+                "big.o .text (0x0-0xff) overlaps small.o .text (0x10-0x17)",
+                "big.o .text (0x0-0xff) overlaps late.o .text (0x80-0x87)",
+            ]
+        );
+    }
+
+    #[test]
+    fn accepts_objects_placed_side_by_side() {
+        let mut linker = Linker::new();
+        linker.add_object(
+            object("a.o", vec![section(".text", vec![1; 0x10])]),
+            Some(0),
+        );
+        linker.add_object(
+            object("b.o", vec![section(".text", vec![2; 0x10])]),
+            Some(0x10),
+        );
+        let linked = linker.link().unwrap();
+        assert_eq!(linked.bytes()[0xf], 1);
+        assert_eq!(linked.bytes()[0x10], 2);
+    }
+
+    #[test]
+    fn reports_objects_that_do_not_hold_together() {
+        let mut long = section(".data", vec![0; 16]);
+        long.size = 8;
+        let mut main = object("main.o", vec![section(".text", vec![0; 10])]);
+        main.relocations.push(Relocation {
+            section: 0,
+            offset: u64::MAX - 4,
+            target: Target::Section(0),
+            addend: 0,
+        });
+        let mut symbols = object("symbols.o", vec![section(".text", vec![0; 10])]);
+        symbols.symbols.push(symbol("nowhere", Some(1), 0));
+        let mut relocations = object("relocations.o", vec![section(".text", vec![0; 10])]);
+        relocations.relocations.push(Relocation {
+            section: 0,
+            offset: 0,
+            target: Target::Section(3),
+            addend: 0,
+        });
+        let mut linker = Linker::new();
+        linker.add_object(object("long.o", vec![long]), None);
+        linker.add_object(main, None);
+        linker.add_object(symbols, None);
+        linker.add_object(relocations, None);
+        assert_eq!(
+            errors(&linker),
+            vec![
+                "long.o: Invalid object file: data longer than its section",
+                "main.o: Invalid object file: relocation outside of its section",
+                "symbols.o: Invalid object file: section index out of range",
+                "relocations.o: Invalid object file: section index out of range",
+            ]
+        );
+    }
+}
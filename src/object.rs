@@ -0,0 +1,401 @@
+use crate::number_parser;
+use crate::section_map::SectionInfo;
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::{self, BufRead};
+
+const MAGIC: &str = "y86-object 1";
+
+/// A relocatable Y86 object, the output of assembling one module
+/// name: where the object came from, used in diagnostics
+/// sections: the code and data, each assembled as if it started at 0
+/// symbols: the labels the object defines, and the external ones it uses
+/// relocations: the places that must be patched once addresses are known
+pub struct ObjectFile {
+    pub name: String,
+    pub sections: Vec<Section>,
+    pub symbols: Vec<Symbol>,
+    pub relocations: Vec<Relocation>,
+}
+
+/// A named, contiguous block of bytes
+/// size may be larger than bytes, the rest is zero-filled
+pub struct Section {
+    pub name: String,
+    pub size: u64,
+    pub bytes: Vec<u8>,
+    pub writable: bool,
+    pub executable: bool,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Binding {
+    Local,
+    Global,
+}
+
+/// A symbol defined at offset in section, or an external one
+/// (section is None) that must be defined by another object
+pub struct Symbol {
+    pub name: String,
+    pub binding: Binding,
+    pub section: Option<usize>,
+    pub offset: u64,
+}
+
+/// What a relocation resolves to
+pub enum Target {
+    /// The address of a symbol, looked up by name
+    Symbol(String),
+    /// The address a section of this object is placed at
+    Section(usize),
+}
+
+/// Patch the little-endian quad at offset in section with the
+/// address of target plus addend
+pub struct Relocation {
+    pub section: usize,
+    pub offset: u64,
+    pub target: Target,
+    pub addend: u64,
+}
+
+#[derive(Debug)]
+struct InvalidObjectError(String);
+
+impl Error for InvalidObjectError {}
+
+impl Display for InvalidObjectError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid object file: {}", self.0)
+    }
+}
+
+fn invalid(message: &str) -> Box<dyn Error> {
+    Box::new(InvalidObjectError(message.to_string()))
+}
+
+impl ObjectFile {
+    /// Reads an object file previously written by save_file
+    pub fn from_file(file_name: String) -> Result<Self, Box<dyn Error>> {
+        let file = File::open(&file_name)?;
+        let mut lines = io::BufReader::new(file).lines();
+        match lines.next() {
+            Some(line) if line.as_ref().is_ok_and(|line| line == MAGIC) => {}
+            _ => return Err(invalid("missing header")),
+        }
+        let mut object = ObjectFile {
+            name: file_name,
+            sections: vec![],
+            symbols: vec![],
+            relocations: vec![],
+        };
+        for line in lines {
+            object.parse_line(&line?)?;
+        }
+        object.validate()?;
+        Ok(object)
+    }
+
+    /// Checks that every section index, data length and relocation
+    /// offset is within range, so the object can be linked without
+    /// indexing outside of it
+    pub fn validate(&self) -> Result<(), Box<dyn Error>> {
+        let in_range = |index: usize| index < self.sections.len();
+        if self
+            .sections
+            .iter()
+            .any(|section| section.bytes.len() as u64 > section.size)
+        {
+            return Err(invalid("data longer than its section"));
+        }
+        if self
+            .symbols
+            .iter()
+            .any(|symbol| symbol.section.is_some_and(|index| !in_range(index)))
+        {
+            return Err(invalid("section index out of range"));
+        }
+        for relocation in &self.relocations {
+            if let Target::Section(index) = relocation.target {
+                if !in_range(index) {
+                    return Err(invalid("section index out of range"));
+                }
+            }
+            if !in_range(relocation.section) {
+                return Err(invalid("section index out of range"));
+            }
+            match relocation.offset.checked_add(8) {
+                Some(end) if end <= self.sections[relocation.section].size => (),
+                _ => return Err(invalid("relocation outside of its section")),
+            }
+        }
+        Ok(())
+    }
+
+    /// Saves the object into a file specified by file_name
+    /// The format is line based text, one record per line
+    pub fn save_file(&self, file_name: String) -> Result<(), Box<dyn Error>> {
+        let mut file = File::create(file_name)?;
+        writeln!(file, "{}", MAGIC)?;
+        writeln!(file, "name {}", self.name)?;
+        for section in &self.sections {
+            let flags = SectionInfo {
+                name: section.name.clone(),
+                start: 0,
+                size: section.size,
+                writable: section.writable,
+                executable: section.executable,
+            }
+            .flags();
+            writeln!(
+                file,
+                "section {} 0x{:x} {}",
+                section.name, section.size, flags
+            )?;
+            let hex: String = section.bytes.iter().map(|b| format!("{:02x}", b)).collect();
+            writeln!(file, "data {}", hex)?;
+        }
+        for symbol in &self.symbols {
+            let binding = match symbol.binding {
+                Binding::Local => "local",
+                Binding::Global => "global",
+            };
+            let section = match symbol.section {
+                Some(index) => index.to_string(),
+                None => "-".to_string(),
+            };
+            writeln!(
+                file,
+                "symbol {} {} {} 0x{:x}",
+                binding, symbol.name, section, symbol.offset
+            )?;
+        }
+        for relocation in &self.relocations {
+            let target = match &relocation.target {
+                Target::Symbol(name) => format!("symbol {}", name),
+                Target::Section(index) => format!("section {}", index),
+            };
+            writeln!(
+                file,
+                "reloc {} 0x{:x} {} 0x{:x}",
+                relocation.section, relocation.offset, target, relocation.addend
+            )?;
+        }
+        Ok(())
+    }
+
+    fn parse_line(&mut self, line: &str) -> Result<(), Box<dyn Error>> {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        match fields.as_slice() {
+            [] => {}
+            ["name", name] => self.name = name.to_string(),
+            ["section", name, size, flags] => self.sections.push(Section {
+                name: name.to_string(),
+                size: number_parser::parse_num(size)?,
+                bytes: vec![],
+                writable: flags.contains('w'),
+                executable: flags.contains('x'),
+            }),
+            ["data", rest @ ..] => {
+                let section = self
+                    .sections
+                    .last_mut()
+                    .ok_or_else(|| invalid("data before section"))?;
+                let hex = rest.concat();
+                section.bytes = (0..hex.len())
+                    .step_by(2)
+                    .map(|i| u8::from_str_radix(hex.get(i..i + 2).unwrap_or("?"), 16))
+                    .collect::<Result<_, _>>()?;
+                if section.bytes.len() as u64 > section.size {
+                    return Err(invalid("data longer than its section"));
+                }
+            }
+            ["symbol", binding, name, section, offset] => {
+                let binding = match *binding {
+                    "local" => Binding::Local,
+                    "global" => Binding::Global,
+                    _ => return Err(invalid("unknown symbol binding")),
+                };
+                let section = match *section {
+                    "-" => None,
+                    index => Some(self.section_index(index)?),
+                };
+                self.symbols.push(Symbol {
+                    name: name.to_string(),
+                    binding,
+                    section,
+                    offset: number_parser::parse_num(offset)?,
+                });
+            }
+            ["reloc", section, offset, kind, target, addend] => {
+                let target = match *kind {
+                    "symbol" => Target::Symbol(target.to_string()),
+                    "section" => Target::Section(self.section_index(target)?),
+                    _ => return Err(invalid("unknown relocation target")),
+                };
+                let section = self.section_index(section)?;
+                let offset = number_parser::parse_num(offset)?;
+                match offset.checked_add(8) {
+                    Some(end) if end <= self.sections[section].size => (),
+                    _ => return Err(invalid("relocation outside of its section")),
+                }
+                self.relocations.push(Relocation {
+                    section,
+                    offset,
+                    target,
+                    addend: number_parser::parse_num(addend)?,
+                });
+            }
+            _ => return Err(invalid(line)),
+        }
+        Ok(())
+    }
+
+    fn section_index(&self, index: &str) -> Result<usize, Box<dyn Error>> {
+        match index.parse::<usize>() {
+            Ok(index) if index < self.sections.len() => Ok(index),
+            _ => Err(invalid("section index out of range")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> ObjectFile {
+        ObjectFile {
+            name: "sample.o".to_string(),
+            sections: vec![
+                Section {
+                    name: ".text".to_string(),
+                    size: 10,
+                    bytes: vec![0x30, 0xf0, 0, 0, 0, 0, 0, 0, 0, 0],
+                    writable: false,
+                    executable: true,
+                },
+                Section {
+                    name: ".bss".to_string(),
+                    size: 0x20,
+                    bytes: vec![],
+                    writable: true,
+                    executable: false,
+                },
+            ],
+            symbols: vec![
+                Symbol {
+                    name: "main".to_string(),
+                    binding: Binding::Global,
+                    section: Some(0),
+                    offset: 0,
+                },
+                Symbol {
+                    name: "buffer".to_string(),
+                    binding: Binding::Local,
+                    section: Some(1),
+                    offset: 0x8,
+                },
+                Symbol {
+                    name: "extern".to_string(),
+                    binding: Binding::Global,
+                    section: None,
+                    offset: 0,
+                },
+            ],
+            relocations: vec![
+                Relocation {
+                    section: 0,
+                    offset: 2,
+                    target: Target::Section(1),
+                    addend: 0x8,
+                },
+                Relocation {
+                    section: 0,
+                    offset: 2,
+                    target: Target::Symbol("extern".to_string()),
+                    addend: 0,
+                },
+            ],
+        }
+    }
+
+    fn temp_file(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("y86-{}-{}", std::process::id(), name));
+        path.display().to_string()
+    }
+
+    #[test]
+    fn save_and_read_back() {
+        let file_name = temp_file("round-trip.o");
+        let object = sample();
+        object.save_file(file_name.clone()).unwrap();
+        let read = ObjectFile::from_file(file_name.clone()).unwrap();
+        std::fs::remove_file(&file_name).unwrap();
+        assert_eq!(read.name, object.name);
+        assert_eq!(read.sections.len(), 2);
+        for (read, section) in read.sections.iter().zip(&object.sections) {
+            assert_eq!(read.name, section.name);
+            assert_eq!(read.size, section.size);
+            assert_eq!(read.bytes, section.bytes);
+            assert_eq!(read.writable, section.writable);
+            assert_eq!(read.executable, section.executable);
+        }
+        assert_eq!(read.symbols.len(), 3);
+        for (read, symbol) in read.symbols.iter().zip(&object.symbols) {
+            assert_eq!(read.name, symbol.name);
+            assert_eq!(read.binding, symbol.binding);
+            assert_eq!(read.section, symbol.section);
+            assert_eq!(read.offset, symbol.offset);
+        }
+        assert_eq!(read.relocations.len(), 2);
+        assert!(matches!(read.relocations[0].target, Target::Section(1)));
+        assert_eq!(read.relocations[0].addend, 0x8);
+        assert!(matches!(&read.relocations[1].target, Target::Symbol(name) if name == "extern"));
+    }
+
+    #[test]
+    fn rejects_a_file_without_the_header() {
+        let file_name = temp_file("no-header.o");
+        std::fs::write(&file_name, "name x\n").unwrap();
+        let res = ObjectFile::from_file(file_name.clone());
+        std::fs::remove_file(&file_name).unwrap();
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn rejects_data_longer_than_its_section() {
+        let file_name = temp_file("long-data.o");
+        let text = format!("{}\nsection .data 0x2 rw\ndata 010203\n", MAGIC);
+        std::fs::write(&file_name, text).unwrap();
+        let res = ObjectFile::from_file(file_name.clone());
+        std::fs::remove_file(&file_name).unwrap();
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn rejects_a_relocation_outside_of_its_section() {
+        let file_name = temp_file("bad-reloc.o");
+        for offset in ["0x3", "0xfffffffffffffffc"] {
+            let text = format!(
+                "{}\nsection .text 0xa rx\nreloc 0 {} section 0 0x0\n",
+                MAGIC, offset
+            );
+            std::fs::write(&file_name, text).unwrap();
+            let res = ObjectFile::from_file(file_name.clone());
+            assert!(res.is_err());
+        }
+        std::fs::remove_file(&file_name).unwrap();
+    }
+
+    #[test]
+    fn rejects_an_out_of_range_section() {
+        let file_name = temp_file("bad-section.o");
+        std::fs::write(&file_name, format!("{}\nsymbol local x 3 0x0\n", MAGIC)).unwrap();
+        let res = ObjectFile::from_file(file_name.clone());
+        std::fs::remove_file(&file_name).unwrap();
+        assert!(res.is_err());
+    }
+}
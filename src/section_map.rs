@@ -0,0 +1,130 @@
+use crate::number_parser;
+use std::error::Error;
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::{self, BufRead};
+
+/// Where a section of a program ended up in memory, and what the
+/// program may do with it
+#[derive(Clone, Debug)]
+pub struct SectionInfo {
+    pub name: String,
+    pub start: u64,
+    pub size: u64,
+    pub writable: bool,
+    pub executable: bool,
+}
+
+impl SectionInfo {
+    /// Whether address falls inside the section
+    pub fn contains(&self, address: u64) -> bool {
+        address >= self.start && address - self.start < self.size
+    }
+
+    /// The usual permissions for a section called name: `.text` is
+    /// executable, everything else is data
+    /// Everything is writable, as Y86 programs keep their data and stack
+    /// in `.text`, `.section .text, "rx"` makes it read only
+    pub fn default_flags(name: &str) -> (bool, bool) {
+        (true, name == ".text")
+    }
+
+    /// The permissions written as "r", "w" and "x" characters
+    pub fn flags(&self) -> String {
+        let mut res = "r".to_string();
+        if self.writable {
+            res.push('w');
+        }
+        if self.executable {
+            res.push('x');
+        }
+        res
+    }
+}
+
+// Sections that are not explicitly placed start on a quad boundary
+const ALIGNMENT: u64 = 8;
+
+/// The order sections are laid out in when nothing else is said:
+/// code, then data, then zero-initialised data, then everything else
+/// in the order given
+fn rank(name: &str) -> usize {
+    match name {
+        ".text" => 0,
+        ".data" => 1,
+        ".bss" => 2,
+        _ => 3,
+    }
+}
+
+/// Decides the address of every section
+/// sections: the name and size of each section, in order of appearance
+/// placements: explicit addresses for some of the sections
+/// Sections without a placement follow the one laid out before them
+pub fn lay_out(sections: &[(&str, u64)], placements: &[(String, u64)]) -> Vec<u64> {
+    let mut order: Vec<usize> = (0..sections.len()).collect();
+    order.sort_by_key(|&index| rank(sections[index].0));
+    let mut res = vec![0; sections.len()];
+    let mut next: u64 = 0;
+    for index in order {
+        let (name, size) = sections[index];
+        let start = match placements.iter().find(|(placed, _)| placed == name) {
+            Some(&(_, address)) => address,
+            None => next.div_ceil(ALIGNMENT) * ALIGNMENT,
+        };
+        res[index] = start;
+        next = start + size;
+    }
+    res
+}
+
+/// Parses a placement written as NAME=ADDRESS, such as `.data=0x400`
+pub fn parse_placement(placement: &str) -> Result<(String, u64), Box<dyn Error>> {
+    let mut split = placement.splitn(2, '=');
+    let name = split.next().unwrap().trim();
+    match split.next() {
+        Some(address) if !name.is_empty() => {
+            Ok((name.to_string(), number_parser::parse_num(address.trim())?))
+        }
+        _ => Err(format!("Invalid section placement \"{}\"", placement).into()),
+    }
+}
+
+/// Writes a section map, one section per line:
+/// NAME START SIZE FLAGS
+pub fn save_file(sections: &[SectionInfo], file_name: String) -> Result<(), Box<dyn Error>> {
+    let mut file = File::create(file_name)?;
+    for section in sections {
+        writeln!(
+            file,
+            "{} 0x{:x} 0x{:x} {}",
+            section.name,
+            section.start,
+            section.size,
+            section.flags()
+        )?;
+    }
+    Ok(())
+}
+
+/// Reads a section map written by save_file
+pub fn from_file(file_name: String) -> Result<Vec<SectionInfo>, Box<dyn Error>> {
+    let file = File::open(file_name)?;
+    let mut res = vec![];
+    for line in io::BufReader::new(file).lines() {
+        let line = line?;
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        match fields.as_slice() {
+            [] => {}
+            [name, start, size, flags] => res.push(SectionInfo {
+                name: name.to_string(),
+                start: number_parser::parse_num(start)?,
+                size: number_parser::parse_num(size)?,
+                writable: flags.contains('w'),
+                executable: flags.contains('x'),
+            }),
+            _ => return Err(format!("Invalid section map line \"{}\"", line).into()),
+        }
+    }
+    Ok(res)
+}
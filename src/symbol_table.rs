@@ -0,0 +1,105 @@
+use crate::number_parser;
+use std::error::Error;
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::{self, BufRead};
+
+/// What a symbol names: an address in executable memory, an address
+/// in data, or a plain value
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum SymbolKind {
+    Code,
+    Data,
+    Constant,
+}
+
+impl SymbolKind {
+    /// The kind as written in a symbol table
+    pub fn name(self) -> &'static str {
+        match self {
+            SymbolKind::Code => "code",
+            SymbolKind::Data => "data",
+            SymbolKind::Constant => "constant",
+        }
+    }
+}
+
+/// A symbol of an assembled program
+/// address: the address of a label, or the value of a constant
+/// file, line: where the symbol was defined, None for symbols that do
+/// not come from the source, such as `-D` definitions
+#[derive(Clone, Debug)]
+pub struct SymbolInfo {
+    pub name: String,
+    pub address: u64,
+    pub kind: SymbolKind,
+    pub file: Option<String>,
+    pub line: Option<usize>,
+}
+
+impl SymbolInfo {
+    /// Where the symbol was defined, written as file:line, or "-"
+    pub fn location(&self) -> String {
+        match (&self.file, self.line) {
+            (Some(file), Some(line)) => format!("{}:{}", file, line),
+            _ => "-".to_string(),
+        }
+    }
+}
+
+/// Writes a symbol table, one symbol per line:
+/// NAME ADDRESS KIND FILE:LINE
+pub fn save_file(symbols: &[SymbolInfo], file_name: String) -> Result<(), Box<dyn Error>> {
+    let mut file = File::create(file_name)?;
+    for symbol in symbols {
+        writeln!(
+            file,
+            "{} 0x{:x} {} {}",
+            symbol.name,
+            symbol.address,
+            symbol.kind.name(),
+            symbol.location()
+        )?;
+    }
+    Ok(())
+}
+
+/// Reads a symbol table written by save_file
+pub fn from_file(file_name: String) -> Result<Vec<SymbolInfo>, Box<dyn Error>> {
+    let file = File::open(file_name)?;
+    let mut res = vec![];
+    for line in io::BufReader::new(file).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let fields: Vec<&str> = line.trim().splitn(4, ' ').collect();
+        let invalid = || format!("Invalid symbol table line \"{}\"", line);
+        let (name, address, kind, location) = match fields.as_slice() {
+            [name, address, kind, location] => (name, address, kind, location),
+            _ => return Err(invalid().into()),
+        };
+        let kind = match *kind {
+            "code" => SymbolKind::Code,
+            "data" => SymbolKind::Data,
+            "constant" => SymbolKind::Constant,
+            _ => return Err(invalid().into()),
+        };
+        // File names may contain ':', the line number follows the last one
+        let (file, line) = match location.rfind(':') {
+            Some(colon) => (
+                Some(location[..colon].to_string()),
+                Some(location[colon + 1..].parse::<usize>()?),
+            ),
+            None => (None, None),
+        };
+        res.push(SymbolInfo {
+            name: name.to_string(),
+            address: number_parser::parse_num(address)?,
+            kind,
+            file,
+            line,
+        });
+    }
+    Ok(res)
+}